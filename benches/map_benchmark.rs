@@ -0,0 +1,140 @@
+// Isolates the map layer of the limiters: every operation only finds or inserts its
+// key and bumps a counter, without any windowing, so the costs of the maps can be
+// told apart from the costs of the sliding logs stored in them.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use crossbeam_skiplist::SkipMap;
+use dashmap::DashMap;
+use ratelimit::workload::{self, Distribution};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+#[cfg(unix)]
+mod perf;
+
+const NUM_REQUESTS: usize = 1_000_000;
+const KEY_COUNTS: [usize; 3] = [1_000, 100_000, 1_000_000];
+
+trait Backend: Default + Sync {
+    const NAME: &'static str;
+
+    fn touch(&self, key: IpAddr);
+}
+
+// How RateLimiter1 to RateLimiter3 store their keys
+#[derive(Default)]
+struct SkipMapBackend(SkipMap<IpAddr, AtomicU64>);
+
+impl Backend for SkipMapBackend {
+    const NAME: &'static str = "skipmap";
+
+    fn touch(&self, key: IpAddr) {
+        let entry = self.0.get_or_insert_with(key, || AtomicU64::new(0));
+        entry.value().fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// How RateLimiter0 stores its keys
+#[derive(Default)]
+struct RwLockHashMapBackend(RwLock<HashMap<IpAddr, u64>>);
+
+impl Backend for RwLockHashMapBackend {
+    const NAME: &'static str = "rwlock_hashmap";
+
+    fn touch(&self, key: IpAddr) {
+        *self.0.write().unwrap().entry(key).or_default() += 1;
+    }
+}
+
+#[derive(Default)]
+struct DashMapBackend(DashMap<IpAddr, u64>);
+
+impl Backend for DashMapBackend {
+    const NAME: &'static str = "dashmap";
+
+    fn touch(&self, key: IpAddr) {
+        *self.0.entry(key).or_default() += 1;
+    }
+}
+
+// Splits the keys over a thread per core, like concurrent callers of a limiter
+fn touch_all<B: Backend>(map: &B, keys: &[IpAddr]) {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    std::thread::scope(|scope| {
+        for chunk in keys.chunks(keys.len().div_ceil(threads)) {
+            scope.spawn(move || chunk.iter().for_each(|&key| map.touch(key)));
+        }
+    });
+}
+
+fn benchmark_backend<B: Backend>(c: &mut Criterion) {
+    for key_count in KEY_COUNTS {
+        // An exponent of 0 spreads the requests evenly over the keys
+        let keys = workload::generate(
+            &Distribution::Zipf {
+                keys: key_count,
+                exponent: 0.0,
+            },
+            NUM_REQUESTS,
+        );
+
+        let mut group = c.benchmark_group("map_benchmarks");
+        group.measurement_time(Duration::new(15, 0));
+        group.sample_size(10);
+
+        // Fresh maps, so the keys' first requests insert them
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}_insert", B::NAME), key_count),
+            &keys,
+            |b, keys| {
+                b.iter_batched(
+                    B::default,
+                    |map| touch_all(&map, keys),
+                    criterion::BatchSize::PerIteration,
+                )
+            },
+        );
+
+        // Maps that already hold every key, as in the steady state of a limiter
+        let map = B::default();
+        touch_all(&map, &keys);
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}_lookup", B::NAME), key_count),
+            &keys,
+            |b, keys| b.iter(|| touch_all(&map, keys)),
+        );
+
+        group.finish();
+    }
+}
+
+fn benchmark_skipmap(c: &mut Criterion) {
+    benchmark_backend::<SkipMapBackend>(c);
+}
+
+fn benchmark_rwlock_hashmap(c: &mut Criterion) {
+    benchmark_backend::<RwLockHashMapBackend>(c);
+}
+
+fn benchmark_dashmap(c: &mut Criterion) {
+    benchmark_backend::<DashMapBackend>(c);
+}
+
+#[cfg(unix)]
+fn config() -> Criterion {
+    Criterion::default().with_profiler(perf::FlamegraphProfiler::new(100))
+}
+
+#[cfg(not(unix))]
+fn config() -> Criterion {
+    Criterion::default()
+}
+
+criterion_group! {
+    name = benches;
+    config = config();
+    targets = benchmark_skipmap, benchmark_rwlock_hashmap, benchmark_dashmap
+}
+criterion_main!(benches);
@@ -1,30 +1,23 @@
 use chrono::Utc;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use ratelimit::{RateLimiter0, RateLimiter1, RateLimiter2, RateLimiter3};
+use ratelimit::workload::{self, Distribution};
+use ratelimit::{
+    RateLimiter0, RateLimiter1, RateLimiter2, RateLimiter3, RateLimiter4, RateLimiter5,
+    RateLimiter6, RateLimiter7, RateLimiter8, RateLimiter9,
+};
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(unix)]
 mod perf;
 
-fn random_ip() -> IpAddr {
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
-
-    IpAddr::V4(std::net::Ipv4Addr::new(
-        rng.gen::<u8>(),
-        rng.gen::<u8>(),
-        rng.gen::<u8>(),
-        rng.gen::<u8>(),
-    ))
-}
-
 fn benchmark_ratelimiter0_tokio(c: &mut Criterion) {
     const NUM_REQUESTS: usize = 1_000_000;
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = Arc::new(RateLimiter0::new());
 
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
 
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
@@ -36,21 +29,9 @@ fn benchmark_ratelimiter0_tokio(c: &mut Criterion) {
             let rate_limiter = Arc::clone(&rate_limiter);
             b.to_async(tokio::runtime::Builder::new_multi_thread().build().unwrap())
                 .iter(|| async {
-                    for chunk in random_ips.chunks(CHUNK_SIZE) {
-                        let tasks: Vec<_> = chunk
-                            .iter()
-                            .map(|&ip| {
-                                let rate_limiter = Arc::clone(&rate_limiter);
-                                tokio::task::spawn(async move {
-                                    rate_limiter.ratelimit0(ip, Utc::now());
-                                })
-                            })
-                            .collect();
-
-                        futures::future::try_join_all(tasks)
-                            .await
-                            .expect("One of the tasks failed.");
-                    }
+                    let rate_limiter = Arc::clone(&rate_limiter);
+                    let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit0(ip, Utc::now()));
+                    workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
                 });
         },
     );
@@ -61,7 +42,7 @@ fn benchmark_ratelimiter0(c: &mut Criterion) {
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = RateLimiter0::new();
 
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
 
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
@@ -71,11 +52,9 @@ fn benchmark_ratelimiter0(c: &mut Criterion) {
         &random_ips,
         |b, random_ips| {
             b.iter(|| {
-                for chunk in random_ips.chunks(CHUNK_SIZE) {
-                    for &ip in chunk {
-                        rate_limiter.ratelimit0(ip, Utc::now());
-                    }
-                }
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit0(ip, Utc::now())
+                })
             });
         },
     );
@@ -88,7 +67,7 @@ fn benchmark_ratelimiter1_tokio(c: &mut Criterion) {
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = Arc::new(RateLimiter1::new());
 
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
 
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
@@ -99,21 +78,9 @@ fn benchmark_ratelimiter1_tokio(c: &mut Criterion) {
         |b, random_ips| {
             b.to_async(tokio::runtime::Builder::new_multi_thread().build().unwrap())
                 .iter(|| async {
-                    for chunk in random_ips.chunks(CHUNK_SIZE) {
-                        let tasks: Vec<_> = chunk
-                            .iter()
-                            .map(|&ip| {
-                                let rate_limiter = Arc::clone(&rate_limiter);
-                                tokio::task::spawn(async move {
-                                    rate_limiter.ratelimit1(ip, Utc::now());
-                                })
-                            })
-                            .collect();
-
-                        futures::future::try_join_all(tasks)
-                            .await
-                            .expect("One of the tasks failed.");
-                    }
+                    let rate_limiter = Arc::clone(&rate_limiter);
+                    let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit1(ip, Utc::now()));
+                    workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
                 });
         },
     );
@@ -124,7 +91,7 @@ fn benchmark_ratelimiter1(c: &mut Criterion) {
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = RateLimiter1::new();
 
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
 
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
@@ -134,11 +101,9 @@ fn benchmark_ratelimiter1(c: &mut Criterion) {
         &random_ips,
         |b, random_ips| {
             b.iter(|| {
-                for chunk in random_ips.chunks(CHUNK_SIZE) {
-                    for &ip in chunk {
-                        rate_limiter.ratelimit1(ip, Utc::now());
-                    }
-                }
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit1(ip, Utc::now())
+                })
             });
         },
     );
@@ -150,7 +115,7 @@ fn benchmark_ratelimiter2_tokio(c: &mut Criterion) {
     const NUM_REQUESTS: usize = 1_000_000;
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = Arc::new(RateLimiter2::new());
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
     group.sample_size(10);
@@ -164,21 +129,9 @@ fn benchmark_ratelimiter2_tokio(c: &mut Criterion) {
                     .unwrap(),
             )
             .iter(|| async {
-                for chunk in random_ips.chunks(CHUNK_SIZE) {
-                    let tasks: Vec<_> = chunk
-                        .iter()
-                        .map(|&ip| {
-                            let rate_limiter = Arc::clone(&rate_limiter);
-                            tokio::task::spawn(async move {
-                                rate_limiter.ratelimit2(ip, Utc::now());
-                            })
-                        })
-                        .collect();
-
-                    futures::future::try_join_all(tasks)
-                        .await
-                        .expect("One of the tasks failed.");
-                }
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit2(ip, Utc::now()));
+                workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
             });
         },
     );
@@ -190,7 +143,7 @@ fn benchmark_ratelimiter2(c: &mut Criterion) {
     const NUM_REQUESTS: usize = 1_000_000;
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = RateLimiter2::new();
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
 
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
@@ -200,11 +153,9 @@ fn benchmark_ratelimiter2(c: &mut Criterion) {
         &random_ips,
         |b, random_ips| {
             b.iter(|| {
-                for chunk in random_ips.chunks(CHUNK_SIZE) {
-                    for &ip in chunk {
-                        rate_limiter.ratelimit2(ip, Utc::now());
-                    }
-                }
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit2(ip, Utc::now())
+                })
             });
         },
     );
@@ -216,7 +167,7 @@ fn benchmark_ratelimiter3_tokio(c: &mut Criterion) {
     const NUM_REQUESTS: usize = 1_000_000;
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = Arc::new(RateLimiter3::new());
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
     group.sample_size(10);
@@ -230,21 +181,9 @@ fn benchmark_ratelimiter3_tokio(c: &mut Criterion) {
                     .unwrap(),
             )
             .iter(|| async {
-                for chunk in random_ips.chunks(CHUNK_SIZE) {
-                    let tasks: Vec<_> = chunk
-                        .iter()
-                        .map(|&ip| {
-                            let rate_limiter = Arc::clone(&rate_limiter);
-                            tokio::task::spawn(async move {
-                                rate_limiter.ratelimit3(ip, Utc::now());
-                            })
-                        })
-                        .collect();
-
-                    futures::future::try_join_all(tasks)
-                        .await
-                        .expect("One of the tasks failed.");
-                }
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit3(ip, Utc::now()));
+                workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
             });
         },
     );
@@ -256,7 +195,7 @@ fn benchmark_ratelimiter3(c: &mut Criterion) {
     const NUM_REQUESTS: usize = 1_000_000;
     const CHUNK_SIZE: usize = 1000;
     let rate_limiter = RateLimiter3::new();
-    let random_ips: Vec<IpAddr> = (0..NUM_REQUESTS).map(|_| random_ip()).collect();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
 
     let mut group = c.benchmark_group("ratelimiter_benchmarks");
     group.measurement_time(Duration::new(45, 0));
@@ -266,11 +205,217 @@ fn benchmark_ratelimiter3(c: &mut Criterion) {
         &random_ips,
         |b, random_ips| {
             b.iter(|| {
-                for chunk in random_ips.chunks(CHUNK_SIZE) {
-                    for &ip in chunk {
-                        rate_limiter.ratelimit3(ip, Utc::now());
-                    }
-                }
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit3(ip, Utc::now())
+                })
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter5_tokio(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = Arc::new(RateLimiter5::new());
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter5_tokio", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.to_async(
+                tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap(),
+            )
+            .iter(|| async {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit5(ip, Utc::now()));
+                workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter5(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = RateLimiter5::new();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter5", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.iter(|| {
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit5(ip, Utc::now())
+                })
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter6_tokio(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = Arc::new(RateLimiter6::new());
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter6_tokio", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.to_async(
+                tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap(),
+            )
+            .iter(|| async {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit6(ip, Utc::now()));
+                workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter6(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = RateLimiter6::new();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter6", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.iter(|| {
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit6(ip, Utc::now())
+                })
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter7_tokio(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = Arc::new(RateLimiter7::new());
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter7_tokio", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.to_async(
+                tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap(),
+            )
+            .iter(|| async {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit7(ip, Utc::now()));
+                workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter7(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = RateLimiter7::new();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter7", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.iter(|| {
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit7(ip, Utc::now())
+                })
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter8_tokio(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = Arc::new(RateLimiter8::new());
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter8_tokio", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.to_async(
+                tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap(),
+            )
+            .iter(|| async {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit8(ip, Utc::now()));
+                workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter8(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = RateLimiter8::new();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter8", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.iter(|| {
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit8(ip, Utc::now())
+                })
             });
         },
     );
@@ -278,10 +423,102 @@ fn benchmark_ratelimiter3(c: &mut Criterion) {
     group.finish();
 }
 
+fn benchmark_ratelimiter9_tokio(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = Arc::new(RateLimiter9::new());
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter9_tokio", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.to_async(
+                tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap(),
+            )
+            .iter(|| async {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| rate_limiter.ratelimit9(ip, Utc::now()));
+                workload::submit_chunked_tokio(random_ips, CHUNK_SIZE, check).await
+            });
+        },
+    );
+
+    group.finish();
+}
+
+fn benchmark_ratelimiter9(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let rate_limiter = RateLimiter9::new();
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter9", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.iter(|| {
+                workload::submit_chunked(random_ips, CHUNK_SIZE, |ip| {
+                    rate_limiter.ratelimit9(ip, Utc::now())
+                })
+            });
+        },
+    );
+
+    group.finish();
+}
+
+// The actor backend can only be checked asynchronously, so it has no sequential
+// counterpart
+fn benchmark_ratelimiter4_tokio(c: &mut Criterion) {
+    const NUM_REQUESTS: usize = 1_000_000;
+    const CHUNK_SIZE: usize = 1000;
+    let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    let rate_limiter = Arc::new(runtime.block_on(async { RateLimiter4::new() }));
+    let random_ips: Vec<IpAddr> = workload::generate(&Distribution::Uniform, NUM_REQUESTS);
+
+    let mut group = c.benchmark_group("ratelimiter_benchmarks");
+    group.measurement_time(Duration::new(45, 0));
+    group.sample_size(10);
+    group.bench_with_input(
+        BenchmarkId::new("ratelimiter4_tokio", NUM_REQUESTS),
+        &random_ips,
+        |b, random_ips| {
+            b.to_async(&runtime).iter(|| async {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let check = Arc::new(move |ip: IpAddr| {
+                    let rate_limiter = Arc::clone(&rate_limiter);
+                    async move { rate_limiter.ratelimit4(ip, Utc::now()).await }
+                });
+                workload::submit_chunked_async(random_ips, CHUNK_SIZE, check).await
+            });
+        },
+    );
+
+    group.finish();
+}
+
+#[cfg(unix)]
+fn config() -> Criterion {
+    Criterion::default().with_profiler(perf::FlamegraphProfiler::new(100))
+}
+
+#[cfg(not(unix))]
+fn config() -> Criterion {
+    Criterion::default()
+}
+
 criterion_group! {
     name = benches;
-    config = Criterion::default().with_profiler(perf::FlamegraphProfiler::new(100));
-    targets = benchmark_ratelimiter0_tokio, benchmark_ratelimiter1_tokio, benchmark_ratelimiter2_tokio, benchmark_ratelimiter3_tokio,
-    benchmark_ratelimiter0, benchmark_ratelimiter1, benchmark_ratelimiter2, benchmark_ratelimiter3
+    config = config();
+    targets = benchmark_ratelimiter0_tokio, benchmark_ratelimiter1_tokio, benchmark_ratelimiter2_tokio, benchmark_ratelimiter3_tokio, benchmark_ratelimiter4_tokio, benchmark_ratelimiter5_tokio, benchmark_ratelimiter6_tokio, benchmark_ratelimiter7_tokio, benchmark_ratelimiter8_tokio, benchmark_ratelimiter9_tokio,
+    benchmark_ratelimiter0, benchmark_ratelimiter1, benchmark_ratelimiter2, benchmark_ratelimiter3, benchmark_ratelimiter5, benchmark_ratelimiter6, benchmark_ratelimiter7, benchmark_ratelimiter8, benchmark_ratelimiter9
 }
 criterion_main!(benches);
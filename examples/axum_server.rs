@@ -0,0 +1,106 @@
+// An axum app limiting every client to 5 requests per minute, behind a reverse proxy
+// on localhost whose X-Forwarded-For is trusted.
+//
+//   cargo run --example axum_server --features axum -- 127.0.0.1:3000
+//
+// serves it until interrupted. Without an address it serves on a random port, runs a
+// few requests against itself, and exits, failing if any answer is unexpected.
+use axum::routing::get;
+use axum::{middleware, Router};
+use chrono::Duration;
+use ratelimit::axum::{rate_limit, ClientIp, ClientRateLimit};
+use ratelimit::RateLimiter2;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const MAX_REQUESTS: usize = 5;
+const WINDOW_SECONDS: i64 = 60;
+
+fn app() -> Router {
+    let window = Duration::seconds(WINDOW_SECONDS);
+    let limiter = Arc::new(RateLimiter2::with_config(MAX_REQUESTS, window));
+    let limit = ClientRateLimit::new(limiter, MAX_REQUESTS, window)
+        .with_trusted_proxies([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+
+    Router::new()
+        .route(
+            "/",
+            get(|ClientIp(ip): ClientIp| async move { format!("Hello, {ip}\n") }),
+        )
+        .layer(middleware::from_fn_with_state(limit, rate_limit))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let addr = std::env::args().nth(1);
+    let listener = TcpListener::bind(addr.as_deref().unwrap_or("127.0.0.1:0")).await?;
+    let local_addr = listener.local_addr()?;
+    println!("Listening on {local_addr}");
+
+    let server = axum::serve(
+        listener,
+        app().into_make_service_with_connect_info::<SocketAddr>(),
+    );
+    if addr.is_some() {
+        server.await?;
+        return Ok(());
+    }
+    tokio::spawn(async move { server.await.expect("Server failed") });
+
+    for i in 1..=MAX_REQUESTS {
+        let response = get_root(local_addr, None).await?;
+        assert_eq!(response.status, 200, "request {i}");
+        assert_eq!(response.body, "Hello, 127.0.0.1\n");
+    }
+    let response = get_root(local_addr, None).await?;
+    assert_eq!(response.status, 429);
+    assert_eq!(response.retry_after.as_deref(), Some("61"));
+    println!("Request {} got a 429, retry after 61s", MAX_REQUESTS + 1);
+
+    // The proxy on localhost is trusted, so the client it forwards for has its own limit
+    let response = get_root(local_addr, Some("203.0.113.7")).await?;
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "Hello, 203.0.113.7\n");
+    println!("A client behind the trusted proxy was admitted on its own limit");
+    Ok(())
+}
+
+struct Response {
+    status: u16,
+    retry_after: Option<String>,
+    body: String,
+}
+
+// A bare HTTP/1.1 client, so the example runs against a real socket without pulling in
+// an HTTP client
+async fn get_root(addr: SocketAddr, forwarded_for: Option<&str>) -> std::io::Result<Response> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let forwarded_for = forwarded_for
+        .map(|client| format!("X-Forwarded-For: {client}\r\n"))
+        .unwrap_or_default();
+    let request =
+        format!("GET / HTTP/1.1\r\nHost: {addr}\r\n{forwarded_for}Connection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(0);
+    let retry_after = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))
+        .map(|(_, value)| value.trim().to_string());
+
+    Ok(Response {
+        status,
+        retry_after,
+        body: body.to_string(),
+    })
+}
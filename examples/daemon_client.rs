@@ -0,0 +1,105 @@
+// A long running client working through jobs against a rate limited API, backing off
+// on every 429 for as long as the server's Retry-After, and a growing, jittered delay
+// of its own. Pointed at a server, such as the axum_server example,
+//
+//   cargo run --example daemon_client --features axum,tower -- 127.0.0.1:3000
+//
+// it keeps going until interrupted. Without an address it starts an axum app limited
+// by the tower RateLimitLayer to 3 requests per second, works through a few jobs
+// against it, and exits.
+use axum::routing::get;
+use axum::Router;
+use chrono::{Duration, Utc};
+use ratelimit::client::{parse_retry_after, Backoff, BackoffPolicy};
+use ratelimit::layer::peer_ip;
+use ratelimit::{RateLimitLayer, RateLimiter2};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const JOBS: usize = 8;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (server, jobs) = match std::env::args().nth(1) {
+        Some(addr) => (addr.parse()?, usize::MAX),
+        None => (spawn_server().await?, JOBS),
+    };
+
+    let mut backoff = Backoff::new(BackoffPolicy {
+        initial: Duration::milliseconds(50),
+        max: Duration::seconds(5),
+        ..BackoffPolicy::default()
+    });
+    let (mut done, mut denials) = (0, 0);
+    while done < jobs {
+        let (status, retry_after) = get_root(server).await?;
+        match status {
+            200 => {
+                backoff.on_success();
+                done += 1;
+                println!("Job {done} done");
+            }
+            429 => {
+                denials += 1;
+                let retry_after =
+                    retry_after.and_then(|value| parse_retry_after(&value, Utc::now()));
+                let delay = backoff.on_denied(retry_after);
+                println!("Denied, backing off for {}ms", delay.num_milliseconds());
+                tokio::time::sleep(delay.to_std()?).await;
+            }
+            status => return Err(format!("Unexpected status {status}").into()),
+        }
+    }
+
+    // 3 requests fit in a window, so the rest had to wait for at least one denial
+    assert!(denials > 0, "Never backed off");
+    println!("Done {done} jobs after {denials} denials");
+    Ok(())
+}
+
+// Serves an axum app limited by RateLimitLayer, keyed by the peer address axum records
+async fn spawn_server() -> std::io::Result<SocketAddr> {
+    let window = Duration::seconds(1);
+    let limiter = Arc::new(RateLimiter2::with_config(3, window));
+    let layer = RateLimitLayer::new(limiter, peer_ip, 3, window);
+    let app = Router::new()
+        .route("/", get(|| async { "ok" }))
+        .layer(layer);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .expect("Server failed");
+    });
+    Ok(addr)
+}
+
+// A bare HTTP/1.1 client returning the status and Retry-After header, so the example
+// runs against a real socket without pulling in an HTTP client
+async fn get_root(addr: SocketAddr) -> std::io::Result<(u16, Option<String>)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!("GET / HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let mut lines = response.lines();
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(0);
+    let retry_after = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))
+        .map(|(_, value)| value.trim().to_string());
+    Ok((status, retry_after))
+}
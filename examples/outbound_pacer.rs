@@ -0,0 +1,65 @@
+// Throttling outbound requests on the client side, rather than finding out about the
+// limits from denials:
+//
+//   cargo run --example outbound_pacer --features tokio
+//
+// First a crawl over two hosts is scheduled politely up front, spacing the pages of
+// each host out by its crawl delay. Then calls to an API allowing 2 per half second
+// await their slot with until_ready0, and one that can't be made before its deadline
+// is given up on straight away. Nothing is actually fetched.
+use chrono::{Duration, Utc};
+use ratelimit::politeness::Politeness;
+use ratelimit::RateLimiter0;
+use std::net::IpAddr;
+use std::time::Instant;
+
+const ROBOTS_TXT: &str = "User-agent: *\nCrawl-delay: 0.1\n";
+
+#[tokio::main]
+async fn main() {
+    let start = Instant::now();
+    let elapsed = || start.elapsed().as_millis();
+
+    let politeness = Politeness::new(Duration::milliseconds(250)).with_jitter(0.1);
+    politeness.ingest_robots("docs.example.com", ROBOTS_TXT, "example-crawler");
+    let pages = [
+        ("docs.example.com", "192.0.2.10", "/"),
+        ("blog.example.com", "192.0.2.20", "/"),
+        ("docs.example.com", "192.0.2.10", "/install"),
+        ("blog.example.com", "192.0.2.20", "/archive"),
+        ("docs.example.com", "192.0.2.10", "/faq"),
+    ];
+    let now = Utc::now();
+    let mut crawl: Vec<_> = pages
+        .into_iter()
+        .map(|(host, addr, path)| {
+            let addr: IpAddr = addr.parse().unwrap();
+            (politeness.schedule(host, addr, now), host, path)
+        })
+        .collect();
+    crawl.sort_by_key(|&(at, _, _)| at);
+
+    for (at, host, path) in crawl {
+        if let Ok(delay) = (at - Utc::now()).to_std() {
+            tokio::time::sleep(delay).await;
+        }
+        println!("{:>5}ms  GET https://{host}{path}", elapsed());
+    }
+
+    let api = "198.51.100.1".parse::<IpAddr>().unwrap();
+    let limiter = RateLimiter0::with_config(2, Duration::milliseconds(500));
+    let calls_started = Instant::now();
+    for call in 1..=5 {
+        limiter.until_ready0(api).await;
+        println!("{:>5}ms  API call {call}", elapsed());
+    }
+    // 5 calls at 2 per window take at least two windows
+    assert!(calls_started.elapsed() >= std::time::Duration::from_millis(1000));
+
+    limiter.until_ready0(api).await;
+    let deadline = Utc::now() + Duration::milliseconds(100);
+    match limiter.until_ready_by0(api, deadline).await {
+        Ok(()) => panic!("Admitted a call over the limit"),
+        Err(exceeded) => println!("{:>5}ms  Gave up on a call: {exceeded}", elapsed()),
+    }
+}
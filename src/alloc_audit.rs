@@ -0,0 +1,92 @@
+// Steady-state checks for a key we already track shouldn't touch the heap, this
+// module enforces that contract with a global allocator that counts allocations
+// per thread, so concurrently running tests don't skew each other's counts.
+//
+// RateLimiter1 is deliberately not audited: it clones the whole queue and
+// reinserts it into the SkipMap on every check.
+use super::*;
+use chrono::{DateTime, Duration, Utc};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::net::IpAddr;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // try_with, as the thread local may already be gone during thread teardown
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+// Fills the key up to the limit, then keeps checking it both while it's denied and
+// while the window slides forward, which exercises pruning as well as admission
+fn assert_steady_state_allocation_free(check: impl Fn(IpAddr, DateTime<Utc>) -> bool) {
+    let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+    let now = Utc::now();
+
+    for _ in 0..MAX_REQUESTS {
+        assert!(check(ip, now));
+    }
+
+    let allocations = allocations_during(|| {
+        for i in 0..MAX_REQUESTS * 10 {
+            check(ip, now);
+            let later = now
+                + Duration::seconds(MAX_REQUESTS_DURATION_SECONDS + 1)
+                + Duration::milliseconds(i as i64);
+            check(ip, later);
+        }
+    });
+
+    assert_eq!(allocations, 0, "steady-state checks allocated");
+}
+
+#[test]
+fn test_ratelimit0_steady_state_allocation_free() {
+    let rate_limiter = RateLimiter0::new();
+    assert_steady_state_allocation_free(|ip, ts| rate_limiter.ratelimit0(ip, ts));
+}
+
+#[test]
+fn test_ratelimit2_steady_state_allocation_free() {
+    let rate_limiter = RateLimiter2::new();
+    assert_steady_state_allocation_free(|ip, ts| rate_limiter.ratelimit2(ip, ts));
+}
+
+#[test]
+fn test_ratelimit3_steady_state_allocation_free() {
+    let rate_limiter = RateLimiter3::new();
+    assert_steady_state_allocation_free(|ip, ts| rate_limiter.ratelimit3(ip, ts));
+}
+
+#[test]
+fn test_allocations_are_counted() {
+    let allocations = allocations_during(|| {
+        std::hint::black_box(Vec::<u8>::with_capacity(16));
+    });
+    assert_eq!(allocations, 1);
+}
@@ -0,0 +1,304 @@
+use crate::{RateLimit, TrackedKeys};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// A block of addresses, such as 10.0.0.0/8 or 2001:db8::/32. The bits past the prefix
+// are cleared, so any address of the block can name it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    // Fails when the prefix is longer than the address
+    pub fn new(ip: IpAddr, prefix_len: u8) -> Result<Self, CidrParseError> {
+        let width = width_of(ip);
+        if prefix_len > width {
+            return Err(CidrParseError);
+        }
+        let mask = u128::MAX
+            .checked_shl((width - prefix_len) as u32)
+            .unwrap_or(0);
+        let network = match ip {
+            IpAddr::V4(ip) => IpAddr::from((ip.to_bits() & mask as u32).to_be_bytes()),
+            IpAddr::V6(ip) => IpAddr::from((ip.to_bits() & mask).to_be_bytes()),
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrParseError;
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid CIDR block syntax")
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+// Parses `address/prefix_len`, or a bare address as a block of one
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (ip, prefix_len) = match s.split_once('/') {
+            Some((ip, prefix_len)) => (ip, Some(prefix_len)),
+            None => (s, None),
+        };
+        let ip = ip.parse::<IpAddr>().map_err(|_| CidrParseError)?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| CidrParseError)?,
+            None => width_of(ip),
+        };
+        Cidr::new(ip, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+fn width_of(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+// An address's bits from the most significant, IPv4 addresses left-aligned so both
+// families walk their trie the same way
+fn bits_of(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => (ip.to_bits() as u128) << 96,
+        IpAddr::V6(ip) => ip.to_bits(),
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Node {
+    children: [Option<u32>; 2],
+    // A block ends here, so every address below is allowed
+    allowed: bool,
+}
+
+// A binary trie of CIDR blocks, one per address family, walked bit by bit from the
+// root. Any block an address falls in allows it, so a lookup stops at the first one on
+// its path, after at most 32 or 128 steps however many blocks there are.
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    ipv4: Vec<Node>,
+    ipv6: Vec<Node>,
+}
+
+impl Default for Allowlist {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Allowlist {
+            ipv4: vec![Node::default()],
+            ipv6: vec![Node::default()],
+        }
+    }
+
+    pub fn with_cidr(mut self, cidr: Cidr) -> Self {
+        self.insert(cidr);
+        self
+    }
+
+    pub fn insert(&mut self, cidr: Cidr) {
+        let nodes = self.trie_mut(cidr.network);
+        let bits = bits_of(cidr.network);
+        let mut node = 0;
+        for i in 0..cidr.prefix_len as u32 {
+            let bit = (bits >> (127 - i)) as usize & 1;
+            node = match nodes[node].children[bit] {
+                Some(child) => child as usize,
+                None => {
+                    nodes.push(Node::default());
+                    let child = nodes.len() - 1;
+                    nodes[node].children[bit] = Some(child as u32);
+                    child
+                }
+            };
+        }
+        nodes[node].allowed = true;
+    }
+
+    // IPv4 addresses mapped into IPv6 are looked up as the IPv4 addresses they are
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let nodes = match ip {
+            IpAddr::V4(_) => &self.ipv4,
+            IpAddr::V6(_) => &self.ipv6,
+        };
+        let bits = bits_of(ip);
+        let mut node = &nodes[0];
+        for i in 0..width_of(ip) as u32 {
+            if node.allowed {
+                return true;
+            }
+            let bit = (bits >> (127 - i)) as usize & 1;
+            match node.children[bit] {
+                Some(child) => node = &nodes[child as usize],
+                None => return false,
+            }
+        }
+        node.allowed
+    }
+
+    fn trie_mut(&mut self, ip: IpAddr) -> &mut Vec<Node> {
+        match ip {
+            IpAddr::V4(_) => &mut self.ipv4,
+            IpAddr::V6(_) => &mut self.ipv6,
+        }
+    }
+}
+
+impl FromIterator<Cidr> for Allowlist {
+    fn from_iter<I: IntoIterator<Item = Cidr>>(iter: I) -> Self {
+        iter.into_iter()
+            .fold(Allowlist::new(), Allowlist::with_cidr)
+    }
+}
+
+// Admits every address on its allowlist, such as internal health checkers or partner
+// ranges, without checking or recording it, so they never use up a quota. Every other
+// address is checked against the limiter. Wrap a PrefixRateLimiter rather than the
+// other way round, so the allowlist sees addresses before they are masked.
+#[derive(Debug, Default)]
+pub struct AllowlistRateLimiter<L> {
+    limiter: L,
+    allowlist: Allowlist,
+}
+
+impl<L> AllowlistRateLimiter<L> {
+    pub fn new(limiter: L, allowlist: Allowlist) -> Self {
+        AllowlistRateLimiter { limiter, allowlist }
+    }
+
+    pub fn limiter(&self) -> &L {
+        &self.limiter
+    }
+
+    pub fn allowlist(&self) -> &Allowlist {
+        &self.allowlist
+    }
+}
+
+impl<L: RateLimit<IpAddr>> RateLimit<IpAddr> for AllowlistRateLimiter<L> {
+    fn check(&self, key: IpAddr, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: IpAddr, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.allowlist.contains(key) || self.limiter.check_with_cost(key, timestamp, cost)
+    }
+
+    fn peek(&self, key: IpAddr, timestamp: DateTime<Utc>) -> bool {
+        self.allowlist.contains(key) || self.limiter.peek(key, timestamp)
+    }
+}
+
+// Allowed addresses are never tracked
+impl<L: TrackedKeys> TrackedKeys for AllowlistRateLimiter<L> {
+    fn tracked_keys(&self) -> usize {
+        self.limiter.tracked_keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiter8;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn cidr(cidr: &str) -> Cidr {
+        cidr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parses_and_clears_host_bits() {
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("192.0.2.7").to_string(), "192.0.2.7/32");
+        assert_eq!(cidr(" ::/0 ").to_string(), "::/0");
+
+        for invalid in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "/8",
+        ] {
+            assert_eq!(invalid.parse::<Cidr>(), Err(CidrParseError), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_allowlist_contains_every_address_of_its_blocks() {
+        let allowlist: Allowlist = ["10.0.0.0/8", "192.0.2.7", "2001:db8:ff::/48"]
+            .into_iter()
+            .map(cidr)
+            .collect();
+
+        assert_eq!(allowlist.contains(ip("10.255.0.1")), true);
+        assert_eq!(allowlist.contains(ip("11.0.0.1")), false);
+        assert_eq!(allowlist.contains(ip("192.0.2.7")), true);
+        assert_eq!(allowlist.contains(ip("192.0.2.8")), false);
+        assert_eq!(allowlist.contains(ip("::ffff:10.0.0.1")), true);
+        assert_eq!(allowlist.contains(ip("2001:db8:ff:1::1")), true);
+        assert_eq!(allowlist.contains(ip("2001:db8:fe::1")), false);
+
+        // The families don't share blocks
+        assert_eq!(allowlist.contains(ip("a00::1")), false);
+        assert_eq!(Allowlist::new().contains(ip("10.0.0.1")), false);
+        assert_eq!(
+            Allowlist::new()
+                .with_cidr(cidr("0.0.0.0/0"))
+                .contains(ip("203.0.113.1")),
+            true
+        );
+    }
+
+    #[test]
+    fn test_allowlist_ratelimiter_skips_allowed_addresses() {
+        let rate_limiter = AllowlistRateLimiter::new(
+            RateLimiter8::with_config(1, Duration::seconds(60)),
+            Allowlist::new().with_cidr(cidr("10.0.0.0/8")),
+        );
+        let now = Utc::now();
+
+        let admitted = |ip| (0..5).filter(|_| rate_limiter.check(ip, now)).count();
+        assert_eq!(admitted(ip("10.0.0.1")), 5);
+        assert_eq!(admitted(ip("203.0.113.1")), 1);
+        assert_eq!(rate_limiter.tracked_keys(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::quota::too_many_requests_headers;
+use crate::RateLimit;
+use ::axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use ::axum::http::request::Parts;
+use ::axum::http::{HeaderMap, Method, StatusCode};
+use ::axum::middleware::Next;
+use ::axum::response::{IntoResponse, Response};
+use chrono::{Duration, Utc};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+// The limiter and proxy configuration the `rate_limit` middleware runs with:
+//
+//   let window = Duration::seconds(60);
+//   let limiter = Arc::new(RateLimiter2::with_config(100, window));
+//   let limit = ClientRateLimit::new(limiter, 100, window)
+//       .with_trusted_proxies(["10.0.0.1".parse()?]);
+//   let app = Router::new()
+//       .route("/", get(handler))
+//       .layer(middleware::from_fn_with_state(limit, rate_limit));
+//
+// The app has to be served with `into_make_service_with_connect_info::<SocketAddr>`
+// for the peer address to be known.
+#[derive(Clone)]
+pub struct ClientRateLimit {
+    limiter: Arc<dyn RateLimit + Send + Sync>,
+    trusted_proxies: Arc<[IpAddr]>,
+    max_requests: usize,
+    window: Duration,
+    unrecorded_methods: Arc<[Method]>,
+}
+
+impl ClientRateLimit {
+    // Trusts no proxies. Like RateLimitLayer, it takes the `max_requests` and `window`
+    // the limiter was built with, to work out the headers of a denial from.
+    pub fn new(
+        limiter: Arc<dyn RateLimit + Send + Sync>,
+        max_requests: usize,
+        window: Duration,
+    ) -> Self {
+        ClientRateLimit {
+            limiter,
+            trusted_proxies: Arc::new([]),
+            max_requests,
+            window,
+            unrecorded_methods: Arc::new([]),
+        }
+    }
+
+    // Proxies whose X-Forwarded-For entries are believed. Only exact addresses are
+    // matched, not ranges.
+    pub fn with_trusted_proxies(mut self, proxies: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.trusted_proxies = proxies.into_iter().collect();
+        self
+    }
+
+    // Requests with these methods are only peeked at, like
+    // RateLimitLayer::with_unrecorded_methods
+    pub fn with_unrecorded_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.unrecorded_methods = methods.into_iter().collect();
+        self
+    }
+}
+
+// The address of the client. Behind the `rate_limit` middleware it is the one the
+// middleware resolved through the trusted proxies, otherwise the peer address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if let Some(&client_ip) = parts.extensions.get::<ClientIp>() {
+            return Ok(client_ip);
+        }
+        peer_ip(parts).map(ClientIp)
+    }
+}
+
+fn peer_ip(parts: &Parts) -> Result<IpAddr, (StatusCode, &'static str)> {
+    parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        .ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "The peer address is unknown, serve the app with into_make_service_with_connect_info",
+        ))
+}
+
+// Resolves the client behind a chain of proxies. Every proxy appends the address it
+// received the request from to X-Forwarded-For, so the chain is walked from the
+// right for as long as the hops are trusted, and the first untrusted one is the
+// client. Entries left of it could have been made up by the client, so they are
+// ignored, as is the whole header when the peer itself isn't trusted.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    for hop in forwarded.iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        // A malformed entry can't be attributed to anyone, so the last trusted proxy
+        // is held responsible
+        match hop.trim().parse() {
+            Ok(hop) => client = hop,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+// Middleware checking every request against the limiter by client IP, answering
+// denied requests with 429 Too Many Requests, a Retry-After header and the
+// RateLimit-* headers of Quota::headers
+pub async fn rate_limit(
+    State(limit): State<ClientRateLimit>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let peer = match peer_ip(&parts) {
+        Ok(peer) => peer,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let client = client_ip(peer, &parts.headers, &limit.trusted_proxies);
+    let now = Utc::now();
+    let admitted = if limit.unrecorded_methods.contains(&parts.method) {
+        limit.limiter.peek(client, now)
+    } else {
+        limit.limiter.check(client, now)
+    };
+    if !admitted {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            too_many_requests_headers(limit.max_requests, limit.window, now),
+        )
+            .into_response();
+    }
+
+    parts.extensions.insert(ClientIp(client));
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiter2;
+    use ::axum::body::Body;
+    use ::axum::http::header;
+    use ::axum::routing::get;
+    use ::axum::{middleware, Router};
+    use pretty_assertions::assert_eq;
+    use tower::ServiceExt;
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_client_ip_ignores_forwarded_for_from_untrusted_peers() {
+        let headers = forwarded_for("203.0.113.7");
+
+        assert_eq!(
+            client_ip(ip("198.51.100.1"), &headers, &[]),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn test_client_ip_walks_trusted_proxies_from_the_right() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        // The client made up the leftmost entry
+        let headers = forwarded_for("192.0.2.66, 203.0.113.7, 10.0.0.2");
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &proxies),
+            ip("203.0.113.7")
+        );
+
+        let mut headers = forwarded_for("203.0.113.7");
+        headers.append("x-forwarded-for", "10.0.0.2".parse().unwrap());
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &proxies),
+            ip("203.0.113.7")
+        );
+
+        // Only trusted hops, so the leftmost is as far as the chain goes
+        let headers = forwarded_for("10.0.0.2");
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &proxies),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn test_client_ip_stops_at_malformed_entries() {
+        let proxies = [ip("10.0.0.1")];
+        let headers = forwarded_for("203.0.113.7, unknown");
+
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &proxies),
+            ip("10.0.0.1")
+        );
+    }
+
+    fn app(limit: ClientRateLimit) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|ClientIp(ip): ClientIp| async move { ip.to_string() }),
+            )
+            .layer(middleware::from_fn_with_state(limit, rate_limit))
+    }
+
+    fn request(peer: &str, forwarded_for: Option<&str>) -> Request {
+        let mut request = Request::get("/");
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("x-forwarded-for", forwarded_for);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        let peer: SocketAddr = peer.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    }
+
+    async fn body(response: Response) -> String {
+        let bytes = ::axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_denies_over_limit_with_retry_after() {
+        let window = Duration::seconds(30);
+        let limiter = Arc::new(RateLimiter2::with_config(2, window));
+        let app = app(ClientRateLimit::new(limiter, 2, window));
+
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(request("192.0.2.1:4000", None))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body(response).await, "192.0.2.1");
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request("192.0.2.1:4000", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "31");
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert_eq!(response.headers()["ratelimit-reset"], "31");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_limits_clients_behind_trusted_proxies() {
+        let window = Duration::seconds(30);
+        let limiter = Arc::new(RateLimiter2::with_config(1, window));
+        let app =
+            app(ClientRateLimit::new(limiter, 1, window).with_trusted_proxies([ip("10.0.0.1")]));
+
+        for client in ["203.0.113.7", "203.0.113.8"] {
+            let response = app
+                .clone()
+                .oneshot(request("10.0.0.1:4000", Some(client)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body(response).await, client);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request("10.0.0.1:4000", Some("203.0.113.7")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_only_peeks_for_unrecorded_methods() {
+        let window = Duration::seconds(30);
+        let limiter = Arc::new(RateLimiter2::with_config(1, window));
+        let app =
+            app(ClientRateLimit::new(limiter, 1, window).with_unrecorded_methods([Method::HEAD]));
+
+        let mut statuses = Vec::new();
+        for method in [Method::HEAD, Method::HEAD, Method::GET, Method::HEAD] {
+            let mut request = request("192.0.2.1:4000", None);
+            *request.method_mut() = method;
+            statuses.push(app.clone().oneshot(request).await.unwrap().status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_without_connect_info() {
+        let window = Duration::seconds(60);
+        let app = app(ClientRateLimit::new(
+            Arc::new(RateLimiter2::with_config(1, window)),
+            1,
+            window,
+        ));
+
+        let response = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{Clock, RateLimit, SystemClock};
+use chrono::{DateTime, Duration, Utc};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// What BackdateGuard does with a request timestamped before `now - window - slack`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backdating {
+    // Denies the request without checking it
+    #[default]
+    Reject,
+    // Checks the request as if it was made now. Clamping it to the cutoff instead
+    // would still let it be counted against a window that has already passed.
+    Clamp,
+}
+
+// Guards a limiter against callers, or compromised upstreams, back-dating requests to
+// an old window with room left in it. Timestamps are trusted as long as they are at
+// most `window + slack` behind the guard's own clock, where the slack allows for
+// requests that queued for a while and for clock skew between hosts.
+#[derive(Debug)]
+pub struct BackdateGuard<L, C = SystemClock> {
+    limiter: L,
+    clock: C,
+    window: Duration,
+    slack: Duration,
+    backdating: Backdating,
+    backdated: AtomicU64,
+}
+
+impl<L> BackdateGuard<L> {
+    // `window` should be the window of the limiter, as it has no say on timestamps
+    // older than that
+    pub fn new(limiter: L, window: Duration) -> Self {
+        Self::with_clock(limiter, window, SystemClock)
+    }
+}
+
+impl<L, C: Clock> BackdateGuard<L, C> {
+    pub fn with_clock(limiter: L, window: Duration, clock: C) -> Self {
+        BackdateGuard {
+            limiter,
+            clock,
+            window,
+            slack: Duration::zero(),
+            backdating: Backdating::default(),
+            backdated: AtomicU64::new(0),
+        }
+    }
+
+    // How far beyond the window a timestamp may lag the clock, zero by default
+    pub fn with_slack(mut self, slack: Duration) -> Self {
+        self.slack = slack;
+        self
+    }
+
+    // Whether back-dated requests are rejected, the default, or clamped to now
+    pub fn with_backdating(mut self, backdating: Backdating) -> Self {
+        self.backdating = backdating;
+        self
+    }
+
+    // The oldest timestamp checked as is at this moment
+    pub fn cutoff(&self) -> DateTime<Utc> {
+        self.clock.now() - self.window - self.slack
+    }
+
+    // The number of back-dated requests, rejected or clamped
+    pub fn backdated(&self) -> u64 {
+        self.backdated.load(Ordering::Relaxed)
+    }
+
+    pub fn limiter(&self) -> &L {
+        &self.limiter
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+}
+
+impl<K, L: RateLimit<K>, C: Clock> RateLimit<K> for BackdateGuard<L, C> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let now = self.clock.now();
+        if timestamp >= now - self.window - self.slack {
+            return self.limiter.check_with_cost(key, timestamp, cost);
+        }
+
+        self.backdated.fetch_add(1, Ordering::Relaxed);
+        match self.backdating {
+            Backdating::Reject => false,
+            Backdating::Clamp => self.limiter.check_with_cost(key, now, cost),
+        }
+    }
+
+    // Backdated peeks are handled like checks, but not counted
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let now = self.clock.now();
+        if timestamp >= now - self.window - self.slack {
+            return self.limiter.peek(key, timestamp);
+        }
+        match self.backdating {
+            Backdating::Reject => false,
+            Backdating::Clamp => self.limiter.peek(key, now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, RateLimiter2};
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_backdate_guard_rejects_requests_older_than_the_window_and_slack() {
+        let clock = ManualClock::default();
+        let window = Duration::seconds(60);
+        let rate_limiter =
+            BackdateGuard::with_clock(RateLimiter2::with_config(2, window), window, &clock)
+                .with_slack(Duration::seconds(5));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let other_ip = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let now = clock.now();
+
+        // The limiter alone would admit these into empty windows in the past
+        assert_eq!(rate_limiter.check(ip, now - Duration::seconds(66)), false);
+        assert_eq!(rate_limiter.check(ip, now - Duration::hours(1)), false);
+        assert_eq!(rate_limiter.backdated(), 2);
+
+        // Within the slack, the timestamp is checked as is
+        assert_eq!(
+            rate_limiter.check(other_ip, now - Duration::seconds(65)),
+            true
+        );
+        assert_eq!(rate_limiter.check(ip, now), true);
+        assert_eq!(rate_limiter.backdated(), 2);
+    }
+
+    #[test]
+    fn test_backdate_guard_clamps_requests_to_now() {
+        let clock = ManualClock::default();
+        let window = Duration::seconds(60);
+        let rate_limiter =
+            BackdateGuard::with_clock(RateLimiter2::with_config(2, window), window, &clock)
+                .with_backdating(Backdating::Clamp);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let long_ago = clock.now() - Duration::hours(1);
+
+        assert_eq!(rate_limiter.check(ip, long_ago), true);
+        assert_eq!(rate_limiter.check(ip, long_ago), true);
+        // Both were counted now, so the window is full
+        assert_eq!(rate_limiter.check(ip, long_ago), false);
+        assert_eq!(rate_limiter.check(ip, clock.now()), false);
+        assert_eq!(rate_limiter.backdated(), 3);
+
+        clock.advance(Duration::seconds(61));
+        assert_eq!(rate_limiter.check(ip, clock.now()), true);
+    }
+}
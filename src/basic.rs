@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The limiters available without the `full` feature, and so without any dependency.
+// They take the time of every check as a std Instant rather than a chrono DateTime,
+// and keep each key's state in a HashMap behind one Mutex, like RateLimiter0.
+
+// Counts the requests of each key in fixed windows, starting at a key's first request
+// and restarting at its first request after the window ended. It keeps a single count
+// per key, at the cost of admitting up to twice the limit across the boundary of two
+// windows.
+#[derive(Debug)]
+pub struct FixedWindowRateLimiter<K = IpAddr> {
+    windows: Mutex<HashMap<K, (Instant, usize)>>,
+    max_requests: usize,
+    window: Duration,
+}
+
+impl<K: Hash + Eq> FixedWindowRateLimiter<K> {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        FixedWindowRateLimiter {
+            windows: Mutex::new(HashMap::new()),
+            max_requests,
+            window,
+        }
+    }
+
+    pub fn check(&self, key: K, now: Instant) -> bool {
+        self.check_with_cost(key, now, 1)
+    }
+
+    // Counts the request `cost` times, if all of them fit
+    pub fn check_with_cost(&self, key: K, now: Instant, cost: u32) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        let (start, count) = windows.entry(key).or_insert((now, 0));
+        if now.saturating_duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+
+        if *count + cost as usize > self.max_requests {
+            return false;
+        }
+        *count += cost as usize;
+        true
+    }
+}
+
+// The generic cell rate algorithm of RateLimiter6: each key keeps the time its next
+// request would be due at the sustained rate, and a request is admitted as long as
+// that doesn't run more than one window ahead of it. Allows bursts of `max_requests`,
+// and sustains `max_requests` per `window`.
+#[derive(Debug)]
+pub struct GcraRateLimiter<K = IpAddr> {
+    tats: Mutex<HashMap<K, Instant>>,
+    emission_interval: Duration,
+    tolerance: Duration,
+}
+
+impl<K: Hash + Eq> GcraRateLimiter<K> {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        let max_requests = u32::try_from(max_requests).unwrap_or(u32::MAX);
+        let emission_interval = window / max_requests.max(1);
+        GcraRateLimiter {
+            tats: Mutex::new(HashMap::new()),
+            emission_interval,
+            tolerance: emission_interval.saturating_mul(max_requests),
+        }
+    }
+
+    pub fn check(&self, key: K, now: Instant) -> bool {
+        self.check_with_cost(key, now, 1)
+    }
+
+    // Pushes the key's TAT back by `cost` emission intervals at once, if it stays
+    // within the tolerance
+    pub fn check_with_cost(&self, key: K, now: Instant, cost: u32) -> bool {
+        let mut tats = self.tats.lock().unwrap();
+        let tat = tats.get(&key).map_or(now, |&tat| tat.max(now));
+        let Some(next) = self
+            .emission_interval
+            .checked_mul(cost)
+            .and_then(|increment| tat.checked_add(increment))
+        else {
+            return false;
+        };
+
+        if next.saturating_duration_since(now) > self.tolerance {
+            return false;
+        }
+        tats.insert(key, next);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_fixed_window_ratelimiter_restarts_the_window() {
+        let rate_limiter = FixedWindowRateLimiter::new(3, Duration::from_secs(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        let admitted = |at| (0..5).filter(|_| rate_limiter.check(ip, at)).count();
+        assert_eq!(admitted(now), 3);
+        assert_eq!(admitted(now + Duration::from_secs(59)), 0);
+        assert_eq!(admitted(now + Duration::from_secs(60)), 3);
+    }
+
+    #[test]
+    fn test_fixed_window_ratelimiter_with_cost() {
+        let rate_limiter = FixedWindowRateLimiter::new(10, Duration::from_secs(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 11), false);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 8), true);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 3), false);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 2), true);
+    }
+
+    #[test]
+    fn test_gcra_ratelimiter_admits_at_sustained_rate_after_burst() {
+        let rate_limiter = GcraRateLimiter::new(2, Duration::from_secs(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(rate_limiter.check(ip, now), true);
+        assert_eq!(rate_limiter.check(ip, now), true);
+        assert_eq!(rate_limiter.check(ip, now), false);
+
+        let interval = Duration::from_millis(500);
+        let later = now + interval - Duration::from_nanos(1);
+        assert_eq!(rate_limiter.check(ip, later), false);
+        assert_eq!(rate_limiter.check(ip, now + interval), true);
+        assert_eq!(rate_limiter.check(ip, now + interval), false);
+        assert_eq!(rate_limiter.check(ip, now + interval * 2), true);
+    }
+
+    #[test]
+    fn test_gcra_ratelimiter_keys_are_independent() {
+        let rate_limiter = GcraRateLimiter::new(1, Duration::from_secs(1));
+        let (ip, other_ip): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let now = Instant::now();
+
+        assert_eq!(rate_limiter.check(ip, now), true);
+        assert_eq!(rate_limiter.check(ip, now), false);
+        assert_eq!(rate_limiter.check(other_ip, now), true);
+    }
+}
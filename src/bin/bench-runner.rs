@@ -0,0 +1,302 @@
+use chrono::Utc;
+use ratelimit::workload::{self, Distribution};
+use ratelimit::{
+    RateLimit, RateLimiter0, RateLimiter1, RateLimiter2, RateLimiter3, RateLimiter4, RateLimiter5,
+    RateLimiter6, RateLimiter7, RateLimiter8, RateLimiter9,
+};
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Instant;
+
+type Limiter = Arc<dyn RateLimit + Send + Sync>;
+type NewLimiter = fn() -> Limiter;
+
+const USAGE: &str = "Usage: bench-runner [--requests N] [--chunk-size N] [--format json|csv] [--label LABEL] [--replay PATH] [--output PATH]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Debug)]
+struct Options {
+    requests: usize,
+    chunk_size: usize,
+    format: Format,
+    label: String,
+    replay: Option<String>,
+    output: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            requests: 1_000_000,
+            chunk_size: 1000,
+            format: Format::Json,
+            label: String::new(),
+            replay: None,
+            output: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BenchResult {
+    label: String,
+    implementation: &'static str,
+    workload: &'static str,
+    mode: &'static str,
+    requests: usize,
+    allowed: usize,
+    denied: usize,
+    elapsed_ns: u128,
+}
+
+impl BenchResult {
+    fn throughput(&self) -> f64 {
+        self.requests as f64 / (self.elapsed_ns as f64 / 1e9)
+    }
+}
+
+fn limiters() -> Vec<(&'static str, NewLimiter)> {
+    vec![
+        ("ratelimiter0", || Arc::new(RateLimiter0::new())),
+        ("ratelimiter1", || Arc::new(RateLimiter1::new())),
+        ("ratelimiter2", || Arc::new(RateLimiter2::new())),
+        ("ratelimiter3", || Arc::new(RateLimiter3::new())),
+        ("ratelimiter5", || Arc::new(RateLimiter5::new())),
+        ("ratelimiter6", || Arc::new(RateLimiter6::new())),
+        ("ratelimiter7", || Arc::new(RateLimiter7::new())),
+        ("ratelimiter8", || Arc::new(RateLimiter8::new())),
+        ("ratelimiter9", || Arc::new(RateLimiter9::new())),
+    ]
+}
+
+fn workloads(options: &Options) -> Result<Vec<(&'static str, Vec<IpAddr>)>, String> {
+    let mut distributions = vec![
+        ("uniform", Distribution::Uniform),
+        (
+            "zipf",
+            Distribution::Zipf {
+                keys: 10_000,
+                exponent: 1.1,
+            },
+        ),
+        ("bursty", Distribution::Bursty { burst_len: 200 }),
+        (
+            "hot_key",
+            Distribution::Replay(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]),
+        ),
+    ];
+
+    if let Some(path) = &options.replay {
+        let recording = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let recording = workload::parse_replay(&recording).map_err(|e| format!("{path}: {e}"))?;
+        if recording.is_empty() {
+            return Err(format!("{path}: recording is empty"));
+        }
+        distributions.push(("replay", Distribution::Replay(recording)));
+    }
+
+    Ok(distributions
+        .into_iter()
+        .map(|(name, distribution)| (name, workload::generate(&distribution, options.requests)))
+        .collect())
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--requests" => {
+                options.requests = value()?.parse().map_err(|e| format!("--requests: {e}"))?
+            }
+            "--chunk-size" => {
+                options.chunk_size = value()?.parse().map_err(|e| format!("--chunk-size: {e}"))?
+            }
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    other => return Err(format!("Unknown format: {other}")),
+                }
+            }
+            "--label" => options.label = value()?,
+            "--replay" => options.replay = Some(value()?),
+            "--output" => options.output = Some(value()?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            other => return Err(format!("Unknown argument: {other}\n{USAGE}")),
+        }
+    }
+
+    if options.chunk_size == 0 {
+        return Err("--chunk-size must be greater than 0".to_string());
+    }
+
+    Ok(options)
+}
+
+fn escape_json(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_json(out: &mut impl Write, results: &[BenchResult]) -> io::Result<()> {
+    writeln!(out, "[")?;
+    for (i, result) in results.iter().enumerate() {
+        let separator = if i + 1 < results.len() { "," } else { "" };
+        writeln!(
+            out,
+            "  {{\"label\": \"{}\", \"implementation\": \"{}\", \"workload\": \"{}\", \"mode\": \"{}\", \"requests\": {}, \"allowed\": {}, \"denied\": {}, \"elapsed_ns\": {}, \"throughput_per_sec\": {:.2}}}{}",
+            escape_json(&result.label),
+            result.implementation,
+            result.workload,
+            result.mode,
+            result.requests,
+            result.allowed,
+            result.denied,
+            result.elapsed_ns,
+            result.throughput(),
+            separator
+        )?;
+    }
+    writeln!(out, "]")
+}
+
+fn write_csv(out: &mut impl Write, results: &[BenchResult]) -> io::Result<()> {
+    writeln!(
+        out,
+        "label,implementation,workload,mode,requests,allowed,denied,elapsed_ns,throughput_per_sec"
+    )?;
+    for result in results {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{:.2}",
+            escape_csv(&result.label),
+            result.implementation,
+            result.workload,
+            result.mode,
+            result.requests,
+            result.allowed,
+            result.denied,
+            result.elapsed_ns,
+            result.throughput()
+        )?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            std::process::exit(2);
+        }
+    };
+
+    let workloads = match workloads(&options) {
+        Ok(workloads) => workloads,
+        Err(message) => {
+            eprintln!("{message}");
+            std::process::exit(2);
+        }
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .build()
+        .expect("Failed to build the tokio runtime");
+
+    let mut results = Vec::new();
+    let mut record = |implementation, workload, mode, requests: usize, allowed, start: Instant| {
+        let elapsed_ns = start.elapsed().as_nanos();
+        eprintln!("{implementation}/{workload}/{mode}: {elapsed_ns}ns");
+        results.push(BenchResult {
+            label: options.label.clone(),
+            implementation,
+            workload,
+            mode,
+            requests,
+            allowed,
+            denied: requests - allowed,
+            elapsed_ns,
+        });
+    };
+    for (workload, ips) in workloads {
+        for (implementation, new_limiter) in limiters() {
+            for mode in ["sequential", "tokio"] {
+                let limiter = new_limiter();
+                let start = Instant::now();
+                let allowed = match mode {
+                    "sequential" => workload::submit_chunked(&ips, options.chunk_size, |ip| {
+                        limiter.check(ip, Utc::now())
+                    }),
+                    _ => {
+                        let limiter = Arc::clone(&limiter);
+                        let check = Arc::new(move |ip: IpAddr| limiter.check(ip, Utc::now()));
+                        runtime.block_on(workload::submit_chunked_tokio(
+                            &ips,
+                            options.chunk_size,
+                            check,
+                        ))
+                    }
+                };
+                record(implementation, workload, mode, ips.len(), allowed, start);
+            }
+        }
+
+        // RateLimiter4 only answers asynchronously, so it isn't a RateLimit. Its actors
+        // are spawned on the runtime, and every check is awaited there.
+        for mode in ["sequential", "tokio"] {
+            let limiter = Arc::new(runtime.block_on(async { RateLimiter4::new() }));
+            let start = Instant::now();
+            let allowed = match mode {
+                "sequential" => runtime.block_on(async {
+                    let mut allowed = 0;
+                    for &ip in &ips {
+                        if limiter.ratelimit4(ip, Utc::now()).await {
+                            allowed += 1;
+                        }
+                    }
+                    allowed
+                }),
+                _ => {
+                    let check = Arc::new(move |ip: IpAddr| {
+                        let limiter = Arc::clone(&limiter);
+                        async move { limiter.ratelimit4(ip, Utc::now()).await }
+                    });
+                    runtime.block_on(workload::submit_chunked_async(
+                        &ips,
+                        options.chunk_size,
+                        check,
+                    ))
+                }
+            };
+            record("ratelimiter4", workload, mode, ips.len(), allowed, start);
+        }
+    }
+
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(File::create(path).expect("Failed to create the output file")),
+        None => Box::new(io::stdout().lock()),
+    };
+    let written = match options.format {
+        Format::Json => write_json(&mut out, &results),
+        Format::Csv => write_csv(&mut out, &results),
+    };
+    written.expect("Failed to write the results");
+}
@@ -0,0 +1,440 @@
+use chrono::{DateTime, Duration, Utc};
+use ratelimit::recorder::{self, DecisionRecord, FlightRecorder};
+use ratelimit::stats::Stats;
+use ratelimit::workload::{self, Distribution};
+use ratelimit::{
+    RateLimit, RateLimiter0, RateLimiter1, RateLimiter2, RateLimiter3, RateLimiter4, RateLimiter5,
+    RateLimiter6, RateLimiter7, RateLimiter8, RateLimiter9, Strictness, MAX_REQUESTS,
+    MAX_REQUESTS_DURATION_SECONDS,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::hash::RandomState;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Version 4 can only answer asynchronously, so it isn't a RateLimit
+enum Limiter {
+    Sync(Arc<dyn RateLimit + Send + Sync>),
+    Sharded(Arc<RateLimiter4>),
+}
+
+type NewLimiter = fn() -> Limiter;
+
+impl Limiter {
+    async fn check(&self, ip: IpAddr, timestamp: DateTime<Utc>) -> bool {
+        match self {
+            Limiter::Sync(limiter) => limiter.check(ip, timestamp),
+            Limiter::Sharded(limiter) => limiter.ratelimit4(ip, timestamp).await,
+        }
+    }
+}
+
+const USAGE: &str = "Usage: soak [--implementation NAME] [--duration SECS] [--report-every SECS] [--reshard-every SECS] [--workers N] [--chunk-size N] [--keys N] [--sample-every N] [--max-rss-mb N] [--diagnostics PATH]";
+
+#[derive(Debug)]
+struct Options {
+    implementation: String,
+    duration: std::time::Duration,
+    report_every: std::time::Duration,
+    // Only version 4 can be resharded
+    reshard_every: std::time::Duration,
+    workers: usize,
+    chunk_size: usize,
+    keys: usize,
+    sample_every: u64,
+    max_rss_mb: u64,
+    diagnostics: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            implementation: "ratelimiter2".to_string(),
+            duration: std::time::Duration::from_secs(4 * 60 * 60),
+            report_every: std::time::Duration::from_secs(60),
+            reshard_every: std::time::Duration::from_secs(10),
+            workers: 4,
+            chunk_size: 100,
+            keys: 10_000,
+            sample_every: 64,
+            max_rss_mb: 1024,
+            diagnostics: "soak-diagnostics.csv".to_string(),
+        }
+    }
+}
+
+fn limiters() -> Vec<(&'static str, Strictness, NewLimiter)> {
+    vec![
+        ("ratelimiter0", RateLimiter0::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter0::new()))
+        }),
+        ("ratelimiter1", RateLimiter1::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter1::new()))
+        }),
+        ("ratelimiter2", RateLimiter2::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter2::new()))
+        }),
+        ("ratelimiter3", RateLimiter3::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter3::new()))
+        }),
+        ("ratelimiter4", RateLimiter4::STRICTNESS, || {
+            Limiter::Sharded(Arc::new(RateLimiter4::new()))
+        }),
+        ("ratelimiter5", RateLimiter5::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter5::new()))
+        }),
+        ("ratelimiter6", RateLimiter6::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter6::new()))
+        }),
+        ("ratelimiter7", RateLimiter7::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter7::new()))
+        }),
+        ("ratelimiter8", RateLimiter8::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter8::new()))
+        }),
+        ("ratelimiter9", RateLimiter9::STRICTNESS, || {
+            Limiter::Sync(Arc::new(RateLimiter9::new()))
+        }),
+    ]
+}
+
+// Watches every decision, keeping an independent log of the admitted requests of a
+// sample of the keys to catch over-admission
+struct Monitor {
+    strictness: Strictness,
+    sample_every: u64,
+    // Timestamps are taken before the check, so every thread inside a check at the
+    // same time may have one request straddling the window boundary
+    tolerance: usize,
+    sampled: Mutex<HashMap<IpAddr, VecDeque<DateTime<Utc>>>>,
+    max_in_window: AtomicUsize,
+    violations: Mutex<Vec<String>>,
+    stats: Stats,
+    recorder: FlightRecorder,
+}
+
+impl Monitor {
+    fn observe(&self, ip: IpAddr, timestamp: DateTime<Utc>, admitted: bool) {
+        self.stats.record(admitted);
+        if !admitted || !recorder::key_hash(&ip).is_multiple_of(self.sample_every) {
+            return;
+        }
+
+        let window = Duration::seconds(MAX_REQUESTS_DURATION_SECONDS);
+        let mut sampled = self.sampled.lock().unwrap();
+        let admitted = sampled.entry(ip).or_default();
+
+        let position = admitted.partition_point(|&time| time <= timestamp);
+        admitted.insert(position, timestamp);
+        while let (Some(&front), Some(&back)) = (admitted.front(), admitted.back()) {
+            if front >= back - window {
+                break;
+            }
+            admitted.pop_front();
+        }
+
+        let in_window = position + 1 - admitted.partition_point(|&time| time < timestamp - window);
+        self.max_in_window.fetch_max(in_window, Ordering::Relaxed);
+        if let Some(max_admitted) = self.strictness.max_admitted(MAX_REQUESTS) {
+            if in_window > max_admitted + self.tolerance {
+                self.violate(format!(
+                    "{ip} had {in_window} requests admitted in the window ending at {timestamp}"
+                ));
+            }
+        }
+    }
+
+    fn violate(&self, violation: String) {
+        self.violations.lock().unwrap().push(violation);
+    }
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        let mut number =
+            || -> Result<u64, String> { value()?.parse().map_err(|e| format!("{arg}: {e}")) };
+        match arg.as_str() {
+            "--implementation" => options.implementation = value()?,
+            "--duration" => options.duration = std::time::Duration::from_secs(number()?),
+            "--report-every" => options.report_every = std::time::Duration::from_secs(number()?),
+            "--reshard-every" => options.reshard_every = std::time::Duration::from_secs(number()?),
+            "--workers" => options.workers = number()? as usize,
+            "--chunk-size" => options.chunk_size = number()? as usize,
+            "--keys" => options.keys = number()? as usize,
+            "--sample-every" => options.sample_every = number()?,
+            "--max-rss-mb" => options.max_rss_mb = number()?,
+            "--diagnostics" => options.diagnostics = value()?,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            other => return Err(format!("Unknown argument: {other}\n{USAGE}")),
+        }
+    }
+
+    for (name, value) in [
+        ("--workers", options.workers as u64),
+        ("--chunk-size", options.chunk_size as u64),
+        ("--keys", options.keys as u64),
+        ("--sample-every", options.sample_every),
+        ("--report-every", options.report_every.as_secs()),
+        ("--reshard-every", options.reshard_every.as_secs()),
+    ] {
+        if value == 0 {
+            return Err(format!("{name} must be greater than 0"));
+        }
+    }
+
+    Ok(options)
+}
+
+// The resident set size, as reported by procfs. statm counts pages, which are 4 KiB
+// on every platform we soak on.
+#[cfg(target_os = "linux")]
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+fn write_diagnostics(out: &mut impl Write, monitor: &Monitor) -> io::Result<()> {
+    writeln!(out, "key_hash,timestamp,admitted,rule,latency_ns")?;
+    for record in monitor.recorder.dump() {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            record.key_hash,
+            record.timestamp.to_rfc3339(),
+            record.admitted,
+            record.rule,
+            record.latency.as_nanos()
+        )?;
+    }
+    Ok(())
+}
+
+fn fail(monitor: &Monitor, options: &Options) -> ! {
+    for violation in monitor.violations.lock().unwrap().iter() {
+        eprintln!("violation: {violation}");
+    }
+    let written =
+        File::create(&options.diagnostics).and_then(|mut out| write_diagnostics(&mut out, monitor));
+    match written {
+        Ok(()) => eprintln!("Wrote the recent decisions to {}", options.diagnostics),
+        Err(e) => eprintln!("Failed to write {}: {e}", options.diagnostics),
+    }
+    std::process::exit(1);
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            std::process::exit(2);
+        }
+    };
+
+    let Some((implementation, strictness, new_limiter)) = limiters()
+        .into_iter()
+        .find(|(name, _, _)| *name == options.implementation)
+    else {
+        eprintln!("Unknown implementation: {}", options.implementation);
+        std::process::exit(2);
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build the tokio runtime");
+
+    let monitor = Arc::new(Monitor {
+        strictness,
+        sample_every: options.sample_every,
+        tolerance: runtime.metrics().num_workers(),
+        sampled: Mutex::new(HashMap::new()),
+        max_in_window: AtomicUsize::new(0),
+        violations: Mutex::new(Vec::new()),
+        stats: Stats::new(),
+        recorder: FlightRecorder::new(10_000),
+    });
+
+    // Version 4 spawns its shards onto the runtime
+    let limiter = Arc::new(runtime.block_on(async { new_limiter() }));
+    let check = {
+        let monitor = Arc::clone(&monitor);
+        let limiter = Arc::clone(&limiter);
+        Arc::new(move |ip: IpAddr| {
+            let monitor = Arc::clone(&monitor);
+            let limiter = Arc::clone(&limiter);
+            async move {
+                let timestamp = Utc::now();
+                let start = Instant::now();
+                let admitted = limiter.check(ip, timestamp).await;
+                monitor.recorder.record(DecisionRecord {
+                    key_hash: recorder::key_hash(&ip),
+                    timestamp,
+                    admitted,
+                    rule: implementation,
+                    latency: start.elapsed(),
+                });
+                monitor.observe(ip, timestamp, admitted);
+                admitted
+            }
+        })
+    };
+
+    let traffic: Arc<[IpAddr]> = workload::generate(
+        &Distribution::Zipf {
+            keys: options.keys,
+            exponent: 1.1,
+        },
+        1 << 20,
+    )
+    .into();
+
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let batch = options.chunk_size * 16;
+    let (worker_count, chunk_size) = (options.workers, options.chunk_size);
+    let workers: Vec<_> = (0..worker_count)
+        .map(|worker| {
+            let check = Arc::clone(&check);
+            let traffic = Arc::clone(&traffic);
+            runtime.spawn(async move {
+                let mut offset = worker * traffic.len() / worker_count;
+                while Instant::now() < deadline {
+                    let end = (offset + batch).min(traffic.len());
+                    workload::submit_chunked_async(
+                        &traffic[offset..end],
+                        chunk_size,
+                        Arc::clone(&check),
+                    )
+                    .await;
+                    offset = if end == traffic.len() { 0 } else { end };
+                }
+            })
+        })
+        .collect();
+
+    // Version 4 is resharded under load, alternating between one shard and twice as
+    // many as there are workers, each time with a new salt, so every key moves. No
+    // request may be admitted over the limit across the moves.
+    let max_shards = 2 * runtime.metrics().num_workers();
+    let resharder = match &*limiter {
+        Limiter::Sync(_) => None,
+        Limiter::Sharded(limiter) => {
+            let limiter = Arc::clone(limiter);
+            let reshard_every = options.reshard_every;
+            Some(runtime.spawn(async move {
+                let mut reshards = 0;
+                loop {
+                    let now = Instant::now();
+                    if now + reshard_every >= deadline {
+                        return reshards;
+                    }
+                    tokio::time::sleep(reshard_every).await;
+                    let shards = if reshards % 2 == 0 { max_shards } else { 1 };
+                    limiter.reshard4(shards, RandomState::new()).await;
+                    reshards += 1;
+                }
+            }))
+        }
+    };
+
+    // Workers keep at most one chunk of tasks in flight each. Version 4 adds a task per
+    // shard, the old ones only stopping once drained by a reshard, and the resharder.
+    let shard_tasks = match &*limiter {
+        Limiter::Sync(_) => 0,
+        Limiter::Sharded(_) => 2 * max_shards + 1,
+    };
+    let max_alive_tasks = options.workers * (options.chunk_size + 1) + shard_tasks;
+    let max_rss = options.max_rss_mb * 1024 * 1024;
+    eprintln!(
+        "Soaking {implementation} ({strictness:?}) for {:?}",
+        options.duration
+    );
+
+    while Instant::now() < deadline {
+        std::thread::sleep(options.report_every.min(deadline - Instant::now()));
+
+        let stats = monitor.stats.snapshot();
+        let alive_tasks = runtime.metrics().num_alive_tasks();
+        let rss = resident_bytes();
+        eprintln!(
+            "{:>8}s allowed={} denied={} max_sampled_in_window={} alive_tasks={} rss_mb={}",
+            started.elapsed().as_secs(),
+            stats.allowed,
+            stats.denied,
+            monitor.max_in_window.load(Ordering::Relaxed),
+            alive_tasks,
+            rss.map_or("n/a".to_string(), |rss| (rss / 1024 / 1024).to_string()),
+        );
+
+        if alive_tasks > max_alive_tasks {
+            monitor.violate(format!(
+                "{alive_tasks} tasks alive, expected at most {max_alive_tasks}"
+            ));
+        }
+        if let Some(rss) = rss.filter(|&rss| rss > max_rss) {
+            monitor.violate(format!(
+                "resident set grew to {} MiB, over the {} MiB bound",
+                rss / 1024 / 1024,
+                options.max_rss_mb
+            ));
+        }
+        if workers.iter().any(|worker| worker.is_finished()) && Instant::now() < deadline {
+            monitor.violate("a worker stopped before the deadline".to_string());
+        }
+        if !monitor.violations.lock().unwrap().is_empty() {
+            fail(&monitor, &options);
+        }
+    }
+
+    for worker in workers {
+        if let Err(e) = runtime.block_on(worker) {
+            monitor.violate(format!("a worker failed: {e}"));
+        }
+    }
+    if let Some(resharder) = resharder {
+        match runtime.block_on(resharder) {
+            Ok(reshards) => eprintln!("Resharded {reshards} times"),
+            Err(e) => monitor.violate(format!("the resharder failed: {e}")),
+        }
+    }
+    // Version 4's shards only stop once the last check holding it is dropped
+    drop((check, limiter));
+    // The alive count lags behind tasks that just completed, so give it a moment
+    let mut leaked_tasks = runtime.metrics().num_alive_tasks();
+    for _ in 0..10 {
+        if leaked_tasks == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        leaked_tasks = runtime.metrics().num_alive_tasks();
+    }
+    if leaked_tasks > 0 {
+        monitor.violate(format!("{leaked_tasks} tasks still alive after the soak"));
+    }
+    if !monitor.violations.lock().unwrap().is_empty() {
+        fail(&monitor, &options);
+    }
+
+    let stats = monitor.stats.snapshot();
+    eprintln!(
+        "Soaked {implementation} for {:?} without violations: allowed={} denied={}",
+        started.elapsed(),
+        stats.allowed,
+        stats.denied
+    );
+}
@@ -0,0 +1,67 @@
+use chrono::{DateTime, Duration, Utc};
+
+// Whether a request made exactly one window before a check still counts against it.
+// External contracts sometimes pin this down, so the sliding log versions take it as
+// an option, defaulting to Inclusive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Boundary {
+    // A request at exactly the cutoff is still inside the window
+    #[default]
+    Inclusive,
+    // A request at exactly the cutoff has already left the window
+    Exclusive,
+}
+
+impl Boundary {
+    // Whether a request made at `time` is inside the window starting at `cutoff`
+    pub fn contains(&self, cutoff: DateTime<Utc>, time: DateTime<Utc>) -> bool {
+        match self {
+            Boundary::Inclusive => time >= cutoff,
+            Boundary::Exclusive => time > cutoff,
+        }
+    }
+
+    // The first moment a request made at `time` no longer counts against a window
+    pub fn expiry(&self, time: DateTime<Utc>, window: Duration) -> DateTime<Utc> {
+        match self {
+            Boundary::Inclusive => time + window + Duration::nanoseconds(1),
+            Boundary::Exclusive => time + window,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_boundary_at_cutoff() {
+        let cutoff = Utc::now();
+
+        assert!(Boundary::Inclusive.contains(cutoff, cutoff));
+        assert!(!Boundary::Exclusive.contains(cutoff, cutoff));
+
+        let before = cutoff - Duration::nanoseconds(1);
+        let after = cutoff + Duration::nanoseconds(1);
+        for boundary in [Boundary::Inclusive, Boundary::Exclusive] {
+            assert!(!boundary.contains(cutoff, before));
+            assert!(boundary.contains(cutoff, after));
+        }
+    }
+
+    #[test]
+    fn test_boundary_expiry_is_first_moment_outside() {
+        let time = Utc::now();
+        let window = Duration::seconds(1);
+
+        for boundary in [Boundary::Inclusive, Boundary::Exclusive] {
+            let expiry = boundary.expiry(time, window);
+            assert_eq!(boundary.contains(expiry - window, time), false);
+            assert_eq!(
+                boundary.contains(expiry - window - Duration::nanoseconds(1), time),
+                true
+            );
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::clock::timestamp_nanos;
+use crate::sketch::WindowSketch;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+
+// The share of the budget set aside for the sketch counting new keys once the rest is
+// spent
+const SKETCH_SHARE: usize = 8;
+
+// What a limiter reports as it runs into its memory budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryEvent {
+    // A new key found the budget spent, so every key all of whose requests left its
+    // window was evicted
+    EmergencyEviction { used_bytes: usize, evicted: usize },
+    // Evicting didn't free enough, so new keys are counted in a fixed size sketch
+    // rather than tracked one by one, until enough keys go idle
+    Degraded { used_bytes: usize },
+    // New keys are tracked one by one again
+    Recovered { used_bytes: usize },
+}
+
+// Keeps count of the bytes a limiter's keys take up, estimated like planning does
+// from the sizes of the types it stores, and decides what happens to new keys once
+// they reach the budget
+pub(crate) struct MemoryBudget {
+    // What the keys may take up, the budget less the sketch
+    keys_bytes: usize,
+    used_bytes: AtomicUsize,
+    degraded: AtomicBool,
+    // Nanoseconds since the epoch of the latest emergency eviction
+    last_eviction: AtomicI64,
+    sketch: WindowSketch,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(bytes: usize, used_bytes: usize) -> Self {
+        let sketch = WindowSketch::with_bytes(bytes / SKETCH_SHARE);
+        MemoryBudget {
+            keys_bytes: bytes.saturating_sub(sketch.bytes()),
+            used_bytes: AtomicUsize::new(used_bytes),
+            degraded: AtomicBool::new(false),
+            last_eviction: AtomicI64::new(i64::MIN),
+            sketch,
+        }
+    }
+
+    pub(crate) fn sketch(&self) -> &WindowSketch {
+        &self.sketch
+    }
+
+    pub(crate) fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn grow(&self, bytes: usize) {
+        self.used_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn shrink(&self, bytes: usize) {
+        self.used_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_spent(&self) -> bool {
+        self.used_bytes() >= self.keys_bytes
+    }
+
+    // Evicting only frees keys whose requests left their window, which takes time, so
+    // keys are swept at most once per `window`, however many new keys come in
+    pub(crate) fn should_evict(&self, timestamp: DateTime<Utc>, window: Duration) -> bool {
+        let now = timestamp_nanos(timestamp);
+        let window = window.num_nanoseconds().unwrap_or(i64::MAX);
+        let last = self.last_eviction.load(Ordering::Relaxed);
+        last.saturating_add(window) <= now
+            && self
+                .last_eviction
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    // The event to report, if new keys weren't counted in the sketch already
+    pub(crate) fn degrade(&self) -> Option<MemoryEvent> {
+        (!self.degraded.swap(true, Ordering::Relaxed)).then(|| MemoryEvent::Degraded {
+            used_bytes: self.used_bytes(),
+        })
+    }
+
+    // The event to report, if new keys were counted in the sketch until now
+    pub(crate) fn recover(&self) -> Option<MemoryEvent> {
+        // Only loads on the hot path, so checks don't contend for the flag
+        if !self.degraded.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.degraded
+            .swap(false, Ordering::Relaxed)
+            .then(|| MemoryEvent::Recovered {
+                used_bytes: self.used_bytes(),
+            })
+    }
+}
+
+impl std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("keys_bytes", &self.keys_bytes)
+            .field("used_bytes", &self.used_bytes)
+            .field("degraded", &self.degraded)
+            .field("sketch_bytes", &self.sketch.bytes())
+            .finish()
+    }
+}
@@ -0,0 +1,352 @@
+use crate::lifecycle::{KeySummary, Lifecycle};
+use crate::{
+    Clock, ClockedRateLimiter, MemoryEvent, RateLimiter8, SystemClock, MAX_REQUESTS,
+    MAX_REQUESTS_DURATION_SECONDS,
+};
+use chrono::Duration;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+// When a limiter drops the state of keys it tracks. Keys are never dropped by default,
+// so a limiter facing many keys, such as every IP on the internet, only grows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    #[default]
+    Never,
+    // Once more than `max_keys` keys are tracked, a check drops every key all of whose
+    // requests left its window. Those would be admitted like new keys anyway, so no
+    // decision changes. Keys that are still limited are kept, so the limiter can hold
+    // more than `max_keys` keys when that many are active.
+    Idle {
+        max_keys: usize,
+    },
+}
+
+// A configuration RateLimiterBuilder refuses to build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    // Nothing would ever be admitted
+    ZeroLimit,
+    // Zero or negative, so no request would ever count against the limit
+    ZeroWindow,
+    // The map's shards have to be a power of two greater than 1
+    InvalidShards { shards: usize },
+    // No key would ever be tracked
+    ZeroMemoryBudget,
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::ZeroLimit => write!(f, "the limit must admit at least 1 request"),
+            BuildError::ZeroWindow => write!(f, "the window must be longer than zero"),
+            BuildError::InvalidShards { shards } => {
+                write!(f, "{} shards isn't a power of two greater than 1", shards)
+            }
+            BuildError::ZeroMemoryBudget => write!(f, "the memory budget must be above zero"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// Configures a limiter in one place, checking the configuration as a whole rather than
+// panicking or misbehaving on the first check. It builds a RateLimiter8, which is
+// strict and only locks the shard of the key being checked, behind a
+// ClockedRateLimiter reading the time of every check from the builder's clock. Every
+// setting defaults to what RateLimiter8::new uses.
+#[derive(Debug)]
+pub struct RateLimiterBuilder<K = IpAddr, C = SystemClock> {
+    max_requests: usize,
+    window: Duration,
+    initial_capacity: usize,
+    shards: Option<usize>,
+    clock: C,
+    eviction: Eviction,
+    memory_budget: Option<usize>,
+    lifecycle: Lifecycle<K>,
+}
+
+impl<K> Default for RateLimiterBuilder<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> RateLimiterBuilder<K> {
+    pub fn new() -> Self {
+        RateLimiterBuilder {
+            max_requests: MAX_REQUESTS,
+            window: Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+            initial_capacity: 0,
+            shards: None,
+            clock: SystemClock,
+            eviction: Eviction::Never,
+            memory_budget: None,
+            lifecycle: Lifecycle::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, C: Clock> RateLimiterBuilder<K, C> {
+    // Admits up to `max_requests` per key within any `window`
+    pub fn with_limit(mut self, max_requests: usize, window: Duration) -> Self {
+        self.max_requests = max_requests;
+        self.window = window;
+        self
+    }
+
+    // The keys to allocate room for up front
+    pub fn with_initial_capacity(mut self, keys: usize) -> Self {
+        self.initial_capacity = keys;
+        self
+    }
+
+    // 4 per core by default
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.shards = Some(shards);
+        self
+    }
+
+    pub fn with_clock<D: Clock>(self, clock: D) -> RateLimiterBuilder<K, D> {
+        RateLimiterBuilder {
+            max_requests: self.max_requests,
+            window: self.window,
+            initial_capacity: self.initial_capacity,
+            shards: self.shards,
+            clock,
+            eviction: self.eviction,
+            memory_budget: self.memory_budget,
+            lifecycle: self.lifecycle,
+        }
+    }
+
+    pub fn with_eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    // See RateLimiter8::with_memory_budget, unbounded by default
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    // See RateLimiter8::with_on_first_seen
+    pub fn with_on_first_seen(mut self, callback: impl Fn(&K) + Send + Sync + 'static) -> Self {
+        self.lifecycle = self.lifecycle.with_on_first_seen(callback);
+        self
+    }
+
+    // See RateLimiter8::with_on_evicted
+    pub fn with_on_evicted(
+        mut self,
+        callback: impl Fn(&K, KeySummary) + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle = self.lifecycle.with_on_evicted(callback);
+        self
+    }
+
+    // See RateLimiter8::with_on_memory_event
+    pub fn with_on_memory_event(
+        mut self,
+        callback: impl Fn(MemoryEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle = self.lifecycle.with_on_memory_event(callback);
+        self
+    }
+
+    pub fn build(self) -> Result<ClockedRateLimiter<RateLimiter8<K>, C>, BuildError> {
+        if self.max_requests == 0 {
+            return Err(BuildError::ZeroLimit);
+        }
+        if self.window <= Duration::zero() {
+            return Err(BuildError::ZeroWindow);
+        }
+        if self.memory_budget == Some(0) {
+            return Err(BuildError::ZeroMemoryBudget);
+        }
+
+        let limiter = match self.shards {
+            None => {
+                RateLimiter8::with_capacity(self.max_requests, self.window, self.initial_capacity)
+            }
+            Some(shards) if shards > 1 && shards.is_power_of_two() => {
+                RateLimiter8::with_capacity_and_shards(
+                    self.max_requests,
+                    self.window,
+                    self.initial_capacity,
+                    shards,
+                )
+            }
+            Some(shards) => return Err(BuildError::InvalidShards { shards }),
+        };
+        let limiter = limiter
+            .with_eviction(self.eviction)
+            .with_lifecycle(self.lifecycle);
+        let limiter = match self.memory_budget {
+            Some(bytes) => limiter.with_memory_budget(bytes),
+            None => limiter,
+        };
+        Ok(ClockedRateLimiter::with_clock(limiter, self.clock))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, TrackedKeys};
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_builder_rejects_invalid_configurations() {
+        let build = |builder: RateLimiterBuilder| builder.build().err();
+
+        assert_eq!(build(RateLimiterBuilder::new()), None);
+        assert_eq!(
+            build(RateLimiterBuilder::new().with_limit(0, Duration::seconds(1))),
+            Some(BuildError::ZeroLimit)
+        );
+        assert_eq!(
+            build(RateLimiterBuilder::new().with_limit(10, Duration::zero())),
+            Some(BuildError::ZeroWindow)
+        );
+        assert_eq!(
+            build(RateLimiterBuilder::new().with_limit(10, Duration::seconds(-1))),
+            Some(BuildError::ZeroWindow)
+        );
+        for shards in [0, 1, 12] {
+            assert_eq!(
+                build(RateLimiterBuilder::new().with_shards(shards)),
+                Some(BuildError::InvalidShards { shards })
+            );
+        }
+        assert_eq!(build(RateLimiterBuilder::new().with_shards(16)), None);
+        assert_eq!(
+            build(RateLimiterBuilder::new().with_memory_budget(0)),
+            Some(BuildError::ZeroMemoryBudget)
+        );
+    }
+
+    #[test]
+    fn test_builder_builds_a_limiter_on_its_clock() {
+        let clock = ManualClock::default();
+        let rate_limiter = RateLimiterBuilder::new()
+            .with_limit(3, Duration::seconds(10))
+            .with_initial_capacity(1024)
+            .with_shards(8)
+            .with_clock(&clock)
+            .build()
+            .unwrap();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        let admitted = || (0..5).filter(|_| rate_limiter.check(ip)).count();
+        assert_eq!(admitted(), 3);
+        clock.advance(Duration::seconds(11));
+        assert_eq!(admitted(), 3);
+    }
+
+    #[test]
+    fn test_builder_with_eviction_drops_idle_keys() {
+        let clock = ManualClock::default();
+        let rate_limiter = RateLimiterBuilder::new()
+            .with_limit(1, Duration::seconds(10))
+            .with_clock(&clock)
+            .with_eviction(Eviction::Idle { max_keys: 4 })
+            .build()
+            .unwrap();
+
+        for i in 0..4u8 {
+            assert_eq!(rate_limiter.check(IpAddr::from([10, 0, 0, i])), true);
+        }
+        clock.advance(Duration::seconds(11));
+
+        // The fifth key goes over max_keys, and the four idle ones are swept out
+        assert_eq!(rate_limiter.check(IpAddr::from([10, 0, 0, 4])), true);
+        assert_eq!(rate_limiter.limiter().tracked_keys(), 1);
+    }
+
+    #[test]
+    fn test_builder_with_lifecycle_callbacks() {
+        let clock = ManualClock::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let rate_limiter = RateLimiterBuilder::new()
+            .with_limit(2, Duration::seconds(10))
+            .with_clock(&clock)
+            .with_eviction(Eviction::Idle { max_keys: 1 })
+            .with_on_first_seen({
+                let seen = Arc::clone(&seen);
+                move |ip: &IpAddr| seen.lock().unwrap().push(*ip)
+            })
+            .with_on_evicted({
+                let evicted = Arc::clone(&evicted);
+                move |ip: &IpAddr, summary| evicted.lock().unwrap().push((*ip, summary))
+            })
+            .build()
+            .unwrap();
+        let (ip, other_ip): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let start = clock.now();
+
+        for _ in 0..3 {
+            rate_limiter.check(ip);
+        }
+        clock.advance(Duration::seconds(11));
+        rate_limiter.check(other_ip);
+
+        assert_eq!(*seen.lock().unwrap(), vec![ip, other_ip]);
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            vec![(
+                ip,
+                KeySummary {
+                    first_seen: start,
+                    last_seen: start,
+                    admitted: 2,
+                    denied: 1,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_builder_with_memory_budget_evicts_then_degrades() {
+        let clock = ManualClock::default();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let rate_limiter = RateLimiterBuilder::new()
+            .with_limit(1, Duration::seconds(10))
+            .with_clock(&clock)
+            .with_memory_budget(64 * 1024)
+            .with_on_memory_event({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event)
+            })
+            .build()
+            .unwrap();
+
+        // Fills the budget, less the eighth set aside for the sketch, with keys that go
+        // idle
+        let mut i = 0u32;
+        while rate_limiter.limiter().memory_used8() < 56 * 1024 {
+            assert_eq!(rate_limiter.check(IpAddr::from(i.to_be_bytes())), true);
+            i += 1;
+        }
+        let tracked = rate_limiter.limiter().tracked_keys();
+        assert!(tracked > 0);
+        clock.advance(Duration::seconds(11));
+
+        // The next new key evicts the idle ones and is tracked
+        let ip = IpAddr::from(u32::MAX.to_be_bytes());
+        assert_eq!(rate_limiter.check(ip), true);
+        assert_eq!(rate_limiter.limiter().tracked_keys(), 1);
+        assert_eq!(
+            events.lock().unwrap().first(),
+            Some(&MemoryEvent::EmergencyEviction {
+                used_bytes: 0,
+                evicted: tracked,
+            })
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use super::*;
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+
+// How a client should back off from a rate limited server. Delays start at
+// `initial`, grow by `multiplier` on every denial up to `max`, and decay back by the
+// same factor on every success. A server provided retry-after is always respected,
+// even when it exceeds `max`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackoffPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    // Delays are randomly stretched by up to this fraction, so clients that were
+    // denied together don't retry together
+    pub jitter: f64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy {
+            initial: Duration::milliseconds(100),
+            max: Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+            multiplier: 2.0,
+            jitter: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: BackoffPolicy,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(policy: BackoffPolicy) -> Self {
+        Backoff {
+            current: policy.initial,
+            policy,
+        }
+    }
+
+    // Recommends how long to wait after a denial, growing the delay for the next one
+    pub fn on_denied(&mut self, retry_after: Option<Duration>) -> Duration {
+        let stretch = 1.0 + rand::thread_rng().gen_range(0.0..=self.policy.jitter.max(0.0));
+        let delay = scale(self.current, stretch).min(self.policy.max);
+
+        self.current = scale(self.current, self.policy.multiplier).min(self.policy.max);
+        retry_after.map_or(delay, |retry_after| delay.max(retry_after))
+    }
+
+    // Like on_denied, taking the retry-after from a peeked quota
+    pub fn on_quota(&mut self, quota: &Quota, now: DateTime<Utc>) -> Option<Duration> {
+        if quota.remaining > 0 {
+            return None;
+        }
+        Some(self.on_denied(Some(quota.next_admission(now) - now)))
+    }
+
+    pub fn on_success(&mut self) {
+        self.current = scale(self.current, 1.0 / self.policy.multiplier).max(self.policy.initial);
+    }
+}
+
+fn scale(duration: Duration, factor: f64) -> Duration {
+    Duration::microseconds((duration.num_microseconds().unwrap_or(i64::MAX) as f64 * factor) as i64)
+}
+
+// Parses a Retry-After header value, either in delta-seconds or as an HTTP-date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u32>() {
+        return Some(Duration::seconds(seconds.into()));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((at.with_timezone(&Utc) - now).max(Duration::zero()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    fn without_jitter() -> BackoffPolicy {
+        BackoffPolicy {
+            jitter: 0.0,
+            ..BackoffPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_and_decays() {
+        let mut backoff = Backoff::new(without_jitter());
+
+        assert_eq!(backoff.on_denied(None), Duration::milliseconds(100));
+        assert_eq!(backoff.on_denied(None), Duration::milliseconds(200));
+        assert_eq!(backoff.on_denied(None), Duration::milliseconds(400));
+
+        backoff.on_success();
+        assert_eq!(backoff.on_denied(None), Duration::milliseconds(400));
+        backoff.on_success();
+        backoff.on_success();
+        backoff.on_success();
+        assert_eq!(backoff.on_denied(None), Duration::milliseconds(100));
+    }
+
+    #[test]
+    fn test_backoff_is_capped_but_respects_retry_after() {
+        let policy = BackoffPolicy {
+            max: Duration::seconds(1),
+            ..without_jitter()
+        };
+        let mut backoff = Backoff::new(policy);
+
+        for _ in 0..10 {
+            assert!(backoff.on_denied(None) <= Duration::seconds(1));
+        }
+        assert_eq!(
+            backoff.on_denied(Some(Duration::seconds(30))),
+            Duration::seconds(30)
+        );
+    }
+
+    #[test]
+    fn test_backoff_jitter_is_bounded() {
+        let mut backoff = Backoff::new(BackoffPolicy {
+            jitter: 0.5,
+            ..BackoffPolicy::default()
+        });
+
+        let delay = backoff.on_denied(None);
+        assert!(delay >= Duration::milliseconds(100) && delay <= Duration::milliseconds(150));
+    }
+
+    #[test]
+    fn test_backoff_on_quota_waits_for_reset() {
+        let mut backoff = Backoff::new(without_jitter());
+        let now = Utc::now();
+        let exhausted = Quota {
+            limit: MAX_REQUESTS,
+            remaining: 0,
+            reset: now + Duration::seconds(5),
+        };
+
+        assert_eq!(
+            backoff.on_quota(&exhausted, now),
+            Some(Duration::seconds(5))
+        );
+        assert_eq!(
+            backoff.on_quota(
+                &Quota {
+                    remaining: 1,
+                    ..exhausted
+                },
+                now
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 0).unwrap();
+
+        assert_eq!(parse_retry_after("120", now), Some(Duration::seconds(120)));
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::seconds(37))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:48:00 GMT", now),
+            Some(Duration::zero())
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::RateLimit;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+
+// Where the time of a check comes from, when callers don't pass it themselves
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// Only moves when told to, so tests can let time pass within a single limiter
+// without sleeping. Share it through a reference or an Arc to keep a handle on it.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    // Can move the clock backwards too, to test how limiters handle a clock jump
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+// Nanoseconds since the epoch, as the atomic limiters store them. Timestamps outside
+// the years 1677 to 2262 don't fit an i64, so they saturate to its bounds rather than
+// panicking.
+pub(crate) fn timestamp_nanos(timestamp: DateTime<Utc>) -> i64 {
+    timestamp
+        .timestamp_nanos_opt()
+        .unwrap_or(if timestamp < DateTime::UNIX_EPOCH {
+            i64::MIN
+        } else {
+            i64::MAX
+        })
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> DateTime<Utc> {
+        (**self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> DateTime<Utc> {
+        (**self).now()
+    }
+}
+
+// Checks requests against a limiter at the time of its clock, rather than at a time
+// every caller has to pass
+#[derive(Debug, Default)]
+pub struct ClockedRateLimiter<L, C = SystemClock> {
+    limiter: L,
+    clock: C,
+}
+
+impl<L> ClockedRateLimiter<L> {
+    pub fn new(limiter: L) -> Self {
+        Self::with_clock(limiter, SystemClock)
+    }
+}
+
+impl<L, C: Clock> ClockedRateLimiter<L, C> {
+    pub fn with_clock(limiter: L, clock: C) -> Self {
+        ClockedRateLimiter { limiter, clock }
+    }
+
+    // Records the request at the clock's current time and returns whether it is
+    // admitted
+    pub fn check<K>(&self, key: K) -> bool
+    where
+        L: RateLimit<K>,
+    {
+        self.limiter.check(key, self.clock.now())
+    }
+
+    pub fn check_with_cost<K>(&self, key: K, cost: u32) -> bool
+    where
+        L: RateLimit<K>,
+    {
+        self.limiter.check_with_cost(key, self.clock.now(), cost)
+    }
+
+    pub fn limiter(&self) -> &L {
+        &self.limiter
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RateLimiter0, RateLimiter2, MAX_REQUESTS, MAX_REQUESTS_DURATION_SECONDS};
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_timestamp_nanos_saturates() {
+        let epoch = DateTime::UNIX_EPOCH;
+        assert_eq!(timestamp_nanos(epoch + Duration::nanoseconds(7)), 7);
+        assert_eq!(timestamp_nanos(DateTime::<Utc>::MIN_UTC), i64::MIN);
+        assert_eq!(timestamp_nanos(DateTime::<Utc>::MAX_UTC), i64::MAX);
+    }
+
+    #[test]
+    fn test_manual_clock_only_moves_when_told() {
+        let start = Utc::now();
+        let clock = ManualClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::seconds(5));
+        assert_eq!(clock.now(), start + Duration::seconds(5));
+
+        clock.set(start - Duration::seconds(1));
+        assert_eq!(clock.now(), start - Duration::seconds(1));
+    }
+
+    #[test]
+    fn test_clocked_ratelimiter_admits_again_once_the_window_passes() {
+        let clock = ManualClock::default();
+        let rate_limiter = ClockedRateLimiter::with_clock(RateLimiter2::new(), &clock);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.check(ip), true);
+        }
+        assert_eq!(rate_limiter.check(ip), false);
+
+        clock.advance(Duration::seconds(MAX_REQUESTS_DURATION_SECONDS / 2));
+        assert_eq!(rate_limiter.check(ip), false);
+
+        clock.advance(Duration::seconds(MAX_REQUESTS_DURATION_SECONDS / 2 + 1));
+        assert_eq!(rate_limiter.check(ip), true);
+    }
+
+    #[test]
+    fn test_clocked_ratelimiter_uses_the_system_clock_by_default() {
+        let rate_limiter = ClockedRateLimiter::new(RateLimiter0::new());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let before = Utc::now();
+
+        assert_eq!(rate_limiter.check(ip), true);
+        let quota = rate_limiter.limiter().peek0(ip, Utc::now());
+        assert_eq!(quota.remaining, MAX_REQUESTS - 1);
+        assert!(quota.reset >= before + Duration::seconds(MAX_REQUESTS_DURATION_SECONDS));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::stats::Stats;
+use crate::RateLimit;
+use chrono::{DateTime, Utc};
+use crossbeam_skiplist::SkipMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// Counts requests submitted for a key at the same timestamp and cost as its previous
+// one, which happens whenever callers reuse a cached `now`. Every such duplicate takes up
+// another slot in the window, unless deduplication is enabled, in which case it gets
+// the decision of the original request without being checked again.
+//
+// The last timestamp of every key seen is kept, so like the limiters this grows with
+// the number of keys.
+#[derive(Debug)]
+pub struct CoalescingRateLimiter<L, K: Ord = IpAddr> {
+    limiter: L,
+    last: SkipMap<K, Mutex<Option<LastRequest>>>,
+    deduplicate: bool,
+    stats: Stats,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LastRequest {
+    at: DateTime<Utc>,
+    cost: u32,
+    admitted: bool,
+}
+
+impl<L, K: Ord + Clone + Send + 'static> CoalescingRateLimiter<L, K> {
+    pub fn new(limiter: L) -> Self {
+        CoalescingRateLimiter {
+            limiter,
+            last: SkipMap::new(),
+            deduplicate: false,
+            stats: Stats::new(),
+        }
+    }
+
+    // Answers duplicates with the decision of the original request instead of
+    // checking them again
+    pub fn with_deduplicate(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    // Every decision, including those of duplicates, and the number of duplicates
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn limiter(&self) -> &L {
+        &self.limiter
+    }
+}
+
+impl<L: RateLimit<K>, K: Ord + Clone + Send + 'static> RateLimit<K>
+    for CoalescingRateLimiter<L, K>
+{
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let entry = self
+            .last
+            .get_or_insert_with(key.clone(), || Mutex::new(None));
+        // Held across the check, so concurrent duplicates can't both be checked
+        let mut last = entry.value().lock().unwrap();
+
+        let admitted = match *last {
+            // A different cost at the same time is another request, and its own
+            // decision may differ
+            Some(LastRequest {
+                at,
+                cost: last_cost,
+                admitted,
+            }) if at == timestamp && last_cost == cost => {
+                self.stats.record_duplicate();
+                if self.deduplicate {
+                    admitted
+                } else {
+                    self.limiter.check_with_cost(key, timestamp, cost)
+                }
+            }
+            _ => self.limiter.check_with_cost(key, timestamp, cost),
+        };
+
+        *last = Some(LastRequest {
+            at: timestamp,
+            cost,
+            admitted,
+        });
+        self.stats.record(admitted);
+        admitted
+    }
+
+    // A peek records nothing, so there is no duplicate of it to coalesce
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.limiter.peek(key, timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::StatsSnapshot;
+    use crate::RateLimiter2;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_coalescing_ratelimiter_counts_duplicates() {
+        let rate_limiter =
+            CoalescingRateLimiter::new(RateLimiter2::with_config(2, Duration::seconds(60)));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.check(ip, now), true);
+        assert_eq!(rate_limiter.check(ip, now), true);
+        assert_eq!(rate_limiter.check(ip, now), false);
+        assert_eq!(rate_limiter.check(ip, now + Duration::seconds(1)), false);
+
+        assert_eq!(
+            rate_limiter.stats().snapshot(),
+            StatsSnapshot {
+                allowed: 2,
+                denied: 2,
+                duplicates: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_coalescing_ratelimiter_deduplicates() {
+        let rate_limiter =
+            CoalescingRateLimiter::new(RateLimiter2::with_config(2, Duration::seconds(60)))
+                .with_deduplicate(true);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let other_ip = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.check(ip, now), true);
+        }
+        // Only the first of them took up a slot
+        assert_eq!(rate_limiter.check(ip, now + Duration::seconds(1)), true);
+        assert_eq!(rate_limiter.check(ip, now + Duration::seconds(2)), false);
+        // Duplicates are per key
+        assert_eq!(rate_limiter.check(other_ip, now), true);
+
+        assert_eq!(rate_limiter.stats().snapshot().duplicates, 4);
+    }
+
+    #[test]
+    fn test_coalescing_ratelimiter_only_deduplicates_the_same_cost() {
+        let rate_limiter =
+            CoalescingRateLimiter::new(RateLimiter2::with_config(5, Duration::seconds(60)))
+                .with_deduplicate(true);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 1), true);
+        // Doesn't fit the 4 slots left, however the cheaper request went
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 5), false);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 5), false);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 4), true);
+        assert_eq!(rate_limiter.check(ip, now + Duration::seconds(1)), false);
+
+        assert_eq!(
+            rate_limiter.stats().snapshot(),
+            StatsSnapshot {
+                allowed: 2,
+                denied: 3,
+                duplicates: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+// A fixed-length byte fingerprint, such as a JA3 hash or a hashed user agent, that
+// keys the limiters in place of an IP address. It is Copy and no larger than the
+// hash itself, so fingerprint keyed limiters cost about the same as IP keyed ones.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fingerprint<const N: usize>([u8; N]);
+
+// JA3 fingerprints are MD5 hashes, usually passed on by the TLS terminator in hex
+pub type Ja3 = Fingerprint<16>;
+
+impl<const N: usize> Fingerprint<N> {
+    pub const fn new(bytes: [u8; N]) -> Self {
+        Fingerprint(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl Fingerprint<8> {
+    // Hashes arbitrary data, such as a User-Agent header or a JA4 string, into a
+    // fingerprint. The hash is only stable within one build, so these fingerprints
+    // must not be persisted or shared between instances.
+    pub fn of(data: impl Hash) -> Self {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        Fingerprint(hasher.finish().to_be_bytes())
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Fingerprint<N> {
+    fn from(bytes: [u8; N]) -> Self {
+        Fingerprint(bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FingerprintParseError;
+
+impl fmt::Display for FingerprintParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid fingerprint syntax")
+    }
+}
+
+impl std::error::Error for FingerprintParseError {}
+
+// Parses exactly 2 * N hex digits, in either case
+impl<const N: usize> FromStr for Fingerprint<N> {
+    type Err = FingerprintParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != N * 2 || !s.is_ascii() {
+            return Err(FingerprintParseError);
+        }
+
+        let mut bytes = [0; N];
+        for (byte, digits) in bytes.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| FingerprintParseError)?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| FingerprintParseError)?;
+        }
+        Ok(Fingerprint(bytes))
+    }
+}
+
+impl<const N: usize> fmt::Display for Fingerprint<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl<const N: usize> fmt::Debug for Fingerprint<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fingerprint({self})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RateLimiter0, RateLimiter2, MAX_REQUESTS};
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+
+    const JA3: &str = "e7d705a3286e19ea42f587b344ee6865";
+
+    #[test]
+    fn test_fingerprint_hex_round_trip() {
+        let fingerprint = JA3.parse::<Ja3>().unwrap();
+
+        assert_eq!(fingerprint.to_string(), JA3);
+        assert_eq!(fingerprint, JA3.to_uppercase().parse().unwrap());
+        assert_eq!(fingerprint.as_bytes()[0], 0xe7);
+    }
+
+    #[test]
+    fn test_fingerprint_rejects_invalid_hex() {
+        assert_eq!("e7d7".parse::<Ja3>(), Err(FingerprintParseError));
+        assert_eq!(
+            "g7d705a3286e19ea42f587b344ee6865".parse::<Ja3>(),
+            Err(FingerprintParseError)
+        );
+        assert_eq!(
+            "é7d705a3286e19ea42f587b344ee686".parse::<Ja3>(),
+            Err(FingerprintParseError)
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_keys_limiters() {
+        let rate_limiter0 = RateLimiter0::new();
+        let rate_limiter2 = RateLimiter2::new();
+        let bot = Fingerprint::of("curl/8.4.0");
+        let browser = Fingerprint::of("Mozilla/5.0");
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert!(rate_limiter0.ratelimit0(bot, now));
+            assert!(rate_limiter2.ratelimit2(bot, now));
+        }
+        assert!(!rate_limiter0.ratelimit0(bot, now));
+        assert!(!rate_limiter2.ratelimit2(bot, now));
+        assert!(rate_limiter0.ratelimit0(browser, now));
+        assert!(rate_limiter2.ratelimit2(browser, now));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::clock::timestamp_nanos;
+use crate::{
+    gcra, relaxed_gcra, RateLimit, Strictness, MAX_REQUESTS, MAX_REQUESTS_DURATION_SECONDS,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+// Caps the total throughput of a process, whatever the key. It admits like a single
+// key of RateLimiter6, so all it keeps is one theoretical arrival time in an atomic,
+// and a check is a load and a compare and swap.
+//
+// That makes it GCRA rather than the sliding window of the keyed versions: it admits
+// a burst of `max_requests` and then sustains `max_requests` per `window`, so up to
+// twice the limit can fall within one window. A shared sliding log would have every
+// check copy or lock the log of the whole process. with_strictness trades the burst
+// for the bound instead, down to never admitting more than the limit within any
+// window when Strict.
+#[derive(Debug)]
+pub struct GlobalRateLimiter {
+    // Nanoseconds since the epoch
+    tat: AtomicI64,
+    max_requests: usize,
+    window: Duration,
+    strictness: Strictness,
+    emission_interval_ns: i64,
+    tolerance_ns: i64,
+}
+
+impl GlobalRateLimiter {
+    // Like RateLimiter6, a burst at the start of a window followed by the sustained
+    // rate admits up to twice the limit within it, see with_strictness
+    pub const STRICTNESS: Strictness = Strictness::Relaxed { epsilon: 1.0 };
+
+    pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Allows bursts of `max_requests`, and sustains `max_requests` per `window`
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        let (emission_interval_ns, tolerance_ns) = gcra(max_requests, window);
+        GlobalRateLimiter {
+            tat: AtomicI64::new(i64::MIN),
+            max_requests,
+            window,
+            strictness: GlobalRateLimiter::STRICTNESS,
+            emission_interval_ns,
+            tolerance_ns,
+        }
+    }
+
+    // Trades the burst for how much may be over-admitted within a window, like
+    // RateLimiter6::with_strictness. Strict bursts a single request and never admits
+    // more than `max_requests` within any window. Unbounded is taken as the default.
+    pub fn with_strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        (self.emission_interval_ns, self.tolerance_ns) =
+            relaxed_gcra(self.max_requests, self.window, self.epsilon());
+        self
+    }
+
+    // How far this limiter may over-admit within a window
+    pub fn strictness(&self) -> Strictness {
+        let epsilon = self.epsilon();
+        if epsilon == 0.0 {
+            Strictness::Strict
+        } else {
+            Strictness::Relaxed { epsilon }
+        }
+    }
+
+    fn epsilon(&self) -> f64 {
+        self.strictness.epsilon().unwrap_or(1.0)
+    }
+
+    pub fn ratelimit(&self, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost(timestamp, 1)
+    }
+
+    pub fn ratelimit_with_cost(&self, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let now = timestamp_nanos(timestamp);
+        let increment = self.emission_interval_ns.saturating_mul(cost as i64);
+
+        let mut current = self.tat.load(Ordering::Acquire);
+        loop {
+            let next = current.max(now).saturating_add(increment);
+            if next.saturating_sub(now) > self.tolerance_ns {
+                return false;
+            }
+            match self
+                .tat
+                .compare_exchange_weak(current, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Default for GlobalRateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Ignores the key, so it can stand in for a keyed limiter, such as behind a
+// RateLimitLayer
+impl<K> RateLimit<K> for GlobalRateLimiter {
+    fn check(&self, _key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit(timestamp)
+    }
+
+    fn check_with_cost(&self, _key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost(timestamp, cost)
+    }
+
+    fn peek(&self, _key: K, timestamp: DateTime<Utc>) -> bool {
+        let now = timestamp_nanos(timestamp);
+        let next = self
+            .tat
+            .load(Ordering::Acquire)
+            .max(now)
+            .saturating_add(self.emission_interval_ns);
+        next.saturating_sub(now) <= self.tolerance_ns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+    use std::thread;
+
+    #[test]
+    fn test_global_ratelimiter_limits_every_key_together() {
+        let rate_limiter = GlobalRateLimiter::with_config(10, Duration::seconds(1));
+        let now = Utc::now();
+
+        let admitted = (0..20u8)
+            .filter(|&i| rate_limiter.check(IpAddr::from([10, 0, 0, i]), now))
+            .count();
+        assert_eq!(admitted, 10);
+
+        // The sustained rate is one request every 100ms
+        let later = now + Duration::milliseconds(100);
+        assert_eq!(rate_limiter.ratelimit(later), true);
+        assert_eq!(rate_limiter.ratelimit(later), false);
+    }
+
+    // Pins how far the GCRA strays from a sliding window: the burst and then the
+    // sustained rate both fall within one window, unless Strict
+    #[test]
+    fn test_global_ratelimiter_bounds_every_window() {
+        let window = Duration::seconds(1);
+        let start = Utc::now();
+
+        for (rate_limiter, max_within_a_window) in [
+            (GlobalRateLimiter::with_config(10, window), 20),
+            (
+                GlobalRateLimiter::with_config(10, window).with_strictness(Strictness::Strict),
+                10,
+            ),
+        ] {
+            // Five requests every 10ms for three windows
+            let admitted: Vec<_> = (0..300)
+                .flat_map(|i| [start + Duration::milliseconds(10 * i); 5])
+                .filter(|&at| rate_limiter.ratelimit(at))
+                .collect();
+            assert_eq!(
+                crate::strictness::max_within_a_window(&admitted, window),
+                max_within_a_window
+            );
+            assert_eq!(
+                rate_limiter.strictness().max_admitted(10).unwrap(),
+                max_within_a_window
+            );
+        }
+    }
+
+    #[test]
+    fn test_global_ratelimiter_out_of_range_timestamps() {
+        let rate_limiter = GlobalRateLimiter::with_config(1, Duration::seconds(1));
+
+        assert_eq!(rate_limiter.ratelimit(DateTime::<Utc>::MIN_UTC), true);
+        assert_eq!(rate_limiter.ratelimit(DateTime::<Utc>::MIN_UTC), false);
+        assert_eq!(rate_limiter.ratelimit(DateTime::<Utc>::MAX_UTC), true);
+    }
+
+    #[test]
+    fn test_global_ratelimiter_with_cost() {
+        let rate_limiter = GlobalRateLimiter::with_config(10, Duration::seconds(1));
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit_with_cost(now, 11), false);
+        assert_eq!(rate_limiter.ratelimit_with_cost(now, 8), true);
+        assert_eq!(rate_limiter.ratelimit_with_cost(now, 3), false);
+        assert_eq!(rate_limiter.ratelimit_with_cost(now, 2), true);
+    }
+
+    #[test]
+    fn test_global_ratelimiter_under_contention() {
+        const NUM_THREADS: usize = 8;
+        let rate_limiter = GlobalRateLimiter::new();
+        let now = Utc::now();
+
+        let admitted: usize = thread::scope(|scope| {
+            let threads: Vec<_> = (0..NUM_THREADS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..MAX_REQUESTS)
+                            .filter(|_| rate_limiter.ratelimit(now))
+                            .count()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .sum()
+        });
+
+        // All at the same instant, so only the burst is admitted
+        assert_eq!(admitted, MAX_REQUESTS);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{GlobalRateLimiter, LimitProvider, RateLimit, RateLimiter0};
+use chrono::{DateTime, Utc};
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::net::IpAddr;
+
+// Enforces a per-key limit and an aggregate limit over every key in one check. The
+// key's slots are reserved first and only committed once the global limit admits the
+// request too, so a request denied by either limit consumes neither.
+#[derive(Debug)]
+pub struct HierarchicalRateLimiter<K = IpAddr, S = RandomState, L = crate::GlobalLimits> {
+    per_key: RateLimiter0<K, S, L>,
+    global: GlobalRateLimiter,
+}
+
+impl<K: Hash + Eq + Clone, S: BuildHasher, L: LimitProvider<K>> HierarchicalRateLimiter<K, S, L> {
+    pub fn new(per_key: RateLimiter0<K, S, L>, global: GlobalRateLimiter) -> Self {
+        HierarchicalRateLimiter { per_key, global }
+    }
+
+    pub fn per_key(&self) -> &RateLimiter0<K, S, L> {
+        &self.per_key
+    }
+
+    pub fn global(&self) -> &GlobalRateLimiter {
+        &self.global
+    }
+}
+
+impl<K, S, L> RateLimit<K> for HierarchicalRateLimiter<K, S, L>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+    L: LimitProvider<K>,
+{
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let Some(reservation) = self.per_key.reserve0(key, timestamp, cost) else {
+            return false;
+        };
+        if !self.global.ratelimit_with_cost(timestamp, cost) {
+            // Dropping the reservation frees the key's slots again
+            return false;
+        }
+        reservation.commit();
+        true
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.per_key.peek0(key.clone(), timestamp).remaining > 0
+            && RateLimit::<K>::peek(&self.global, key, timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+
+    fn rate_limiter() -> HierarchicalRateLimiter {
+        HierarchicalRateLimiter::new(
+            RateLimiter0::with_config(5, Duration::seconds(60)),
+            GlobalRateLimiter::with_config(8, Duration::seconds(60)),
+        )
+    }
+
+    #[test]
+    fn test_hierarchical_ratelimiter_denied_by_the_global_limit_keeps_the_key_quota() {
+        let rate_limiter = rate_limiter();
+        let (ip, other_ip): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let now = Utc::now();
+
+        let admitted = |ip| (0..5).filter(|_| rate_limiter.check(ip, now)).count();
+        assert_eq!(admitted(ip), 5);
+        assert_eq!(admitted(other_ip), 3);
+
+        // The two denied requests were rolled back
+        assert_eq!(rate_limiter.per_key().peek0(other_ip, now).remaining, 2);
+    }
+
+    #[test]
+    fn test_hierarchical_ratelimiter_denied_by_the_key_limit_keeps_the_global_quota() {
+        let rate_limiter = rate_limiter();
+        let (ip, other_ip): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let now = Utc::now();
+
+        let admitted = |ip| (0..10).filter(|_| rate_limiter.check(ip, now)).count();
+        assert_eq!(admitted(ip), 5);
+        assert_eq!(admitted(other_ip), 3);
+    }
+}
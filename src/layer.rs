@@ -0,0 +1,314 @@
+use crate::quota::too_many_requests_headers;
+use crate::RateLimit;
+use chrono::{Duration, Utc};
+use futures::future::{self, Either, Ready};
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+// Finds the key to limit a request by in its extensions. Servers record the peer
+// differently, if at all, so there is no default: peer_ip reads what axum and hand
+// written hyper services record, while tonic records a TcpConnectInfo its own key
+// function has to read.
+pub type KeyFn = fn(&http::Extensions) -> Option<IpAddr>;
+
+// Tower middleware checking every request against a limiter before it reaches the
+// wrapped service. Denied requests are answered with 429 Too Many Requests instead,
+// with a Retry-After header and the RateLimit-* headers of Quota::headers.
+//
+// Requests the key function finds no key for are answered with 400 Bad Request. With
+// a key taken from the request, such as an API key header, the client left it out,
+// and letting them through unlimited would let any client skip the limit that way.
+pub struct RateLimitLayer<L: ?Sized> {
+    limiter: Arc<L>,
+    key: KeyFn,
+    max_requests: usize,
+    window: Duration,
+    unrecorded_methods: Arc<[Method]>,
+}
+
+impl<L: ?Sized> RateLimitLayer<L> {
+    // Limits by the key `key` finds, such as peer_ip. The limiter can't be asked for
+    // its config, so `max_requests` and `window` are the ones it was built with, which
+    // the headers of a denial are worked out from.
+    pub fn new(limiter: Arc<L>, key: KeyFn, max_requests: usize, window: Duration) -> Self {
+        RateLimitLayer {
+            limiter,
+            key,
+            max_requests,
+            window,
+            unrecorded_methods: Arc::new([]),
+        }
+    }
+
+    // Requests with these methods, such as HEAD and OPTIONS for CORS preflights, are
+    // only peeked at. They are denied whenever another request would be, but don't
+    // use up the quota.
+    pub fn with_unrecorded_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.unrecorded_methods = methods.into_iter().collect();
+        self
+    }
+}
+
+impl<L: ?Sized> Clone for RateLimitLayer<L> {
+    fn clone(&self) -> Self {
+        RateLimitLayer {
+            limiter: Arc::clone(&self.limiter),
+            key: self.key,
+            max_requests: self.max_requests,
+            window: self.window,
+            unrecorded_methods: Arc::clone(&self.unrecorded_methods),
+        }
+    }
+}
+
+impl<S, L: ?Sized> Layer<S> for RateLimitLayer<L> {
+    type Service = RateLimitService<S, L>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub struct RateLimitService<S, L: ?Sized> {
+    inner: S,
+    layer: RateLimitLayer<L>,
+}
+
+impl<S: Clone, L: ?Sized> Clone for RateLimitService<S, L> {
+    fn clone(&self) -> Self {
+        RateLimitService {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+impl<S, L, B, ResBody> Service<Request<B>> for RateLimitService<S, L>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    L: RateLimit + ?Sized,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Response<ResBody>, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let now = Utc::now();
+        let mut response = Response::new(ResBody::default());
+        let admit = |ip| {
+            if self.layer.unrecorded_methods.contains(request.method()) {
+                self.layer.limiter.peek(ip, now)
+            } else {
+                self.layer.limiter.check(ip, now)
+            }
+        };
+        match (self.layer.key)(request.extensions()) {
+            Some(ip) if admit(ip) => {
+                return Either::Left(self.inner.call(request));
+            }
+            Some(_) => {
+                *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                let headers =
+                    too_many_requests_headers(self.layer.max_requests, self.layer.window, now);
+                for (name, value) in headers {
+                    let value = HeaderValue::from_str(&value).expect("Header values are digits");
+                    response.headers_mut().insert(name, value);
+                }
+            }
+            None => *response.status_mut() = StatusCode::BAD_REQUEST,
+        }
+        Either::Right(future::ok(response))
+    }
+}
+
+// The address of the peer, which servers record in the extensions as either the
+// SocketAddr of the connection or just its IpAddr. With the `axum` feature, it is also
+// read from the ConnectInfo<SocketAddr> of apps served with
+// `into_make_service_with_connect_info`.
+pub fn peer_ip(extensions: &http::Extensions) -> Option<IpAddr> {
+    let peer = extensions
+        .get::<SocketAddr>()
+        .map(SocketAddr::ip)
+        .or_else(|| extensions.get::<IpAddr>().copied());
+    #[cfg(feature = "axum")]
+    let peer = peer.or_else(|| {
+        extensions
+            .get::<::axum::extract::ConnectInfo<SocketAddr>>()
+            .map(|connect_info| connect_info.0.ip())
+    });
+    peer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiter2;
+    use http::header;
+    use pretty_assertions::assert_eq;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    fn request(extension: Option<SocketAddr>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(addr) = extension {
+            request.extensions_mut().insert(addr);
+        }
+        request
+    }
+
+    fn service<L: RateLimit + ?Sized>(
+        layer: &RateLimitLayer<L>,
+    ) -> impl Service<Request<()>, Response = Response<String>, Error = Infallible> + Clone + '_
+    {
+        layer.layer(service_fn(|_: Request<()>| async {
+            Ok(Response::new("ok".to_string()))
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_layer_denies_over_limit_with_retry_after() {
+        let window = Duration::seconds(60);
+        let layer = RateLimitLayer::new(
+            Arc::new(RateLimiter2::with_config(2, window)),
+            peer_ip,
+            2,
+            window,
+        );
+        let peer = "127.0.0.1:4000".parse().unwrap();
+
+        for _ in 0..2 {
+            let response = service(&layer).oneshot(request(Some(peer))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.into_body(), "ok");
+        }
+
+        let response = service(&layer).oneshot(request(Some(peer))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "61");
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert_eq!(response.headers()["ratelimit-reset"], "61");
+        assert_eq!(response.into_body(), "");
+
+        let other_peer = "127.0.0.2:4000".parse().unwrap();
+        let response = service(&layer)
+            .oneshot(request(Some(other_peer)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_layer_rejects_requests_without_a_key() {
+        let window = Duration::seconds(60);
+        let limiter: Arc<dyn RateLimit + Send + Sync> =
+            Arc::new(RateLimiter2::with_config(1, window));
+        let layer = RateLimitLayer::new(limiter, peer_ip, 1, window);
+
+        // Never reaches the limiter, so can't use up anyone's quota
+        for _ in 0..2 {
+            let response = service(&layer).oneshot(request(None)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(response.headers().get(header::RETRY_AFTER), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layer_with_key() {
+        let window = Duration::milliseconds(4500);
+        let layer = RateLimitLayer::new(
+            Arc::new(RateLimiter2::with_config(1, window)),
+            |_| Some(IpAddr::from([10, 0, 0, 1])),
+            1,
+            window,
+        );
+
+        let response = service(&layer).oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = service(&layer).oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    }
+
+    #[tokio::test]
+    async fn test_layer_only_peeks_for_unrecorded_methods() {
+        let window = Duration::seconds(60);
+        let layer = RateLimitLayer::new(
+            Arc::new(RateLimiter2::with_config(1, window)),
+            peer_ip,
+            1,
+            window,
+        )
+        .with_unrecorded_methods([Method::HEAD, Method::OPTIONS]);
+        let peer = SocketAddr::from(([192, 0, 2, 1], 4000));
+
+        let mut statuses = Vec::new();
+        for method in [Method::HEAD, Method::OPTIONS, Method::GET, Method::HEAD] {
+            let mut request = request(Some(peer));
+            *request.method_mut() = method;
+            statuses.push(service(&layer).oneshot(request).await.unwrap().status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+
+    // peer_ip finds the peer of an axum app served with connect info, which is what a
+    // real server passes to the app, rather than a SocketAddr inserted by hand
+    #[cfg(feature = "axum")]
+    #[tokio::test]
+    async fn test_layer_limits_axum_apps_by_connect_info() {
+        use ::axum::body::Body;
+        use ::axum::routing::get;
+        use ::axum::Router;
+
+        let window = Duration::seconds(60);
+        let layer = RateLimitLayer::new(
+            Arc::new(RateLimiter2::with_config(1, window)),
+            peer_ip,
+            1,
+            window,
+        );
+        let mut make_service = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(layer)
+            .into_make_service_with_connect_info::<SocketAddr>();
+
+        let mut statuses = Vec::new();
+        for peer in ["192.0.2.1:4000", "192.0.2.1:4001", "192.0.2.2:4000"] {
+            let app = make_service
+                .call(peer.parse::<SocketAddr>().unwrap())
+                .await
+                .unwrap();
+            let request = Request::get("/").body(Body::empty()).unwrap();
+            statuses.push(app.oneshot(request).await.unwrap().status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::OK
+            ]
+        );
+    }
+}
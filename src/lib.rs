@@ -1,14 +1,220 @@
+// Without the `full` feature, only the basic limiters are built, which depend on
+// nothing but std
+pub mod basic;
+pub use basic::*;
+
+#[cfg(feature = "full")]
 pub mod version0;
+#[cfg(feature = "full")]
 pub use version0::*;
 
+#[cfg(feature = "full")]
 pub mod version1;
+#[cfg(feature = "full")]
 pub use version1::*;
 
+#[cfg(feature = "full")]
 pub mod version2;
+#[cfg(feature = "full")]
 pub use version2::*;
 
+#[cfg(feature = "full")]
 pub mod version3;
+#[cfg(feature = "full")]
 pub use version3::*;
 
+#[cfg(feature = "full")]
+pub mod version4;
+#[cfg(feature = "full")]
+pub use version4::*;
+
+#[cfg(feature = "full")]
+pub mod version5;
+#[cfg(feature = "full")]
+pub use version5::*;
+
+#[cfg(feature = "full")]
+pub mod version6;
+#[cfg(feature = "full")]
+pub use version6::*;
+
+#[cfg(feature = "full")]
+pub mod version7;
+#[cfg(feature = "full")]
+pub use version7::*;
+
+#[cfg(feature = "full")]
+pub mod version8;
+#[cfg(feature = "full")]
+pub use version8::*;
+
+#[cfg(feature = "full")]
+pub mod version9;
+#[cfg(feature = "full")]
+pub use version9::*;
+
+#[cfg(feature = "full")]
+pub mod allowlist;
+#[cfg(feature = "full")]
+pub use allowlist::*;
+
+#[cfg(feature = "axum")]
+pub mod axum;
+
+#[cfg(feature = "full")]
+pub mod backdate;
+#[cfg(feature = "full")]
+pub use backdate::*;
+
+#[cfg(feature = "full")]
+pub mod boundary;
+#[cfg(feature = "full")]
+pub use boundary::*;
+
+#[cfg(feature = "full")]
+pub mod budget;
+#[cfg(feature = "full")]
+pub use budget::MemoryEvent;
+
+#[cfg(feature = "full")]
+pub mod builder;
+#[cfg(feature = "full")]
+pub use builder::*;
+
+#[cfg(feature = "full")]
+pub mod client;
+
+#[cfg(feature = "full")]
+pub mod clock;
+#[cfg(feature = "full")]
+pub use clock::*;
+
+#[cfg(feature = "full")]
+pub mod coalesce;
+#[cfg(feature = "full")]
+pub use coalesce::*;
+
+#[cfg(feature = "full")]
+pub mod fingerprint;
+#[cfg(feature = "full")]
+pub use fingerprint::*;
+
+#[cfg(feature = "full")]
+pub mod global;
+#[cfg(feature = "full")]
+pub use global::*;
+
+// Runs the examples of guarantees.md as doctests
+#[cfg(feature = "full")]
+#[doc = include_str!("guarantees.md")]
+pub mod guarantees {}
+
+#[cfg(feature = "full")]
+pub mod hierarchical;
+#[cfg(feature = "full")]
+pub use hierarchical::*;
+
+#[cfg(feature = "tower")]
+pub mod layer;
+#[cfg(feature = "tower")]
+pub use layer::{RateLimitLayer, RateLimitService};
+
+#[cfg(feature = "full")]
+pub mod lifecycle;
+#[cfg(feature = "full")]
+pub use lifecycle::KeySummary;
+
+#[cfg(feature = "full")]
+pub mod limits;
+#[cfg(feature = "full")]
+pub use limits::*;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "full")]
+pub mod pacer;
+#[cfg(feature = "full")]
+pub use pacer::*;
+
+#[cfg(feature = "full")]
+pub mod planning;
+
+#[cfg(feature = "full")]
+pub mod policy;
+#[cfg(feature = "full")]
+pub use policy::*;
+
+#[cfg(feature = "full")]
+pub mod politeness;
+
+#[cfg(feature = "full")]
+pub mod prefix;
+#[cfg(feature = "full")]
+pub use prefix::*;
+
+#[cfg(feature = "full")]
+pub mod quota;
+#[cfg(feature = "full")]
+pub use quota::*;
+
+#[cfg(feature = "full")]
+pub mod rate_limit;
+#[cfg(feature = "full")]
+pub use rate_limit::*;
+
+#[cfg(feature = "full")]
+pub mod recorder;
+
+#[cfg(feature = "redis")]
+pub mod redis;
+
+#[cfg(feature = "full")]
+pub mod rollout;
+#[cfg(feature = "full")]
+pub use rollout::*;
+
+#[cfg(feature = "script")]
+pub mod script;
+
+#[cfg(feature = "full")]
+pub mod self_check;
+#[cfg(feature = "full")]
+pub use self_check::*;
+
+#[cfg(feature = "full")]
+mod sketch;
+
+#[cfg(feature = "full")]
+pub mod slo;
+
+#[cfg(feature = "serde")]
+pub mod snapshot;
+#[cfg(feature = "serde")]
+pub use snapshot::*;
+
+#[cfg(feature = "full")]
+pub mod stats;
+
+#[cfg(feature = "full")]
+pub mod storage;
+
+#[cfg(feature = "full")]
+pub mod strictness;
+#[cfg(feature = "full")]
+pub use strictness::*;
+
+#[cfg(feature = "full")]
+pub mod templates;
+
+#[cfg(feature = "full")]
+pub mod workload;
+
+#[cfg(all(test, feature = "alloc-audit"))]
+mod alloc_audit;
+
+#[cfg(all(test, feature = "full"))]
+mod vectors;
+
 pub const MAX_REQUESTS: usize = 100;
 pub const MAX_REQUESTS_DURATION_SECONDS: i64 = 60;
@@ -0,0 +1,111 @@
+use crate::budget::MemoryEvent;
+use chrono::{DateTime, Utc};
+
+// What a limiter knew about a key by the time it stopped tracking it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySummary {
+    pub first_seen: DateTime<Utc>,
+    // The time of the key's latest check
+    pub last_seen: DateTime<Utc>,
+    // Checks rather than slots, so a check of any cost counts once
+    pub admitted: u64,
+    pub denied: u64,
+}
+
+impl KeySummary {
+    pub(crate) fn new(first_seen: DateTime<Utc>) -> Self {
+        KeySummary {
+            first_seen,
+            last_seen: first_seen,
+            admitted: 0,
+            denied: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, timestamp: DateTime<Utc>, admitted: bool) {
+        self.last_seen = self.last_seen.max(timestamp);
+        if admitted {
+            self.admitted += 1;
+        } else {
+            self.denied += 1;
+        }
+    }
+}
+
+type OnFirstSeen<K> = Box<dyn Fn(&K) + Send + Sync>;
+type OnEvicted<K> = Box<dyn Fn(&K, KeySummary) + Send + Sync>;
+type OnMemoryEvent = Box<dyn Fn(MemoryEvent) + Send + Sync>;
+
+// The callbacks a limiter fires as keys come and go, and as they run into its memory
+// budget, none by default. They run while the limiter holds the lock of a key, so
+// they must not check the same limiter, and should hand anything slow, such as an
+// enrichment lookup, over to another task.
+pub(crate) struct Lifecycle<K> {
+    on_first_seen: Option<OnFirstSeen<K>>,
+    on_evicted: Option<OnEvicted<K>>,
+    on_memory_event: Option<OnMemoryEvent>,
+}
+
+impl<K> Lifecycle<K> {
+    pub(crate) fn with_on_first_seen(
+        mut self,
+        callback: impl Fn(&K) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_first_seen = Some(Box::new(callback));
+        self
+    }
+
+    pub(crate) fn with_on_evicted(
+        mut self,
+        callback: impl Fn(&K, KeySummary) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_evicted = Some(Box::new(callback));
+        self
+    }
+
+    pub(crate) fn with_on_memory_event(
+        mut self,
+        callback: impl Fn(MemoryEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_memory_event = Some(Box::new(callback));
+        self
+    }
+
+    pub(crate) fn first_seen(&self, key: &K) {
+        if let Some(on_first_seen) = &self.on_first_seen {
+            on_first_seen(key);
+        }
+    }
+
+    pub(crate) fn evicted(&self, key: &K, summary: KeySummary) {
+        if let Some(on_evicted) = &self.on_evicted {
+            on_evicted(key, summary);
+        }
+    }
+
+    pub(crate) fn memory_event(&self, event: MemoryEvent) {
+        if let Some(on_memory_event) = &self.on_memory_event {
+            on_memory_event(event);
+        }
+    }
+}
+
+impl<K> Default for Lifecycle<K> {
+    fn default() -> Self {
+        Lifecycle {
+            on_first_seen: None,
+            on_evicted: None,
+            on_memory_event: None,
+        }
+    }
+}
+
+impl<K> std::fmt::Debug for Lifecycle<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lifecycle")
+            .field("on_first_seen", &self.on_first_seen.is_some())
+            .field("on_evicted", &self.on_evicted.is_some())
+            .field("on_memory_event", &self.on_memory_event.is_some())
+            .finish()
+    }
+}
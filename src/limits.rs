@@ -0,0 +1,101 @@
+use chrono::Duration;
+use crossbeam_skiplist::map::Entry;
+use crossbeam_skiplist::SkipMap;
+
+// Resolves the `(max_requests, window)` of a key, so a single limiter can enforce
+// different quotas per key, such as per customer tier. Keys it returns None for get
+// the limiter's own config. RateLimiter0 asks at every check, so keys move between
+// tiers straight away. The other versions size the state of a key when they first
+// see it, so they ask once and keep those limits for as long as they track the key.
+//
+// Any `Fn(&K) -> Option<(usize, Duration)>` is a provider, so a lookup of the key's
+// tier can be passed as a closure.
+pub trait LimitProvider<K> {
+    fn limits(&self, key: &K) -> Option<(usize, Duration)>;
+}
+
+// Gives every key the limiter's own config, which is the default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GlobalLimits;
+
+impl<K> LimitProvider<K> for GlobalLimits {
+    fn limits(&self, _key: &K) -> Option<(usize, Duration)> {
+        None
+    }
+}
+
+impl<K, F: Fn(&K) -> Option<(usize, Duration)>> LimitProvider<K> for F {
+    fn limits(&self, key: &K) -> Option<(usize, Duration)> {
+        self(key)
+    }
+}
+
+// Looks up the state of a key, creating it with the key's limits when it has none
+// yet. The provider is only asked for new keys, so the SkipMap backends keep the
+// limits a key was created with until it is dropped.
+pub(crate) fn get_or_insert_with_limits<'a, K, V, L>(
+    map: &'a SkipMap<K, V>,
+    key: K,
+    provider: &L,
+    default: (usize, Duration),
+    init: impl FnOnce(usize, Duration) -> V,
+) -> Entry<'a, K, V>
+where
+    K: Ord + Send + 'static,
+    V: Send + 'static,
+    L: LimitProvider<K>,
+{
+    if let Some(entry) = map.get(&key) {
+        return entry;
+    }
+    let (max_requests, window) = provider.limits(&key).unwrap_or(default);
+    map.get_or_insert_with(key, || init(max_requests, window))
+}
+
+// The max_requests a key without state yet would be created with. A peek at such a
+// key admits it unless that is 0, as its state would start out empty.
+pub(crate) fn max_requests_of<K, L: LimitProvider<K>>(
+    provider: &L,
+    key: &K,
+    default: usize,
+) -> usize {
+    provider
+        .limits(key)
+        .map_or(default, |(max_requests, _)| max_requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RateLimiter0, RateLimiter2};
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_limits_are_kept_from_key_creation_except_by_ratelimiter0() {
+        let premium = AtomicBool::new(false);
+        let tier = |_: &IpAddr| {
+            premium
+                .load(Ordering::Relaxed)
+                .then(|| (5, Duration::seconds(60)))
+        };
+        let rate_limiter0 = RateLimiter0::with_config(2, Duration::seconds(60)).with_limits(tier);
+        let rate_limiter2 = RateLimiter2::with_config(2, Duration::seconds(60)).with_limits(tier);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter0.ratelimit0(ip, now), true);
+        assert_eq!(rate_limiter2.ratelimit2(ip, now), true);
+
+        premium.store(true, Ordering::Relaxed);
+        let admitted0 = (0..10)
+            .filter(|_| rate_limiter0.ratelimit0(ip, now))
+            .count();
+        let admitted2 = (0..10)
+            .filter(|_| rate_limiter2.ratelimit2(ip, now))
+            .count();
+        assert_eq!((admitted0, admitted2), (4, 1));
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{RateLimit, TrackedKeys};
+use ::metrics::{counter, gauge, histogram, Counter, Gauge, Histogram};
+use chrono::{DateTime, Utc};
+use std::time::Instant;
+
+// Records every decision of a limiter through the metrics facade, to whichever
+// recorder the application installed, such as a Prometheus exporter:
+//
+// - `ratelimit_decisions_total`, a counter labelled by `decision`, allowed or denied
+// - `ratelimit_check_duration_seconds`, a histogram of how long checks took
+// - `ratelimit_tracked_keys`, a gauge of the keys the limiter holds, once enabled
+//   with `with_tracked_keys`
+//
+// Every metric is labelled with the `limiter` name, so several limiters can report
+// to the same recorder. The handles are registered on construction, so the recorder
+// has to be installed before.
+pub struct MeteredRateLimiter<L> {
+    limiter: L,
+    name: String,
+    allowed: Counter,
+    denied: Counter,
+    duration: Histogram,
+    tracked_keys: Option<Gauge>,
+    count_keys: fn(&L) -> usize,
+}
+
+impl<L> MeteredRateLimiter<L> {
+    pub fn new(limiter: L, name: impl Into<String>) -> Self {
+        let name = name.into();
+        let decisions = |decision| {
+            counter!(
+                "ratelimit_decisions_total",
+                "limiter" => name.clone(),
+                "decision" => decision
+            )
+        };
+        MeteredRateLimiter {
+            limiter,
+            allowed: decisions("allowed"),
+            denied: decisions("denied"),
+            duration: histogram!("ratelimit_check_duration_seconds", "limiter" => name.clone()),
+            tracked_keys: None,
+            count_keys: |_| 0,
+            name,
+        }
+    }
+
+    // Also sets the tracked keys gauge after every check. Wrappers of the versions
+    // don't count their keys, so this is left to limiters that do.
+    pub fn with_tracked_keys(mut self) -> Self
+    where
+        L: TrackedKeys,
+    {
+        let gauge = gauge!("ratelimit_tracked_keys", "limiter" => self.name.clone());
+        self.tracked_keys = Some(gauge);
+        self.count_keys = |limiter| limiter.tracked_keys();
+        self
+    }
+
+    pub fn limiter(&self) -> &L {
+        &self.limiter
+    }
+}
+
+impl<K, L: RateLimit<K>> RateLimit<K> for MeteredRateLimiter<L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let start = Instant::now();
+        let admitted = self.limiter.check_with_cost(key, timestamp, cost);
+        self.duration.record(start.elapsed().as_secs_f64());
+
+        if admitted {
+            self.allowed.increment(1);
+        } else {
+            self.denied.increment(1);
+        }
+        if let Some(gauge) = &self.tracked_keys {
+            gauge.set((self.count_keys)(&self.limiter) as f64);
+        }
+        admitted
+    }
+
+    // Only decisions are metered, and a peek records none
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.limiter.peek(key, timestamp)
+    }
+}
+
+impl<L: std::fmt::Debug> std::fmt::Debug for MeteredRateLimiter<L> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MeteredRateLimiter")
+            .field("limiter", &self.limiter)
+            .field("name", &self.name)
+            .field("tracked_keys", &self.tracked_keys.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiter2;
+    use ::metrics::with_local_recorder;
+    use chrono::Duration;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_metered_ratelimiter_records_decisions_keys_and_latency() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let rate_limiter = with_local_recorder(&recorder, || {
+            MeteredRateLimiter::new(RateLimiter2::with_config(2, Duration::seconds(60)), "api")
+                .with_tracked_keys()
+        });
+        let now = Utc::now();
+
+        for ip in ["127.0.0.1", "127.0.0.1", "127.0.0.1", "127.0.0.2"] {
+            rate_limiter.check(ip.parse::<IpAddr>().unwrap(), now);
+        }
+
+        // By name and labels, with histograms by their number of samples
+        let metrics: HashMap<String, f64> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels: Vec<_> = key
+                    .labels()
+                    .map(|label| format!("{}={}", label.key(), label.value()))
+                    .collect();
+                let value = match value {
+                    DebugValue::Counter(count) => count as f64,
+                    DebugValue::Gauge(gauge) => gauge.into_inner(),
+                    DebugValue::Histogram(samples) => samples.len() as f64,
+                };
+                (format!("{}{{{}}}", key.name(), labels.join(",")), value)
+            })
+            .collect();
+
+        assert_eq!(
+            metrics,
+            HashMap::from([
+                (
+                    "ratelimit_decisions_total{limiter=api,decision=allowed}".to_string(),
+                    3.0
+                ),
+                (
+                    "ratelimit_decisions_total{limiter=api,decision=denied}".to_string(),
+                    1.0
+                ),
+                (
+                    "ratelimit_check_duration_seconds{limiter=api}".to_string(),
+                    4.0
+                ),
+                ("ratelimit_tracked_keys{limiter=api}".to_string(), 2.0),
+            ])
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use super::*;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// Schedules outbound jobs per target at times the target's limit will admit them,
+// rather than finding out by trial and error. Reservations are recorded in a
+// RateLimiter0, so a Pacer only knows about the jobs it scheduled itself.
+#[derive(Debug, Default)]
+pub struct Pacer {
+    limiter: RateLimiter0,
+    // The latest slot handed out per target. Reserving a slot prunes everything that
+    // fell out of its window, so slots must never be handed out before an earlier
+    // reservation, or requests that still count towards that earlier window are lost.
+    latest: Mutex<HashMap<IpAddr, DateTime<Utc>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledJob<J> {
+    pub target: IpAddr,
+    pub at: DateTime<Utc>,
+    pub job: J,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Pacer {
+            limiter: RateLimiter0::new(),
+            latest: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Reserves the earliest slot at or after `earliest` at which the target admits
+    // another request
+    pub fn schedule(&self, target: IpAddr, earliest: DateTime<Utc>) -> DateTime<Utc> {
+        let mut latest = self.latest.lock().unwrap();
+        let latest = latest.entry(target).or_insert(earliest);
+
+        let at = (*latest).max(earliest);
+        let at = self.limiter.peek0(target, at).next_admission(at);
+        let admitted = self.limiter.ratelimit0(target, at);
+        debug_assert!(admitted, "peeked slot wasn't admitted");
+
+        *latest = at;
+        at
+    }
+
+    // Schedules every job, returning them ordered by the time they should run.
+    // Jobs for the same target keep their relative order.
+    pub fn schedule_jobs<J>(
+        &self,
+        jobs: impl IntoIterator<Item = (IpAddr, J)>,
+        earliest: DateTime<Utc>,
+    ) -> Vec<ScheduledJob<J>> {
+        let mut scheduled: Vec<_> = jobs
+            .into_iter()
+            .map(|(target, job)| ScheduledJob {
+                target,
+                at: self.schedule(target, earliest),
+                job,
+            })
+            .collect();
+        scheduled.sort_by_key(|scheduled| scheduled.at);
+        scheduled
+    }
+
+    // Schedules the jobs from now on and runs each one once its slot has arrived
+    pub async fn run<J, F, Fut>(&self, jobs: impl IntoIterator<Item = (IpAddr, J)>, mut run_job: F)
+    where
+        F: FnMut(IpAddr, J) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        for scheduled in self.schedule_jobs(jobs, Utc::now()) {
+            if let Ok(delay) = (scheduled.at - Utc::now()).to_std() {
+                tokio::time::sleep(delay).await;
+            }
+            run_job(scheduled.target, scheduled.job).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_schedule_spreads_jobs_over_windows() {
+        let pacer = Pacer::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+        let window = Duration::seconds(MAX_REQUESTS_DURATION_SECONDS) + Duration::nanoseconds(1);
+
+        let slots: Vec<_> = (0..MAX_REQUESTS * 2 + 1)
+            .map(|_| pacer.schedule(ip, now))
+            .collect();
+
+        assert!(slots[..MAX_REQUESTS].iter().all(|&at| at == now));
+        assert!(slots[MAX_REQUESTS..MAX_REQUESTS * 2]
+            .iter()
+            .all(|&at| at == now + window));
+        assert_eq!(slots[MAX_REQUESTS * 2], now + window + window);
+    }
+
+    #[test]
+    fn test_schedule_never_goes_back_in_time() {
+        let pacer = Pacer::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let last = (0..=MAX_REQUESTS)
+            .map(|_| pacer.schedule(ip, now))
+            .last()
+            .unwrap();
+
+        assert!(pacer.schedule(ip, now) >= last);
+    }
+
+    #[test]
+    fn test_schedule_jobs_orders_by_time() {
+        let pacer = Pacer::new();
+        let busy = "10.0.0.1".parse::<IpAddr>().unwrap();
+        let idle = "10.0.0.2".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let jobs = (0..=MAX_REQUESTS)
+            .map(|i| (busy, i))
+            .chain(std::iter::once((idle, MAX_REQUESTS + 1)));
+        let scheduled = pacer.schedule_jobs(jobs, now);
+
+        assert_eq!(scheduled.len(), MAX_REQUESTS + 2);
+        assert!(scheduled.windows(2).all(|pair| pair[0].at <= pair[1].at));
+        let last = scheduled.last().unwrap();
+        assert_eq!((last.target, last.job), (busy, MAX_REQUESTS));
+        assert!(last.at > now);
+        assert!(scheduled
+            .iter()
+            .any(|scheduled| scheduled.target == idle && scheduled.at == now));
+    }
+
+    #[tokio::test]
+    async fn test_run_runs_every_admitted_job() {
+        let pacer = Pacer::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let mut ran = Vec::new();
+
+        pacer
+            .run((0..10).map(|i| (ip, i)), |_, job| {
+                ran.push(job);
+                async {}
+            })
+            .await;
+
+        assert_eq!(ran, (0..10).collect::<Vec<_>>());
+    }
+}
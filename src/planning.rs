@@ -0,0 +1,388 @@
+use super::*;
+use chrono::{DateTime, Duration, Utc};
+use crossbeam_queue::ArrayQueue;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::AtomicI64;
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+// Checks timed by a calibration run
+const CALIBRATION_SAMPLES: usize = 10_000;
+// Calibrating with more keys than this takes long to set up and barely changes the
+// latency, as the maps already don't fit in the caches by then
+const MAX_CALIBRATION_KEYS: usize = 1_000_000;
+// Bookkeeping the allocator adds to every allocation, roughly
+const ALLOCATION_OVERHEAD: usize = 16;
+// SkipMap nodes have a geometrically distributed height with p = 1/2, so they hold
+// two tower pointers on average
+const SKIPLIST_POINTERS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Version0,
+    Version1,
+    Version2,
+    Version3,
+    Version4,
+    Version5,
+    Version6,
+    Version7,
+    Version8,
+    Version9,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 10] = [
+        Backend::Version0,
+        Backend::Version1,
+        Backend::Version2,
+        Backend::Version3,
+        Backend::Version4,
+        Backend::Version5,
+        Backend::Version6,
+        Backend::Version7,
+        Backend::Version8,
+        Backend::Version9,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Version0 => "ratelimiter0",
+            Backend::Version1 => "ratelimiter1",
+            Backend::Version2 => "ratelimiter2",
+            Backend::Version3 => "ratelimiter3",
+            Backend::Version4 => "ratelimiter4",
+            Backend::Version5 => "ratelimiter5",
+            Backend::Version6 => "ratelimiter6",
+            Backend::Version7 => "ratelimiter7",
+            Backend::Version8 => "ratelimiter8",
+            Backend::Version9 => "ratelimiter9",
+        }
+    }
+}
+
+// What a limiter is expected to face once deployed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deployment {
+    pub backend: Backend,
+    // Distinct keys seen within a window
+    pub keys: usize,
+    // Checks per second, over all keys
+    pub requests_per_second: f64,
+    pub max_requests: usize,
+    pub window: Duration,
+    // Only used by Version4
+    pub shards: usize,
+}
+
+impl Deployment {
+    // Uses the crate wide limit, and a shard per available core like RateLimiter4::new
+    pub fn new(backend: Backend, keys: usize, requests_per_second: f64) -> Self {
+        Deployment {
+            backend,
+            keys,
+            requests_per_second,
+            max_requests: MAX_REQUESTS,
+            window: Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+            shards: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    pub fn with_config(mut self, max_requests: usize, window: Duration) -> Self {
+        self.max_requests = max_requests;
+        self.window = window;
+        self
+    }
+
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.shards = shards.max(1);
+        self
+    }
+
+    // Admitted requests a key keeps logged, assuming the requests are spread evenly
+    // over the keys. A log never holds more than the limit.
+    fn logged_per_key(&self) -> usize {
+        let per_key = self.requests_per_second / self.keys.max(1) as f64;
+        let per_window = per_key * self.window.num_milliseconds() as f64 / 1000.0;
+        (per_window.ceil() as usize).min(self.max_requests)
+    }
+}
+
+// Latency of a single uncontended check, measured on this machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub mean: std::time::Duration,
+    pub p99: std::time::Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub memory_bytes: usize,
+    // None when checks arrive faster than the backend's lock or shards can serve them
+    pub p99_latency: Option<std::time::Duration>,
+}
+
+// Calibrates the backend, which takes from milliseconds to a few seconds depending on
+// the number of keys, and estimates the deployment from it
+pub fn estimate(deployment: &Deployment) -> Estimate {
+    let calibration = calibrate(deployment);
+    Estimate {
+        memory_bytes: estimate_memory(deployment),
+        p99_latency: estimate_p99(deployment, &calibration),
+    }
+}
+
+// Estimates every backend for the same workload, to pick one from
+pub fn compare(keys: usize, requests_per_second: f64) -> Vec<(Backend, Estimate)> {
+    Backend::ALL
+        .into_iter()
+        .map(|backend| {
+            let deployment = Deployment::new(backend, keys, requests_per_second);
+            (backend, estimate(&deployment))
+        })
+        .collect()
+}
+
+// The heap the limiter's state takes once every key has been seen, from the sizes of
+// the types it stores. The maps' growth and the allocator make the actual usage vary
+// around it, so treat it as an order of magnitude.
+pub fn estimate_memory(deployment: &Deployment) -> usize {
+    let keys = deployment.keys;
+    // VecDeques double their capacity as they grow
+    let log = match deployment.logged_per_key() {
+        0 => 0,
+        logged => logged.next_power_of_two() * size_of::<DateTime<Utc>>() + ALLOCATION_OVERHEAD,
+    };
+
+    // Every version but 0 stores the limits each key was created with next to its state
+    let limits = size_of::<(usize, Duration)>();
+    match deployment.backend {
+        Backend::Version0 => hash_table(keys, size_of::<VecDeque<DateTime<Utc>>>()) + keys * log,
+        Backend::Version1 => {
+            keys * (skiplist_node(limits + size_of::<VecDeque<DateTime<Utc>>>()) + log)
+        }
+        Backend::Version2 => {
+            keys * (skiplist_node(limits + size_of::<RwLock<VecDeque<DateTime<Utc>>>>()) + log)
+        }
+        Backend::Version3 => {
+            // The queues allocate a slot for every request up front, each holding a
+            // stamp next to the timestamp
+            let slot = (size_of::<usize>() + size_of::<DateTime<Utc>>())
+                .next_multiple_of(align_of::<usize>());
+            let queue = deployment.max_requests * slot + ALLOCATION_OVERHEAD;
+            keys * (skiplist_node(limits + size_of::<ArrayQueue<DateTime<Utc>>>()) + queue)
+        }
+        Backend::Version4 => {
+            let shards = deployment.shards.max(1);
+            let table = hash_table(
+                keys.div_ceil(shards),
+                limits + size_of::<VecDeque<DateTime<Utc>>>(),
+            );
+            shards * table + keys * log
+        }
+        // The tokens and refill time of RateLimiter5's buckets, next to their size and
+        // rate
+        Backend::Version5 => {
+            keys * skiplist_node(size_of::<Mutex<(usize, DateTime<Utc>, usize, Duration)>>())
+        }
+        // The TAT, next to its emission interval and tolerance
+        Backend::Version6 => keys * skiplist_node(size_of::<(AtomicI64, i64, i64)>()),
+        // The counts of the current and previous window, next to their limit
+        Backend::Version7 => {
+            keys * skiplist_node(size_of::<Mutex<(usize, i64, i64, usize, usize)>>())
+        }
+        // The shards split the keys between them, so they add up to about one table.
+        // Each key also keeps the KeySummary handed to lifecycle callbacks.
+        Backend::Version8 => {
+            let value = limits + size_of::<VecDeque<DateTime<Utc>>>() + size_of::<KeySummary>();
+            hash_table(keys, value) + keys * log
+        }
+        // The log is boxed, so it can be swapped in whole
+        Backend::Version9 => {
+            let boxed = size_of::<VecDeque<DateTime<Utc>>>() + ALLOCATION_OVERHEAD;
+            keys * (skiplist_node(limits + size_of::<usize>()) + boxed + log)
+        }
+    }
+}
+
+// hashbrown keeps at most 7/8 of its power of two buckets full, and a control byte
+// next to each of them
+fn hash_table(entries: usize, value: usize) -> usize {
+    if entries == 0 {
+        return 0;
+    }
+    let buckets = (entries * 8).div_ceil(7).next_power_of_two();
+    buckets * (size_of::<IpAddr>() + value + 1) + ALLOCATION_OVERHEAD
+}
+
+// Every SkipMap entry is a separate allocation holding a reference count and height
+// next to the entry and its tower
+fn skiplist_node(value: usize) -> usize {
+    size_of::<IpAddr>() + value + size_of::<usize>() * (1 + SKIPLIST_POINTERS) + ALLOCATION_OVERHEAD
+}
+
+// Estimates the p99 of a check under the deployment's load from an uncontended
+// calibration. Version0 takes a write lock for every check and Version4 serves each
+// shard from a single task, so checks queue behind each other, which is modelled as
+// an M/M/1 queue whose p99 response time is ln(100) / (service rate - arrival rate).
+// The other backends only synchronize per key, or per shard of Version8's DashMap,
+// which has several times as many shards as cores, so with the load spread over the
+// keys they keep their uncontended latency. A single hot key queues on them as well.
+pub fn estimate_p99(
+    deployment: &Deployment,
+    calibration: &Calibration,
+) -> Option<std::time::Duration> {
+    let queued = |arrivals_per_second: f64| {
+        let service_rate = 1.0 / calibration.mean.as_secs_f64().max(f64::MIN_POSITIVE);
+        if arrivals_per_second >= service_rate {
+            return None;
+        }
+        let p99 = 100f64.ln() / (service_rate - arrivals_per_second);
+        Some(calibration.p99.max(std::time::Duration::from_secs_f64(p99)))
+    };
+
+    match deployment.backend {
+        Backend::Version0 => queued(deployment.requests_per_second),
+        Backend::Version4 => {
+            queued(deployment.requests_per_second / deployment.shards.max(1) as f64)
+        }
+        _ => Some(calibration.p99),
+    }
+}
+
+// Times checks of a fresh limiter for the deployment, once it has seen every key
+pub fn calibrate(deployment: &Deployment) -> Calibration {
+    let keys: Vec<IpAddr> = (0..deployment.keys.clamp(1, MAX_CALIBRATION_KEYS) as u32)
+        .map(|i| IpAddr::V4(Ipv4Addr::from(i)))
+        .collect();
+    let (max_requests, window) = (deployment.max_requests.max(1), deployment.window);
+
+    let limiter: Box<dyn RateLimit> = match deployment.backend {
+        Backend::Version0 => Box::new(RateLimiter0::with_config(max_requests, window)),
+        Backend::Version1 => Box::new(RateLimiter1::with_config(max_requests, window)),
+        Backend::Version2 => Box::new(RateLimiter2::with_config(max_requests, window)),
+        Backend::Version3 => Box::new(RateLimiter3::with_config(max_requests, window)),
+        Backend::Version5 => Box::new(RateLimiter5::with_config(max_requests, window)),
+        Backend::Version6 => Box::new(RateLimiter6::with_config(max_requests, window)),
+        Backend::Version7 => Box::new(RateLimiter7::with_config(max_requests, window)),
+        Backend::Version8 => Box::new(RateLimiter8::with_config(max_requests, window)),
+        Backend::Version9 => Box::new(RateLimiter9::with_config(max_requests, window)),
+        Backend::Version4 => {
+            // The shards only run on the runtime, and their latency hardly depends on
+            // the limit, so the default one is calibrated. Blocking on every check
+            // includes the wake-up of the caller, as it would for synchronous callers.
+            let runtime = tokio::runtime::Runtime::new().expect("Failed to start a runtime");
+            let limiter: RateLimiter4 =
+                runtime.block_on(async { RateLimiter4::with_shards(deployment.shards) });
+            return time_checks(&keys, |ip, timestamp| {
+                runtime.block_on(limiter.ratelimit4(ip, timestamp))
+            });
+        }
+    };
+    time_checks(&keys, |ip, timestamp| limiter.check(ip, timestamp))
+}
+
+fn time_checks(
+    keys: &[IpAddr],
+    mut check: impl FnMut(IpAddr, DateTime<Utc>) -> bool,
+) -> Calibration {
+    for &key in keys {
+        check(key, Utc::now());
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut latencies: Vec<std::time::Duration> = (0..CALIBRATION_SAMPLES)
+        .map(|_| {
+            let key = keys[rng.gen_range(0..keys.len())];
+            let timestamp = Utc::now();
+            let start = Instant::now();
+            check(key, timestamp);
+            start.elapsed()
+        })
+        .collect();
+
+    latencies.sort_unstable();
+    Calibration {
+        mean: latencies.iter().sum::<std::time::Duration>() / latencies.len() as u32,
+        p99: latencies[(latencies.len() * 99).div_ceil(100) - 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn calibration(micros: u64) -> Calibration {
+        Calibration {
+            mean: std::time::Duration::from_micros(micros),
+            p99: std::time::Duration::from_micros(micros * 2),
+        }
+    }
+
+    #[test]
+    fn test_estimate_memory_grows_with_keys_and_logs() {
+        let small = Deployment::new(Backend::Version0, 1_000, 1_000.0);
+        let more_keys = Deployment::new(Backend::Version0, 100_000, 100_000.0);
+        let longer_logs = Deployment::new(Backend::Version0, 1_000, 100_000.0);
+
+        assert!(estimate_memory(&more_keys) > estimate_memory(&small) * 50);
+        assert!(estimate_memory(&longer_logs) > estimate_memory(&small));
+        // Logs are capped at the limit, so more traffic over it takes no more memory
+        let over_limit = Deployment::new(Backend::Version0, 1_000, 1_000_000.0);
+        assert_eq!(estimate_memory(&over_limit), estimate_memory(&longer_logs));
+    }
+
+    #[test]
+    fn test_estimate_memory_of_constant_size_backends() {
+        let deployment = |backend| Deployment::new(backend, 10_000, 1_000_000.0);
+
+        // GCRA keeps a single timestamp where the sliding logs keep up to the limit
+        assert!(
+            estimate_memory(&deployment(Backend::Version6)) * 10
+                < estimate_memory(&deployment(Backend::Version2))
+        );
+        // So does the sliding window counter, with its two counts
+        assert!(
+            estimate_memory(&deployment(Backend::Version7)) * 10
+                < estimate_memory(&deployment(Backend::Version2))
+        );
+        // Version3 preallocates the whole limit, however little is logged
+        let quiet = Deployment::new(Backend::Version3, 10_000, 1.0);
+        assert_eq!(
+            estimate_memory(&quiet),
+            estimate_memory(&deployment(Backend::Version3))
+        );
+    }
+
+    #[test]
+    fn test_estimate_p99_queues_on_locks_and_shards() {
+        // A check takes 10µs, so a lock serves at most 100 000 checks per second
+        let calibration = calibration(10);
+        let deployment = |backend, rps| Deployment::new(backend, 1_000, rps).with_shards(4);
+
+        assert_eq!(
+            estimate_p99(&deployment(Backend::Version2, 200_000.0), &calibration),
+            Some(calibration.p99)
+        );
+        assert_eq!(
+            estimate_p99(&deployment(Backend::Version0, 200_000.0), &calibration),
+            None
+        );
+        let sharded = estimate_p99(&deployment(Backend::Version4, 200_000.0), &calibration)
+            .expect("4 shards serve 400 000 checks per second");
+        let idle = estimate_p99(&deployment(Backend::Version4, 1.0), &calibration).unwrap();
+        assert!(sharded > idle);
+    }
+
+    #[test]
+    fn test_estimate_calibrates_every_backend() {
+        for (backend, estimate) in compare(100, 1_000.0) {
+            assert!(estimate.memory_bytes > 0, "{}", backend.name());
+            assert!(estimate.p99_latency.is_some(), "{}", backend.name());
+        }
+    }
+}
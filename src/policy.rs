@@ -0,0 +1,266 @@
+use crate::{Boundary, RateLimit};
+use chrono::{DateTime, Duration, Utc};
+use crossbeam_skiplist::SkipMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// Admits up to `max_requests` per key within any `window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub max_requests: usize,
+    pub window: Duration,
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} per {}s",
+            self.max_requests,
+            self.window.num_seconds()
+        )
+    }
+}
+
+// Rules that all have to admit a request, such as 100 per minute and 2000 per hour.
+// A policy without rules admits everything.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule(mut self, max_requests: usize, window: Duration) -> Self {
+        self.rules.push(Rule {
+            max_requests,
+            window,
+        });
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    fn longest_window(&self) -> Duration {
+        self.rules
+            .iter()
+            .map(|rule| rule.window)
+            .max()
+            .unwrap_or_else(Duration::zero)
+    }
+}
+
+// The rule that denied a request, the first in policy order if several did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub rule: Rule,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "limit of {} exceeded", self.rule)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+// Enforces every rule of a policy per key. Each key has a single sliding log as long
+// as the longest window, and each rule counts the requests within its own window, so
+// checking all of them and recording the request happen under one lock. A denied
+// request takes up no slot in any window.
+#[derive(Debug)]
+pub struct PolicyRateLimiter<K: Ord = IpAddr> {
+    requests: SkipMap<K, Mutex<VecDeque<DateTime<Utc>>>>,
+    policy: Policy,
+    boundary: Boundary,
+}
+
+impl<K: Ord + Send + 'static> PolicyRateLimiter<K> {
+    pub fn new(policy: Policy) -> Self {
+        PolicyRateLimiter {
+            requests: SkipMap::new(),
+            policy,
+            boundary: Boundary::default(),
+        }
+    }
+
+    // Whether a request made exactly a rule's `window` before a check still counts
+    // against it, which is the case by default
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    // Records the request if every rule admits it, and returns the rule that didn't
+    // otherwise
+    pub fn check_policy(&self, key: K, timestamp: DateTime<Utc>) -> Result<(), Violation> {
+        self.check_policy_with_cost(key, timestamp, 1)
+    }
+
+    pub fn check_policy_with_cost(
+        &self,
+        key: K,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+    ) -> Result<(), Violation> {
+        if self.policy.rules.is_empty() {
+            return Ok(());
+        }
+
+        let entry = self
+            .requests
+            .get_or_insert_with(key, || Mutex::new(VecDeque::new()));
+        let mut requests = entry.value().lock().unwrap();
+
+        let cutoff_time = timestamp - self.policy.longest_window();
+        while let Some(front_time) = requests.front() {
+            if !self.boundary.contains(cutoff_time, *front_time) {
+                requests.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        for &rule in &self.policy.rules {
+            let cutoff_time = timestamp - rule.window;
+            let in_window = requests
+                .iter()
+                .filter(|&&time| self.boundary.contains(cutoff_time, time))
+                .count();
+            if in_window + cost as usize > rule.max_requests {
+                return Err(Violation { rule });
+            }
+        }
+
+        requests.extend(std::iter::repeat_n(timestamp, cost as usize));
+        Ok(())
+    }
+}
+
+impl<K: Ord + Send + 'static> RateLimit<K> for PolicyRateLimiter<K> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_policy(key, timestamp).is_ok()
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.check_policy_with_cost(key, timestamp, cost).is_ok()
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let entry = self.requests.get(&key);
+        let requests = entry.as_ref().map(|entry| entry.value().lock().unwrap());
+        self.policy.rules.iter().all(|rule| {
+            let cutoff_time = timestamp - rule.window;
+            let in_window = requests
+                .iter()
+                .flat_map(|requests| requests.iter())
+                .filter(|&&time| self.boundary.contains(cutoff_time, time))
+                .count();
+            in_window < rule.max_requests
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn per_second_and_minute() -> PolicyRateLimiter {
+        PolicyRateLimiter::new(
+            Policy::new()
+                .with_rule(3, Duration::seconds(1))
+                .with_rule(5, Duration::minutes(1)),
+        )
+    }
+
+    #[test]
+    fn test_policy_ratelimiter_reports_the_violated_rule() {
+        let rate_limiter = per_second_and_minute();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+        let per_second = rate_limiter.policy().rules()[0];
+        let per_minute = rate_limiter.policy().rules()[1];
+
+        for _ in 0..3 {
+            assert_eq!(rate_limiter.check_policy(ip, now), Ok(()));
+        }
+        // A burst runs into the shorter window first
+        assert_eq!(
+            rate_limiter.check_policy(ip, now),
+            Err(Violation { rule: per_second })
+        );
+
+        let later = now + Duration::seconds(2);
+        for _ in 0..2 {
+            assert_eq!(rate_limiter.check_policy(ip, later), Ok(()));
+        }
+        // The second is fresh, but the minute is full
+        assert_eq!(
+            rate_limiter.check_policy(ip, later),
+            Err(Violation { rule: per_minute })
+        );
+
+        let next_minute = now + Duration::seconds(61);
+        assert_eq!(rate_limiter.check_policy(ip, next_minute), Ok(()));
+    }
+
+    #[test]
+    fn test_policy_ratelimiter_denied_requests_take_no_slot_in_any_window() {
+        let rate_limiter = per_second_and_minute();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..3 {
+            assert_eq!(rate_limiter.check(ip, now), true);
+        }
+        // Denied by the second, so these don't count against the minute
+        for _ in 0..10 {
+            assert_eq!(rate_limiter.check(ip, now), false);
+        }
+
+        let later = now + Duration::seconds(2);
+        assert_eq!(rate_limiter.check(ip, later), true);
+        assert_eq!(rate_limiter.check(ip, later), true);
+        assert_eq!(rate_limiter.check(ip, later), false);
+    }
+
+    #[test]
+    fn test_policy_ratelimiter_with_cost() {
+        let rate_limiter = per_second_and_minute();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 4), false);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 3), true);
+        assert_eq!(
+            rate_limiter.check_with_cost(ip, now + Duration::seconds(2), 3),
+            false
+        );
+        assert_eq!(
+            rate_limiter.check_with_cost(ip, now + Duration::seconds(2), 2),
+            true
+        );
+    }
+
+    #[test]
+    fn test_policy_ratelimiter_without_rules_admits_everything() {
+        let rate_limiter = PolicyRateLimiter::new(Policy::new());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..1000 {
+            assert_eq!(rate_limiter.check(ip, now), true);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use super::*;
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// The longest Crawl-delay taken from a robots.txt. Longer ones are capped to it, as the
+// file is untrusted and a huge delay would overflow the schedule.
+pub const MAX_CRAWL_DELAY: Duration = Duration::days(1);
+
+// Crawler politeness on top of the Pacer: besides the per-address limit, requests to
+// the same host are spaced out by its crawl delay. That is the one set for the host
+// itself or ingested from its robots.txt, else the one of its most specific domain
+// given to with_domain_crawl_delay, else `default_crawl_delay`.
+// Every slot is pushed back by a random fraction of the delay, so crawlers don't hit
+// hosts in lock step. Hosts resolving to the same address share its Pacer slots, so
+// they are never scheduled before a slot already handed out for that address.
+#[derive(Debug)]
+pub struct Politeness {
+    pacer: Pacer,
+    default_crawl_delay: Duration,
+    // Lowercase domains without a trailing dot
+    domains: HashMap<String, Duration>,
+    jitter: f64,
+    hosts: Mutex<HashMap<String, Host>>,
+}
+
+#[derive(Debug, Default)]
+struct Host {
+    crawl_delay: Option<Duration>,
+    next: Option<DateTime<Utc>>,
+}
+
+impl Politeness {
+    pub fn new(default_crawl_delay: Duration) -> Self {
+        assert_non_negative(default_crawl_delay);
+        Politeness {
+            pacer: Pacer::new(),
+            default_crawl_delay,
+            domains: HashMap::new(),
+            jitter: 0.0,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The fraction of the crawl delay, clamped to 0..=1, by which slots are randomly
+    // pushed back
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    // Overrides the default crawl delay for a domain and every host under it, so
+    // "example.com" also covers "img.example.com" but not "badexample.com"
+    pub fn with_domain_crawl_delay(mut self, domain: &str, crawl_delay: Duration) -> Self {
+        assert_non_negative(crawl_delay);
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.insert(domain, crawl_delay);
+        self
+    }
+
+    // Takes precedence over the crawl delay of the host's domain
+    pub fn set_crawl_delay(&self, host: &str, crawl_delay: Duration) {
+        assert_non_negative(crawl_delay);
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .crawl_delay = Some(crawl_delay);
+    }
+
+    pub fn crawl_delay(&self, host: &str) -> Duration {
+        let hosts = self.hosts.lock().unwrap();
+        let host = host.to_ascii_lowercase();
+        hosts
+            .get(&host)
+            .and_then(|host| host.crawl_delay)
+            .unwrap_or_else(|| self.domain_crawl_delay(&host))
+    }
+
+    // The delay of the longest domain `host` is or is under, walking up a label at a
+    // time
+    fn domain_crawl_delay(&self, host: &str) -> Duration {
+        let mut domain = host.trim_end_matches('.');
+        loop {
+            if let Some(&crawl_delay) = self.domains.get(domain) {
+                return crawl_delay;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return self.default_crawl_delay,
+            }
+        }
+    }
+
+    // Applies the Crawl-delay of the robots.txt group matching the user agent, if any
+    pub fn ingest_robots(&self, host: &str, robots_txt: &str, user_agent: &str) {
+        if let Some(crawl_delay) = parse_crawl_delay(robots_txt, user_agent) {
+            self.set_crawl_delay(host, crawl_delay);
+        }
+    }
+
+    // Reserves the earliest slot at or after `earliest` respecting both the host's
+    // crawl delay and the limit of the address it resolved to
+    pub fn schedule(&self, host: &str, addr: IpAddr, earliest: DateTime<Utc>) -> DateTime<Utc> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = host.to_ascii_lowercase();
+        let domain_crawl_delay = self.domain_crawl_delay(&host);
+        let host = hosts.entry(host).or_default();
+        let crawl_delay = host.crawl_delay.unwrap_or(domain_crawl_delay);
+
+        let mut at = host.next.map_or(earliest, |next| next.max(earliest));
+        if self.jitter > 0.0 {
+            let max_jitter = crawl_delay.num_milliseconds() as f64 * self.jitter;
+            let jitter = rand::thread_rng().gen_range(0.0..=max_jitter);
+            at = at
+                .checked_add_signed(Duration::milliseconds(jitter as i64))
+                .unwrap_or(at);
+        }
+
+        let at = self.pacer.schedule(addr, at);
+        // Saturates rather than panicking with the hosts locked, which would poison them
+        host.next = Some(
+            at.checked_add_signed(crawl_delay)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        );
+        at
+    }
+}
+
+// A negative delay would schedule a host's requests before each other, and can't be
+// jittered
+fn assert_non_negative(crawl_delay: Duration) {
+    assert!(
+        crawl_delay >= Duration::zero(),
+        "Crawl delays can't be negative"
+    );
+}
+
+// Finds the Crawl-delay, in (fractional) seconds, of the robots.txt group whose
+// User-agent matches ours, falling back to the `*` group. Delays over MAX_CRAWL_DELAY
+// are capped to it.
+pub fn parse_crawl_delay(robots_txt: &str, user_agent: &str) -> Option<Duration> {
+    let user_agent = user_agent.to_ascii_lowercase();
+    let mut agents: Vec<String> = Vec::new();
+    let mut in_rules = false;
+    let mut matching = None;
+    let mut wildcard = None;
+
+    for line in robots_txt.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        match field.trim().to_ascii_lowercase().as_str() {
+            "user-agent" => {
+                // A User-agent line after a group's rules starts a new group
+                if in_rules {
+                    agents.clear();
+                    in_rules = false;
+                }
+                agents.push(value.to_ascii_lowercase());
+            }
+            "crawl-delay" => {
+                in_rules = true;
+                let Some(delay) = value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|delay| delay.is_finite() && *delay >= 0.0)
+                else {
+                    continue;
+                };
+                let delay = Duration::milliseconds((delay * 1000.0) as i64).min(MAX_CRAWL_DELAY);
+                for agent in &agents {
+                    if agent == "*" {
+                        wildcard.get_or_insert(delay);
+                    } else if user_agent.contains(agent.as_str()) {
+                        matching.get_or_insert(delay);
+                    }
+                }
+            }
+            _ => in_rules = true,
+        }
+    }
+
+    matching.or(wildcard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const ROBOTS_TXT: &str = "
+User-agent: *
+Disallow: /private
+Crawl-delay: 10
+
+# Be gentler with the image crawler
+User-agent: ImageBot
+User-agent: SlowBot
+Crawl-delay: 2.5
+";
+
+    #[test]
+    fn test_parse_crawl_delay_prefers_matching_group() {
+        assert_eq!(
+            parse_crawl_delay(ROBOTS_TXT, "SlowBot/1.0"),
+            Some(Duration::milliseconds(2500))
+        );
+        assert_eq!(
+            parse_crawl_delay(ROBOTS_TXT, "OtherBot/1.0"),
+            Some(Duration::seconds(10))
+        );
+        assert_eq!(parse_crawl_delay("User-agent: *\nDisallow: /", "Bot"), None);
+    }
+
+    #[test]
+    fn test_parse_crawl_delay_ignores_invalid_values() {
+        assert_eq!(
+            parse_crawl_delay("User-agent: *\nCrawl-delay: soon", "Bot"),
+            None
+        );
+        assert_eq!(
+            parse_crawl_delay("User-agent: *\nCrawl-delay: -1", "Bot"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_crawl_delay_caps_huge_values() {
+        for delay in ["1e15", "1e300"] {
+            assert_eq!(
+                parse_crawl_delay(&format!("User-agent: *\nCrawl-delay: {delay}"), "Bot"),
+                Some(MAX_CRAWL_DELAY)
+            );
+        }
+    }
+
+    // A huge delay neither panics nor poisons the hosts for later calls
+    #[test]
+    fn test_schedule_saturates_huge_crawl_delays() {
+        let politeness = Politeness::new(Duration::seconds(1));
+        politeness.ingest_robots("example.com", "User-agent: *\nCrawl-delay: 1e15", "Bot");
+        politeness.set_crawl_delay("example.org", Duration::MAX);
+        let addr = "93.184.216.34".parse::<IpAddr>().unwrap();
+        let other_addr = "93.184.216.35".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        politeness.schedule("example.com", addr, now);
+        assert_eq!(
+            politeness.schedule("example.com", addr, now),
+            now + MAX_CRAWL_DELAY
+        );
+        assert_eq!(politeness.schedule("example.org", other_addr, now), now);
+        assert_eq!(politeness.crawl_delay("example.net"), Duration::seconds(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Crawl delays can't be negative")]
+    fn test_negative_crawl_delays_are_rejected() {
+        Politeness::new(Duration::seconds(1))
+            .with_jitter(0.5)
+            .with_domain_crawl_delay("example.com", Duration::seconds(-1));
+    }
+
+    #[test]
+    fn test_schedule_spaces_requests_by_crawl_delay() {
+        let politeness = Politeness::new(Duration::seconds(1));
+        politeness.ingest_robots("Example.com", ROBOTS_TXT, "SlowBot/1.0");
+        let addr = "93.184.216.34".parse::<IpAddr>().unwrap();
+        let other_addr = "93.184.216.35".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let first = politeness.schedule("example.com", addr, now);
+        let second = politeness.schedule("example.com", addr, now);
+        let other_host = politeness.schedule("example.org", other_addr, now);
+
+        assert_eq!(first, now);
+        assert_eq!(second, now + Duration::milliseconds(2500));
+        assert_eq!(other_host, now);
+        assert_eq!(politeness.crawl_delay("example.org"), Duration::seconds(1));
+    }
+
+    #[test]
+    fn test_domain_crawl_delay_covers_subdomains() {
+        let politeness = Politeness::new(Duration::seconds(1))
+            .with_domain_crawl_delay("Example.com", Duration::seconds(5))
+            .with_domain_crawl_delay("cdn.example.com.", Duration::seconds(2));
+        politeness.set_crawl_delay("slow.example.com", Duration::seconds(30));
+
+        let crawl_delays = [
+            "example.com",
+            "www.Example.com",
+            "img.cdn.example.com",
+            "slow.example.com",
+            "badexample.com",
+            "example.org",
+        ]
+        .map(|host| politeness.crawl_delay(host).num_seconds());
+        assert_eq!(crawl_delays, [5, 5, 2, 30, 1, 1]);
+
+        let addr = "93.184.216.34".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+        politeness.schedule("www.example.com", addr, now);
+        assert_eq!(
+            politeness.schedule("www.example.com", addr, now),
+            now + Duration::seconds(5)
+        );
+    }
+
+    #[test]
+    fn test_schedule_jitter_is_bounded() {
+        let crawl_delay = Duration::seconds(1);
+        let politeness = Politeness::new(crawl_delay).with_jitter(0.5);
+        let addr = "93.184.216.34".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for i in 0..20 {
+            let host = format!("host{i}.example.com");
+            let at = politeness.schedule(&host, addr, now);
+            assert!(at >= now && at <= now + Duration::milliseconds(500));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{RateLimit, TrackedKeys};
+use chrono::{DateTime, Utc};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// The network an address is limited as. Hosts usually get a whole IPv6 /64, and an
+// attacker can rotate through it for free, so limiting single IPv6 addresses limits
+// nothing. Addresses are masked to their network before the lookup, so every address
+// in it shares one quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    ipv4: u8,
+    ipv6: u8,
+}
+
+impl IpPrefix {
+    // Masks IPv6 addresses to their first `prefix_len` bits and keeps IPv4 addresses
+    // whole. Lengths over 128 keep the whole address.
+    pub fn ipv6(prefix_len: u8) -> Self {
+        IpPrefix {
+            ipv4: 32,
+            ipv6: prefix_len.min(128),
+        }
+    }
+
+    // Also masks IPv4 addresses, such as to /24 for clients behind carrier-grade NAT
+    // or a hosting provider's range. Lengths over 32 keep the whole address.
+    pub fn with_ipv4(mut self, prefix_len: u8) -> Self {
+        self.ipv4 = prefix_len.min(32);
+        self
+    }
+
+    // IPv4 addresses mapped into IPv6, as dual-stack sockets report them, are masked as
+    // the IPv4 addresses they are, so a client is limited the same on either stack
+    pub fn mask(&self, ip: IpAddr) -> IpAddr {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - self.ipv4 as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(ip.to_bits() & mask))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - self.ipv6 as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(ip.to_bits() & mask))
+            }
+        }
+    }
+}
+
+// A /64, the smallest network IPv6 hosts are usually given
+impl Default for IpPrefix {
+    fn default() -> Self {
+        Self::ipv6(64)
+    }
+}
+
+// Masks every address to its IpPrefix before checking it against any version, so
+// all addresses of a network count against the same key. RateLimiter4 can only answer
+// asynchronously, so its callers mask the address with IpPrefix::mask themselves.
+#[derive(Debug, Default)]
+pub struct PrefixRateLimiter<L> {
+    limiter: L,
+    prefix: IpPrefix,
+}
+
+impl<L> PrefixRateLimiter<L> {
+    pub fn new(limiter: L, prefix: IpPrefix) -> Self {
+        PrefixRateLimiter { limiter, prefix }
+    }
+
+    pub fn limiter(&self) -> &L {
+        &self.limiter
+    }
+
+    pub fn prefix(&self) -> IpPrefix {
+        self.prefix
+    }
+}
+
+impl<L: RateLimit<IpAddr>> RateLimit<IpAddr> for PrefixRateLimiter<L> {
+    fn check(&self, key: IpAddr, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: IpAddr, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.limiter
+            .check_with_cost(self.prefix.mask(key), timestamp, cost)
+    }
+
+    fn peek(&self, key: IpAddr, timestamp: DateTime<Utc>) -> bool {
+        self.limiter.peek(self.prefix.mask(key), timestamp)
+    }
+}
+
+// Counts networks rather than addresses
+impl<L: TrackedKeys> TrackedKeys for PrefixRateLimiter<L> {
+    fn tracked_keys(&self) -> usize {
+        self.limiter.tracked_keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_prefix_masks_each_family() {
+        let prefix = IpPrefix::default();
+        assert_eq!(
+            prefix.mask(ip("2001:db8:1:2:aaaa::1")),
+            ip("2001:db8:1:2::")
+        );
+        assert_eq!(prefix.mask(ip("192.0.2.77")), ip("192.0.2.77"));
+
+        let prefix = IpPrefix::ipv6(48).with_ipv4(24);
+        assert_eq!(prefix.mask(ip("2001:db8:1:2:aaaa::1")), ip("2001:db8:1::"));
+        assert_eq!(prefix.mask(ip("192.0.2.77")), ip("192.0.2.0"));
+        assert_eq!(prefix.mask(ip("::ffff:192.0.2.77")), ip("192.0.2.0"));
+    }
+
+    #[test]
+    fn test_ip_prefix_lengths_at_the_bounds() {
+        let whole = IpPrefix::ipv6(200).with_ipv4(40);
+        assert_eq!(whole, IpPrefix::ipv6(128).with_ipv4(32));
+        assert_eq!(whole.mask(ip("2001:db8::1")), ip("2001:db8::1"));
+        assert_eq!(whole.mask(ip("192.0.2.77")), ip("192.0.2.77"));
+
+        let everything = IpPrefix::ipv6(0).with_ipv4(0);
+        assert_eq!(everything.mask(ip("2001:db8::1")), ip("::"));
+        assert_eq!(everything.mask(ip("192.0.2.77")), ip("0.0.0.0"));
+    }
+
+    // Every version limits the addresses of a /64 together
+    #[test]
+    fn test_prefix_ratelimiter_shares_a_quota_per_network() {
+        fn prefixed(rate_limiter: impl RateLimit + 'static) -> Box<dyn RateLimit> {
+            Box::new(PrefixRateLimiter::new(rate_limiter, IpPrefix::default()))
+        }
+        let window = Duration::seconds(60);
+        let rate_limiters = [
+            (
+                "RateLimiter0",
+                prefixed(RateLimiter0::with_config(3, window)),
+            ),
+            (
+                "RateLimiter1",
+                prefixed(RateLimiter1::with_config(3, window)),
+            ),
+            (
+                "RateLimiter2",
+                prefixed(RateLimiter2::with_config(3, window)),
+            ),
+            (
+                "RateLimiter3",
+                prefixed(RateLimiter3::with_config(3, window)),
+            ),
+            (
+                "RateLimiter5",
+                prefixed(RateLimiter5::with_config(3, window)),
+            ),
+            (
+                "RateLimiter6",
+                prefixed(RateLimiter6::with_config(3, window)),
+            ),
+            (
+                "RateLimiter7",
+                prefixed(RateLimiter7::with_config(3, window)),
+            ),
+            (
+                "RateLimiter8",
+                prefixed(RateLimiter8::with_config(3, window)),
+            ),
+            (
+                "RateLimiter9",
+                prefixed(RateLimiter9::with_config(3, window)),
+            ),
+        ];
+        let now = Utc::now();
+
+        for (name, rate_limiter) in &rate_limiters {
+            // Rotating through the network doesn't get past the limit
+            let admitted = (1..=5)
+                .filter(|i| rate_limiter.check(ip(&format!("2001:db8::{i:x}")), now))
+                .count();
+            assert_eq!(admitted, 3, "{}", name);
+            assert_eq!(
+                rate_limiter.check(ip("2001:db8:0:1::1"), now),
+                true,
+                "{}",
+                name
+            );
+        }
+    }
+}
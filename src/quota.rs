@@ -0,0 +1,185 @@
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: usize,
+    pub remaining: usize,
+    // When the oldest request still in the window expires, freeing up a slot. Equal to
+    // the peeked timestamp when the window is empty
+    pub reset: DateTime<Utc>,
+}
+
+impl Quota {
+    // The earliest time at or after `timestamp` at which a request would be admitted
+    pub fn next_admission(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        if self.remaining > 0 {
+            timestamp
+        } else {
+            self.reset.max(timestamp)
+        }
+    }
+
+    // Renders the quota as seen at `now` for API consumers. The schema is stable:
+    //
+    // {"limit": 100, "remaining": 0, "reset": "2023-10-01T12:00:00.000Z", "reset_after_seconds": 2}
+    //
+    // where reset_after_seconds is rounded up, so waiting that long always suffices
+    pub fn to_json(&self, now: DateTime<Utc>) -> String {
+        format!(
+            "{{\"limit\": {}, \"remaining\": {}, \"reset\": \"{}\", \"reset_after_seconds\": {}}}",
+            self.limit,
+            self.remaining,
+            self.reset.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.reset_after_seconds(now)
+        )
+    }
+
+    // The RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset response headers of
+    // the IETF draft, with the reset in seconds from `now`, rounded up like to_json
+    pub fn headers(&self, now: DateTime<Utc>) -> [(&'static str, String); 3] {
+        [
+            ("RateLimit-Limit", self.limit.to_string()),
+            ("RateLimit-Remaining", self.remaining.to_string()),
+            ("RateLimit-Reset", self.reset_after_seconds(now).to_string()),
+        ]
+    }
+
+    // Rounded up from the exact duration, as the reset can be a nanosecond past a whole
+    // second, such as one window after the oldest request under Boundary::Inclusive
+    fn reset_after_seconds(&self, now: DateTime<Utc>) -> i64 {
+        let reset_after = (self.reset - now).max(Duration::zero());
+        let seconds = reset_after.num_seconds();
+        if reset_after > Duration::seconds(seconds) {
+            seconds + 1
+        } else {
+            seconds
+        }
+    }
+}
+
+// The headers of a 429 from the HTTP middleware: Retry-After, and the RateLimit-*
+// headers of Quota::headers. The middleware only learns that the request was denied,
+// not the state of its key, so the quota is taken as exhausted for a whole window
+// from now. By then the oldest request in a sliding log has expired, even under
+// Boundary::Inclusive, so it is a bound on the wait rather than the exact reset.
+#[cfg(any(feature = "tower", feature = "axum"))]
+pub(crate) fn too_many_requests_headers(
+    max_requests: usize,
+    window: Duration,
+    now: DateTime<Utc>,
+) -> [(&'static str, String); 4] {
+    let quota = Quota {
+        limit: max_requests,
+        remaining: 0,
+        reset: now + window + Duration::nanoseconds(1),
+    };
+    let [limit, remaining, reset] = quota.headers(now);
+    [("Retry-After", reset.1.clone()), limit, remaining, reset]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Boundary, RateLimiter0};
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_quota_to_json() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let quota = Quota {
+            limit: 100,
+            remaining: 0,
+            reset: now + Duration::milliseconds(1500),
+        };
+
+        assert_eq!(
+            quota.to_json(now),
+            r#"{"limit": 100, "remaining": 0, "reset": "2023-10-01T12:00:01.500Z", "reset_after_seconds": 2}"#
+        );
+        assert_eq!(
+            Quota {
+                reset: now,
+                ..quota
+            }
+            .to_json(now + Duration::seconds(5)),
+            r#"{"limit": 100, "remaining": 0, "reset": "2023-10-01T12:00:00.000Z", "reset_after_seconds": 0}"#
+        );
+    }
+
+    #[test]
+    fn test_quota_reset_after_seconds_rounds_up_to_the_nanosecond() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let quota = |reset| Quota {
+            limit: 1,
+            remaining: 0,
+            reset,
+        };
+
+        assert_eq!(
+            quota(now + Duration::seconds(60)).reset_after_seconds(now),
+            60
+        );
+        assert_eq!(
+            quota(now + Duration::seconds(60) + Duration::nanoseconds(1)).reset_after_seconds(now),
+            61
+        );
+        assert_eq!(
+            quota(now - Duration::seconds(1)).reset_after_seconds(now),
+            0
+        );
+    }
+
+    // Waiting the advertised reset is enough for a limiter to admit again, whichever
+    // boundary it counts its window with
+    #[test]
+    fn test_quota_reset_after_seconds_suffices() {
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+
+        for boundary in [Boundary::Inclusive, Boundary::Exclusive] {
+            let rate_limiter =
+                RateLimiter0::with_config(2, Duration::seconds(60)).with_boundary(boundary);
+            rate_limiter.ratelimit0(ip, now);
+            rate_limiter.ratelimit0(ip, now);
+
+            let reset_after = rate_limiter.peek0(ip, now).reset_after_seconds(now);
+            let retry_at = now + Duration::seconds(reset_after);
+            assert_eq!(
+                rate_limiter.ratelimit0(ip, retry_at - Duration::seconds(1)),
+                false,
+                "{:?}",
+                boundary
+            );
+            assert_eq!(
+                rate_limiter.ratelimit0(ip, retry_at),
+                true,
+                "{:?}",
+                boundary
+            );
+        }
+    }
+
+    #[cfg(any(feature = "tower", feature = "axum"))]
+    #[test]
+    fn test_too_many_requests_headers_wait_out_the_window() {
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap();
+        let rate_limiter = RateLimiter0::with_config(2, Duration::seconds(60));
+        while rate_limiter.ratelimit0(ip, now) {}
+
+        let headers = too_many_requests_headers(2, Duration::seconds(60), now);
+        assert_eq!(
+            headers,
+            [
+                ("Retry-After", "61".to_string()),
+                ("RateLimit-Limit", "2".to_string()),
+                ("RateLimit-Remaining", "0".to_string()),
+                ("RateLimit-Reset", "61".to_string()),
+            ]
+        );
+        let retry_after = Duration::seconds(headers[0].1.parse().unwrap());
+        assert_eq!(rate_limiter.ratelimit0(ip, now + retry_after), true);
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+
+// The check every synchronous version implements, so implementations can be swapped
+// through generics or `dyn RateLimit`. RateLimiter4 can only answer asynchronously,
+// so it isn't one.
+pub trait RateLimit<K = IpAddr> {
+    // Records the request and returns whether it is admitted
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool;
+
+    // Like check, but the request takes up `cost` slots of the limit, for endpoints
+    // that are heavier than others. It is admitted only when all of them are free, and
+    // takes none when denied, so a cost over the limit is never admitted.
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool;
+
+    // Whether check would admit the request, without recording it, for requests that
+    // should be told about the limit without using it up, such as HEAD requests and
+    // CORS preflights. Wrappers still apply their own rules on top, so an allowlisted
+    // key is admitted and a script can deny a key all the same.
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool;
+}
+
+// The number of keys a limiter holds state for. Keys are never dropped, unless by
+// RateLimiter8 under an Eviction policy, so this only grows, and with it the
+// limiter's memory.
+pub trait TrackedKeys {
+    fn tracked_keys(&self) -> usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, StoredRateLimiter};
+    use crate::*;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_rate_limit_is_object_safe() {
+        let rate_limiters: Vec<Box<dyn RateLimit>> = vec![
+            Box::new(RateLimiter0::new()),
+            Box::new(RateLimiter1::new()),
+            Box::new(RateLimiter2::new()),
+            Box::new(RateLimiter3::new()),
+            Box::new(RateLimiter5::new()),
+            Box::new(RateLimiter6::new()),
+            Box::new(RateLimiter7::new()),
+            Box::new(RateLimiter8::new()),
+            Box::new(RateLimiter9::new()),
+        ];
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for rate_limiter in &rate_limiters {
+            let admitted = (0..=MAX_REQUESTS)
+                .filter(|_| rate_limiter.check(ip, now))
+                .count();
+            assert_eq!(admitted, MAX_REQUESTS);
+        }
+    }
+
+    #[test]
+    fn test_peek_does_not_record() {
+        let window = Duration::seconds(60);
+        let rate_limiters: Vec<Box<dyn RateLimit>> = vec![
+            Box::new(RateLimiter0::with_config(2, window)),
+            Box::new(RateLimiter1::with_config(2, window)),
+            Box::new(RateLimiter2::with_config(2, window)),
+            Box::new(RateLimiter3::with_config(2, window)),
+            Box::new(RateLimiter5::with_config(2, window)),
+            Box::new(RateLimiter6::with_config(2, window)),
+            Box::new(RateLimiter7::with_config(2, window)),
+            Box::new(RateLimiter8::with_config(2, window)),
+            Box::new(RateLimiter9::with_config(2, window)),
+            Box::new(GlobalRateLimiter::with_config(2, window)),
+            Box::new(PolicyRateLimiter::new(Policy::new().with_rule(2, window))),
+            Box::new(StoredRateLimiter::with_config(
+                MemoryStorage::default(),
+                2,
+                window,
+            )),
+            Box::new(HierarchicalRateLimiter::new(
+                RateLimiter0::with_config(2, window),
+                GlobalRateLimiter::with_config(10, window),
+            )),
+        ];
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for rate_limiter in &rate_limiters {
+            assert!((0..10).all(|_| rate_limiter.peek(ip, now)));
+            let admitted = (0..3).filter(|_| rate_limiter.check(ip, now)).count();
+            assert_eq!(admitted, 2);
+            assert_eq!(rate_limiter.peek(ip, now), false);
+            assert_eq!(rate_limiter.peek(ip, now + window * 2), true);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionRecord {
+    // Keys are only kept hashed, so dumps don't leak addresses
+    pub key_hash: u64,
+    pub timestamp: DateTime<Utc>,
+    pub admitted: bool,
+    pub rule: &'static str,
+    pub latency: std::time::Duration,
+}
+
+// Keeps the last `capacity` decisions in a ring buffer, so the context leading up to
+// an incident can be dumped on demand or automatically when denials spike. Every
+// slot has its own lock, so concurrent recorders only contend when they wrap around
+// onto the same slot.
+pub struct FlightRecorder {
+    slots: Box<[Slot]>,
+    next: AtomicU64,
+    deny_spike: Option<DenySpike>,
+}
+
+// A recorded decision along with its sequence number, which orders the dump
+type Slot = Mutex<Option<(u64, DecisionRecord)>>;
+
+struct DenySpike {
+    threshold: usize,
+    window: Duration,
+    // The start of the current window and the denials recorded in it
+    current: Mutex<(DateTime<Utc>, usize)>,
+    dump: Box<dyn Fn(Vec<DecisionRecord>) + Send + Sync>,
+}
+
+impl FlightRecorder {
+    pub fn new(capacity: usize) -> Self {
+        FlightRecorder {
+            slots: (0..capacity.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicU64::new(0),
+            deny_spike: None,
+        }
+    }
+
+    // Dumps the recorder once `threshold` denials are recorded within a `window` of
+    // decision time, and again for every later window that reaches it
+    pub fn on_deny_spike(
+        mut self,
+        threshold: usize,
+        window: Duration,
+        dump: impl Fn(Vec<DecisionRecord>) + Send + Sync + 'static,
+    ) -> Self {
+        self.deny_spike = Some(DenySpike {
+            threshold: threshold.max(1),
+            window,
+            current: Mutex::new((DateTime::<Utc>::MIN_UTC, 0)),
+            dump: Box::new(dump),
+        });
+        self
+    }
+
+    pub fn record(&self, record: DecisionRecord) {
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = (sequence % self.slots.len() as u64) as usize;
+        let (admitted, timestamp) = (record.admitted, record.timestamp);
+        *self.slots[slot].lock().unwrap() = Some((sequence, record));
+
+        if let (false, Some(deny_spike)) = (admitted, &self.deny_spike) {
+            let mut current = deny_spike.current.lock().unwrap();
+            if timestamp - current.0 >= deny_spike.window {
+                *current = (timestamp, 0);
+            }
+            current.1 += 1;
+            if current.1 == deny_spike.threshold {
+                drop(current);
+                (deny_spike.dump)(self.dump());
+            }
+        }
+    }
+
+    // Runs a limiter check and records its decision and how long it took
+    pub fn check<K: Hash>(
+        &self,
+        key: &K,
+        timestamp: DateTime<Utc>,
+        rule: &'static str,
+        check: impl FnOnce() -> bool,
+    ) -> bool {
+        let start = Instant::now();
+        let admitted = check();
+        self.record(DecisionRecord {
+            key_hash: key_hash(key),
+            timestamp,
+            admitted,
+            rule,
+            latency: start.elapsed(),
+        });
+        admitted
+    }
+
+    // The recorded decisions, oldest first. Recording carries on while dumping, so
+    // decisions made meanwhile may or may not be included.
+    pub fn dump(&self) -> Vec<DecisionRecord> {
+        let mut records: Vec<_> = self
+            .slots
+            .iter()
+            .filter_map(|slot| slot.lock().unwrap().clone())
+            .collect();
+        records.sort_by_key(|(sequence, _)| *sequence);
+        records.into_iter().map(|(_, record)| record).collect()
+    }
+}
+
+impl fmt::Debug for FlightRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlightRecorder")
+            .field("capacity", &self.slots.len())
+            .field("recorded", &self.next.load(Ordering::Relaxed))
+            .field(
+                "deny_spike_threshold",
+                &self.deny_spike.as_ref().map(|s| s.threshold),
+            )
+            .finish()
+    }
+}
+
+pub fn key_hash<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RateLimiter0, MAX_REQUESTS};
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    fn decision(admitted: bool, timestamp: DateTime<Utc>) -> DecisionRecord {
+        DecisionRecord {
+            key_hash: 0,
+            timestamp,
+            admitted,
+            rule: "default",
+            latency: std::time::Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_recorder_keeps_last_decisions_in_order() {
+        let recorder = FlightRecorder::new(3);
+        let now = Utc::now();
+
+        for i in 0..5 {
+            recorder.record(decision(true, now + Duration::seconds(i)));
+        }
+
+        let timestamps: Vec<_> = recorder.dump().iter().map(|r| r.timestamp).collect();
+        assert_eq!(
+            timestamps,
+            (2..5)
+                .map(|i| now + Duration::seconds(i))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_recorder_check_records_limiter_decisions() {
+        let recorder = FlightRecorder::new(MAX_REQUESTS * 2);
+        let rate_limiter = RateLimiter0::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..=MAX_REQUESTS {
+            recorder.check(&ip, now, "default", || rate_limiter.ratelimit0(ip, now));
+        }
+
+        let records = recorder.dump();
+        assert_eq!(records.len(), MAX_REQUESTS + 1);
+        assert!(records.iter().all(|r| r.key_hash == key_hash(&ip)));
+        assert_eq!(records.iter().filter(|r| !r.admitted).count(), 1);
+    }
+
+    #[test]
+    fn test_recorder_dumps_on_deny_spike() {
+        let dumps = Arc::new(Mutex::new(Vec::new()));
+        let recorder = FlightRecorder::new(10).on_deny_spike(3, Duration::seconds(1), {
+            let dumps = Arc::clone(&dumps);
+            move |records| dumps.lock().unwrap().push(records.len())
+        });
+        let now = Utc::now();
+
+        // Spread out denials never spike
+        for i in 0..3 {
+            recorder.record(decision(false, now + Duration::seconds(i)));
+        }
+        assert!(dumps.lock().unwrap().is_empty());
+
+        let later = now + Duration::seconds(10);
+        for _ in 0..5 {
+            recorder.record(decision(true, later));
+            recorder.record(decision(false, later));
+        }
+        assert_eq!(*dumps.lock().unwrap(), vec![9]);
+    }
+}
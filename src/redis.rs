@@ -0,0 +1,144 @@
+use crate::storage::Storage;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Mutex;
+
+// Runs the check of MemoryStorage inside Redis, which executes scripts atomically.
+// Lua numbers are doubles, which can't hold nanosecond timestamps exactly, so the
+// timestamps are stored as zero padded strings, whose order is the numeric order.
+const CHECK_SCRIPT: &str = r"
+local cutoff, timestamp, cost = ARGV[1], ARGV[2], tonumber(ARGV[3])
+local max_requests, expire_ms = tonumber(ARGV[4]), ARGV[5]
+
+while true do
+    local oldest = redis.call('LINDEX', KEYS[1], 0)
+    if not oldest or oldest >= cutoff then
+        break
+    end
+    redis.call('LPOP', KEYS[1])
+end
+
+if redis.call('LLEN', KEYS[1]) + cost > max_requests then
+    return 0
+end
+for _ = 1, cost do
+    redis.call('RPUSH', KEYS[1], timestamp)
+end
+redis.call('PEXPIRE', KEYS[1], expire_ms)
+return 1
+";
+
+// Keeps the sliding logs in Redis lists, so every replica using the same server
+// enforces the limits together. Checks share a single connection, so they are
+// serialized per RedisStorage.
+pub struct RedisStorage {
+    connection: Mutex<::redis::Connection>,
+    script: ::redis::Script,
+    prefix: String,
+}
+
+impl RedisStorage {
+    pub fn new(connection: ::redis::Connection) -> Self {
+        RedisStorage {
+            connection: Mutex::new(connection),
+            script: ::redis::Script::new(CHECK_SCRIPT),
+            prefix: "ratelimit:".to_string(),
+        }
+    }
+
+    // Connects to a server URL such as redis://127.0.0.1/
+    pub fn open(url: &str) -> ::redis::RedisResult<Self> {
+        Ok(Self::new(::redis::Client::open(url)?.get_connection()?))
+    }
+
+    // Prepended to every key, "ratelimit:" by default
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+impl Storage for RedisStorage {
+    type Error = ::redis::RedisError;
+
+    fn check(
+        &self,
+        key: &str,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+        max_requests: usize,
+        window: Duration,
+    ) -> ::redis::RedisResult<bool> {
+        // Idle keys expire once their newest request has left the window
+        let expire_ms = window.num_milliseconds().max(0) + 1;
+        let mut connection = self.connection.lock().unwrap();
+        self.script
+            .key(format!("{}{key}", self.prefix))
+            .arg(padded_nanos(timestamp - window))
+            .arg(padded_nanos(timestamp))
+            .arg(cost)
+            .arg(max_requests)
+            .arg(expire_ms)
+            .invoke(&mut *connection)
+    }
+}
+
+impl std::fmt::Debug for RedisStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStorage")
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+// Nanoseconds since the epoch, padded to the 19 digits of i64::MAX. Timestamps
+// before the epoch are clamped to it.
+fn padded_nanos(timestamp: DateTime<Utc>) -> String {
+    let nanos = timestamp.timestamp_nanos_opt().unwrap_or(i64::MAX).max(0);
+    format!("{nanos:019}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StoredRateLimiter;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_padded_nanos_sort_numerically() {
+        let epoch = DateTime::<Utc>::UNIX_EPOCH;
+        let timestamps = [
+            epoch - Duration::seconds(1),
+            epoch + Duration::nanoseconds(9),
+            epoch + Duration::nanoseconds(10),
+            Utc::now(),
+        ];
+
+        let padded: Vec<_> = timestamps.into_iter().map(padded_nanos).collect();
+        assert!(padded.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(padded.iter().all(|nanos| nanos.len() == 19));
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    fn test_redis_storage_shares_the_limit() {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL isn't set");
+        let prefix = format!("ratelimit-test-{}:", std::process::id());
+        let replicas: Vec<_> = (0..2)
+            .map(|_| {
+                let storage = RedisStorage::open(&url).unwrap().with_prefix(&prefix);
+                StoredRateLimiter::with_config(storage, 4, Duration::seconds(1))
+            })
+            .collect();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = (0..8)
+            .filter(|i| replicas[i % 2].try_check(ip, now).unwrap())
+            .count();
+        assert_eq!(admitted, 4);
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(replicas[0].try_check(ip, later).unwrap(), true);
+    }
+}
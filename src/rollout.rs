@@ -0,0 +1,212 @@
+use crate::stats::{Stats, StatsSnapshot};
+use crate::RateLimit;
+use chrono::{DateTime, Utc};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Percentages are kept in basis points, so rollouts can go below 1%
+const BASIS_POINTS: u32 = 10_000;
+
+// Which side of a toggle a key is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cohort {
+    Control,
+    Treatment,
+}
+
+impl Cohort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cohort::Control => "control",
+            Cohort::Treatment => "treatment",
+        }
+    }
+}
+
+// Enables a new behaviour, such as a new backend, stricter limits or penalties, for a
+// percentage of keys. A key's cohort is decided by its hash salted with the toggle's
+// name, so it is the same on every check and every replica of a build, and separate
+// toggles pick unrelated fractions of the keys. Raising the percentage only moves
+// keys into the treatment, so a rollout never flips a key back and forth.
+#[derive(Debug)]
+pub struct Toggle {
+    name: String,
+    basis_points: AtomicU32,
+}
+
+impl Toggle {
+    pub fn new(name: impl Into<String>, percent: f64) -> Self {
+        let toggle = Toggle {
+            name: name.into(),
+            basis_points: AtomicU32::new(0),
+        };
+        toggle.set_percent(percent);
+        toggle
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.basis_points.load(Ordering::Relaxed) as f64 / 100.0
+    }
+
+    // Takes effect on the next check, clamped to between 0 and 100
+    pub fn set_percent(&self, percent: f64) {
+        let basis_points = (percent * 100.0).round().clamp(0.0, BASIS_POINTS as f64);
+        self.basis_points
+            .store(basis_points as u32, Ordering::Relaxed);
+    }
+
+    pub fn cohort<K: Hash>(&self, key: &K) -> Cohort {
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        key.hash(&mut hasher);
+        let bucket = (hasher.finish() % BASIS_POINTS as u64) as u32;
+
+        if bucket < self.basis_points.load(Ordering::Relaxed) {
+            Cohort::Treatment
+        } else {
+            Cohort::Control
+        }
+    }
+
+    pub fn is_enabled<K: Hash>(&self, key: &K) -> bool {
+        self.cohort(key) == Cohort::Treatment
+    }
+}
+
+// Checks the keys of the toggle's treatment against `treatment`, and every other key
+// against `control`, counting the decisions of each cohort so the two can be
+// compared before rolling out further. Each limiter only sees its own cohort's
+// requests, so a key moved into the treatment starts with a fresh quota there.
+#[derive(Debug)]
+pub struct RolloutRateLimiter<C, T> {
+    toggle: Toggle,
+    control: C,
+    treatment: T,
+    control_stats: Stats,
+    treatment_stats: Stats,
+}
+
+impl<C, T> RolloutRateLimiter<C, T> {
+    pub fn new(toggle: Toggle, control: C, treatment: T) -> Self {
+        RolloutRateLimiter {
+            toggle,
+            control,
+            treatment,
+            control_stats: Stats::new(),
+            treatment_stats: Stats::new(),
+        }
+    }
+
+    // To change the percentage while running
+    pub fn toggle(&self) -> &Toggle {
+        &self.toggle
+    }
+
+    // The decisions made for the cohort's keys so far
+    pub fn stats(&self, cohort: Cohort) -> StatsSnapshot {
+        match cohort {
+            Cohort::Control => self.control_stats.snapshot(),
+            Cohort::Treatment => self.treatment_stats.snapshot(),
+        }
+    }
+}
+
+impl<K: Hash, C: RateLimit<K>, T: RateLimit<K>> RateLimit<K> for RolloutRateLimiter<C, T> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        match self.toggle.cohort(&key) {
+            Cohort::Control => {
+                let admitted = self.control.check_with_cost(key, timestamp, cost);
+                self.control_stats.record(admitted);
+                admitted
+            }
+            Cohort::Treatment => {
+                let admitted = self.treatment.check_with_cost(key, timestamp, cost);
+                self.treatment_stats.record(admitted);
+                admitted
+            }
+        }
+    }
+
+    // Peeks aren't decisions, so they are left out of either cohort's stats
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        match self.toggle.cohort(&key) {
+            Cohort::Control => self.control.peek(key, timestamp),
+            Cohort::Treatment => self.treatment.peek(key, timestamp),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiter2;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn ips() -> impl Iterator<Item = IpAddr> {
+        (0..10_000u32).map(|i| IpAddr::V4(Ipv4Addr::from(i)))
+    }
+
+    #[test]
+    fn test_toggle_enables_about_the_percentage_of_keys() {
+        let toggle = Toggle::new("stricter-limits", 10.0);
+
+        let enabled = ips().filter(|ip| toggle.is_enabled(ip)).count();
+        assert!((800..1200).contains(&enabled), "{} keys enabled", enabled);
+
+        assert_eq!(
+            ips()
+                .filter(|ip| Toggle::new("off", 0.0).is_enabled(ip))
+                .count(),
+            0
+        );
+        assert_eq!(
+            ips()
+                .filter(|ip| Toggle::new("on", 100.0).is_enabled(ip))
+                .count(),
+            10_000
+        );
+    }
+
+    #[test]
+    fn test_toggle_raising_the_percentage_keeps_enabled_keys() {
+        let toggle = Toggle::new("new-backend", 5.0);
+        let enabled: Vec<IpAddr> = ips().filter(|ip| toggle.is_enabled(ip)).collect();
+
+        toggle.set_percent(50.0);
+        assert_eq!(toggle.percent(), 50.0);
+        assert!(enabled.iter().all(|ip| toggle.is_enabled(ip)));
+    }
+
+    #[test]
+    fn test_rollout_ratelimiter_splits_keys_and_stats_by_cohort() {
+        let toggle = Toggle::new("stricter-limits", 50.0);
+        let control_ip = ips().find(|ip| !toggle.is_enabled(ip)).unwrap();
+        let treatment_ip = ips().find(|ip| toggle.is_enabled(ip)).unwrap();
+        let rate_limiter = RolloutRateLimiter::new(
+            toggle,
+            RateLimiter2::with_config(3, Duration::seconds(60)),
+            RateLimiter2::with_config(1, Duration::seconds(60)),
+        );
+        let now = Utc::now();
+
+        let admitted = |ip| (0..5).filter(|_| rate_limiter.check(ip, now)).count();
+        assert_eq!(admitted(control_ip), 3);
+        assert_eq!(admitted(treatment_ip), 1);
+
+        let control = rate_limiter.stats(Cohort::Control);
+        let treatment = rate_limiter.stats(Cohort::Treatment);
+        assert_eq!((control.allowed, control.denied), (3, 2));
+        assert_eq!((treatment.allowed, treatment.denied), (1, 4));
+    }
+}
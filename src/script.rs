@@ -0,0 +1,245 @@
+use crate::RateLimit;
+use chrono::{DateTime, Utc};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::fmt::{self, Display};
+
+// Operations a script may run per decision by default, which is plenty for comparing a
+// few metadata fields and keeps a runaway loop from stalling the check
+const MAX_OPERATIONS: u64 = 10_000;
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Parse(rhai::ParseError),
+    // The script failed, ran out of its budget, or didn't return a bool
+    Eval(Box<rhai::EvalAltResult>),
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Parse(error) => write!(f, "invalid decision script: {error}"),
+            ScriptError::Eval(error) => write!(f, "decision script failed: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+// Post-processes the decisions of a limiter with a rhai script, so policy tweaks can
+// be shipped as configuration instead of a rebuild of the host service. The script
+// sees the `key` as a string, whether the limiter `admitted` the request, and the
+// `metadata` of the check as a map of strings, and returns whether to admit it:
+//
+//   if metadata.plan == "internal" { true } else { admitted }
+//
+// The limiter has already recorded an admitted request by the time the script runs,
+// so denying it anyway doesn't give the slot back, and admitting a denied request
+// doesn't take one. Scripts run under a budget of operations, call depth and sizes,
+// and the limiter's decision stands when they fail or exceed it.
+pub struct ScriptedRateLimiter<L> {
+    limiter: L,
+    engine: Engine,
+    script: AST,
+}
+
+impl<L> ScriptedRateLimiter<L> {
+    pub fn new(limiter: L, script: &str) -> Result<Self, ScriptError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(8);
+        engine.set_max_expr_depths(32, 32);
+        engine.set_max_string_size(4096);
+        engine.set_max_array_size(256);
+        engine.set_max_map_size(256);
+        // Scripts have no business writing to the host's output
+        engine.on_print(|_| {});
+        engine.on_debug(|_, _, _| {});
+
+        let script = engine.compile(script).map_err(ScriptError::Parse)?;
+        Ok(ScriptedRateLimiter {
+            limiter,
+            engine,
+            script,
+        })
+    }
+
+    // Operations a script may run per decision, 10 000 by default
+    pub fn with_max_operations(mut self, max_operations: u64) -> Self {
+        self.engine.set_max_operations(max_operations.max(1));
+        self
+    }
+
+    pub fn try_check_with_metadata<K: Display>(
+        &self,
+        key: K,
+        timestamp: DateTime<Utc>,
+        metadata: &[(&str, &str)],
+    ) -> Result<bool, ScriptError>
+    where
+        L: RateLimit<K>,
+    {
+        let key_string = key.to_string();
+        let admitted = self.limiter.check(key, timestamp);
+        self.decide(key_string, admitted, metadata)
+    }
+
+    // Falls back to the limiter's decision when the script fails
+    pub fn check_with_metadata<K: Display>(
+        &self,
+        key: K,
+        timestamp: DateTime<Utc>,
+        metadata: &[(&str, &str)],
+    ) -> bool
+    where
+        L: RateLimit<K>,
+    {
+        let key_string = key.to_string();
+        let admitted = self.limiter.check(key, timestamp);
+        self.decide(key_string, admitted, metadata)
+            .unwrap_or(admitted)
+    }
+
+    fn decide(
+        &self,
+        key: String,
+        admitted: bool,
+        metadata: &[(&str, &str)],
+    ) -> Result<bool, ScriptError> {
+        let metadata: Map = metadata
+            .iter()
+            .map(|&(name, value)| (name.into(), Dynamic::from(value.to_string())))
+            .collect();
+        let mut scope = Scope::new();
+        scope.push("key", key);
+        scope.push("admitted", admitted);
+        scope.push("metadata", metadata);
+
+        self.engine
+            .eval_ast_with_scope(&mut scope, &self.script)
+            .map_err(ScriptError::Eval)
+    }
+}
+
+impl<K: Display, L: RateLimit<K>> RateLimit<K> for ScriptedRateLimiter<L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_metadata(key, timestamp, &[])
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let key_string = key.to_string();
+        let admitted = self.limiter.check_with_cost(key, timestamp, cost);
+        self.decide(key_string, admitted, &[]).unwrap_or(admitted)
+    }
+
+    // The script decides on the peek like on a check, so a key it denies stays denied
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let key_string = key.to_string();
+        let admitted = self.limiter.peek(key, timestamp);
+        self.decide(key_string, admitted, &[]).unwrap_or(admitted)
+    }
+}
+
+impl<L: fmt::Debug> fmt::Debug for ScriptedRateLimiter<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScriptedRateLimiter")
+            .field("limiter", &self.limiter)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RateLimiter2;
+    use chrono::Duration;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_scripted_ratelimiter_overrides_by_metadata() {
+        let rate_limiter = ScriptedRateLimiter::new(
+            RateLimiter2::with_config(1, Duration::seconds(60)),
+            r#"if metadata.plan == "internal" { true } else { admitted }"#,
+        )
+        .unwrap();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.check_with_metadata(ip, now, &[]), true);
+        assert_eq!(rate_limiter.check_with_metadata(ip, now, &[]), false);
+        assert_eq!(
+            rate_limiter.check_with_metadata(ip, now, &[("plan", "internal")]),
+            true
+        );
+        assert_eq!(
+            rate_limiter.check_with_metadata(ip, now, &[("plan", "free")]),
+            false
+        );
+    }
+
+    #[test]
+    fn test_scripted_ratelimiter_sees_the_key() {
+        let rate_limiter = ScriptedRateLimiter::new(
+            RateLimiter2::new(),
+            r#"admitted && !key.starts_with("10.")"#,
+        )
+        .unwrap();
+        let now = Utc::now();
+
+        assert_eq!(
+            rate_limiter.check("192.0.2.1".parse::<IpAddr>().unwrap(), now),
+            true
+        );
+        assert_eq!(
+            rate_limiter.check("10.0.0.1".parse::<IpAddr>().unwrap(), now),
+            false
+        );
+    }
+
+    #[test]
+    fn test_scripted_ratelimiter_decides_on_peeks() {
+        let rate_limiter = ScriptedRateLimiter::new(
+            RateLimiter2::with_config(1, Duration::seconds(60)),
+            r#"admitted && !key.starts_with("10.")"#,
+        )
+        .unwrap();
+        let (ip, banned) = (
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+            "10.0.0.1".parse::<IpAddr>().unwrap(),
+        );
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.peek(banned, now), false);
+        assert_eq!(rate_limiter.peek(ip, now), true);
+        assert_eq!(rate_limiter.check(ip, now), true);
+        assert_eq!(rate_limiter.peek(ip, now), false);
+    }
+
+    #[test]
+    fn test_scripted_ratelimiter_falls_back_when_over_budget() {
+        let rate_limiter = ScriptedRateLimiter::new(RateLimiter2::new(), "loop {}")
+            .unwrap()
+            .with_max_operations(100);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        assert!(matches!(
+            rate_limiter.try_check_with_metadata(ip, Utc::now(), &[]),
+            Err(ScriptError::Eval(_))
+        ));
+        assert_eq!(rate_limiter.check(ip, Utc::now()), true);
+    }
+
+    #[test]
+    fn test_scripted_ratelimiter_rejects_invalid_scripts() {
+        assert!(matches!(
+            ScriptedRateLimiter::new(RateLimiter2::<IpAddr>::new(), "if {"),
+            Err(ScriptError::Parse(_))
+        ));
+
+        let rate_limiter = ScriptedRateLimiter::new(RateLimiter2::new(), "42").unwrap();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        assert!(rate_limiter
+            .try_check_with_metadata(ip, Utc::now(), &[])
+            .is_err());
+    }
+}
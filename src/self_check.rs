@@ -0,0 +1,311 @@
+use super::*;
+use crate::storage::{MemoryStorage, Storage, StoredRateLimiter};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+
+// Quick invariant checks suitable for readiness probes. Every version, the
+// StoredRateLimiter over a MemoryStorage and the GlobalRateLimiter are exercised on a
+// fresh instance, so running it never affects live limiters. The storage is only
+// probed when given to self_check_with_storage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfCheckReport {
+    pub clock: CheckOutcome,
+    pub implementations: Vec<ImplementationReport>,
+    pub storage: Option<CheckOutcome>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImplementationReport {
+    pub name: &'static str,
+    pub strictness: Strictness,
+    pub outcome: CheckOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Passed,
+    Failed(String),
+}
+
+impl CheckOutcome {
+    pub fn passed(&self) -> bool {
+        *self == CheckOutcome::Passed
+    }
+}
+
+impl SelfCheckReport {
+    pub fn passed(&self) -> bool {
+        self.clock.passed()
+            && self
+                .implementations
+                .iter()
+                .all(|implementation| implementation.outcome.passed())
+            && self.storage.as_ref().is_none_or(CheckOutcome::passed)
+    }
+}
+
+pub fn self_check() -> SelfCheckReport {
+    SelfCheckReport {
+        clock: check_clock(),
+        implementations: check_implementations(),
+        storage: None,
+    }
+}
+
+// Also checks that the storage of the StoredRateLimiters in use, such as a
+// RedisStorage, can be reached
+pub fn self_check_with_storage<S: Storage>(storage: &S) -> SelfCheckReport
+where
+    S::Error: Display,
+{
+    SelfCheckReport {
+        storage: Some(probe_storage(storage)),
+        ..self_check()
+    }
+}
+
+fn check_implementations() -> Vec<ImplementationReport> {
+    vec![
+        ImplementationReport {
+            name: "ratelimiter0",
+            strictness: RateLimiter0::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter0::new();
+                move |ip, ts| rate_limiter.ratelimit0(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter1",
+            strictness: RateLimiter1::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter1::new();
+                move |ip, ts| rate_limiter.ratelimit1(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter2",
+            strictness: RateLimiter2::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter2::new();
+                move |ip, ts| rate_limiter.ratelimit2(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter3",
+            strictness: RateLimiter3::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter3::new();
+                move |ip, ts| rate_limiter.ratelimit3(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter4",
+            strictness: RateLimiter4::STRICTNESS,
+            outcome: check_ratelimiter4(),
+        },
+        ImplementationReport {
+            name: "ratelimiter5",
+            strictness: RateLimiter5::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter5::new();
+                move |ip, ts| rate_limiter.ratelimit5(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter6",
+            strictness: RateLimiter6::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter6::new();
+                move |ip, ts| rate_limiter.ratelimit6(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter7",
+            strictness: RateLimiter7::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter7::new();
+                move |ip, ts| rate_limiter.ratelimit7(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter8",
+            strictness: RateLimiter8::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter8::new();
+                move |ip, ts| rate_limiter.ratelimit8(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "ratelimiter9",
+            strictness: RateLimiter9::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = RateLimiter9::new();
+                move |ip, ts| rate_limiter.ratelimit9(ip, ts)
+            }),
+        },
+        // MemoryStorage checks under a write lock
+        ImplementationReport {
+            name: "stored",
+            strictness: Strictness::Strict,
+            outcome: check_invariants({
+                let rate_limiter = StoredRateLimiter::new(MemoryStorage::default());
+                move |ip, ts| rate_limiter.check(ip, ts)
+            }),
+        },
+        ImplementationReport {
+            name: "global",
+            strictness: GlobalRateLimiter::STRICTNESS,
+            outcome: check_invariants({
+                let rate_limiter = GlobalRateLimiter::new();
+                move |_, ts| rate_limiter.ratelimit(ts)
+            }),
+        },
+    ]
+}
+
+// RateLimiter4 needs a runtime for its shards. Callers such as a readiness probe
+// usually run on one already, where blocking on another would panic, so it gets a
+// runtime of its own on a thread of its own.
+fn check_ratelimiter4() -> CheckOutcome {
+    let checked = thread::spawn(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|error| format!("no runtime for the shards: {error}"))?;
+        let rate_limiter = runtime.block_on(async { RateLimiter4::new() });
+        Ok(check_invariants(|ip, ts| {
+            runtime.block_on(rate_limiter.ratelimit4(ip, ts))
+        }))
+    })
+    .join();
+    match checked {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(error)) => CheckOutcome::Failed(error),
+        Err(_) => CheckOutcome::Failed("a shard stopped".to_string()),
+    }
+}
+
+// A check of cost 0 goes through the storage like any other, but records nothing
+fn probe_storage<S: Storage>(storage: &S) -> CheckOutcome
+where
+    S::Error: Display,
+{
+    match storage.check("self-check", Utc::now(), 0, 1, Duration::seconds(1)) {
+        Ok(true) => CheckOutcome::Passed,
+        Ok(false) => CheckOutcome::Failed("storage denied a request of cost 0".to_string()),
+        Err(error) => CheckOutcome::Failed(format!("storage failed a check: {error}")),
+    }
+}
+
+fn check_clock() -> CheckOutcome {
+    let first = Utc::now();
+    let second = Utc::now();
+    let sane_after: DateTime<Utc> = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+
+    if first < sane_after {
+        return CheckOutcome::Failed(format!("system clock reports {first}"));
+    }
+    if second < first {
+        return CheckOutcome::Failed(format!("system clock went back from {first} to {second}"));
+    }
+    if first
+        .checked_add_signed(Duration::seconds(MAX_REQUESTS_DURATION_SECONDS))
+        .is_none()
+    {
+        return CheckOutcome::Failed(format!("{first} plus the window overflows"));
+    }
+    CheckOutcome::Passed
+}
+
+// The limit is admitted, the next request is denied, and a request after the window
+// is admitted again
+fn check_invariants(check: impl Fn(IpAddr, DateTime<Utc>) -> bool) -> CheckOutcome {
+    let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let now = Utc::now();
+
+    let admitted = (0..MAX_REQUESTS).filter(|_| check(ip, now)).count();
+    if admitted != MAX_REQUESTS {
+        return CheckOutcome::Failed(format!(
+            "admitted {admitted} of the first {MAX_REQUESTS} requests"
+        ));
+    }
+    if check(ip, now) {
+        return CheckOutcome::Failed("admitted a request over the limit".to_string());
+    }
+    let later = now + Duration::seconds(MAX_REQUESTS_DURATION_SECONDS + 1);
+    if !check(ip, later) {
+        return CheckOutcome::Failed("denied a request after the window passed".to_string());
+    }
+    CheckOutcome::Passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_self_check_passes() {
+        let report = self_check();
+
+        assert!(report.passed(), "{:?}", report);
+        assert_eq!(report.implementations.len(), 12);
+        assert_eq!(report.storage, None);
+    }
+
+    // Even from within a runtime, where RateLimiter4 can't block on one of its own
+    #[tokio::test]
+    async fn test_self_check_passes_within_a_runtime() {
+        let report = self_check();
+
+        assert!(report.passed(), "{:?}", report);
+    }
+
+    // Fails every check, like a storage that can't be reached
+    struct Unreachable;
+
+    impl Storage for Unreachable {
+        type Error = &'static str;
+
+        fn check(
+            &self,
+            _: &str,
+            _: DateTime<Utc>,
+            _: u32,
+            _: usize,
+            _: Duration,
+        ) -> Result<bool, Self::Error> {
+            Err("connection refused")
+        }
+    }
+
+    #[test]
+    fn test_self_check_with_storage_probes_it() {
+        let report = self_check_with_storage(&MemoryStorage::default());
+        assert!(report.passed(), "{:?}", report);
+        assert_eq!(report.storage, Some(CheckOutcome::Passed));
+
+        let report = self_check_with_storage(&Unreachable);
+        assert!(!report.passed());
+        assert_eq!(
+            report.storage,
+            Some(CheckOutcome::Failed(
+                "storage failed a check: connection refused".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_invariants_detects_broken_limiter() {
+        assert_eq!(
+            check_invariants(|_, _| true),
+            CheckOutcome::Failed("admitted a request over the limit".to_string())
+        );
+        assert_eq!(
+            check_invariants(|_, _| false),
+            CheckOutcome::Failed(format!("admitted 0 of the first {} requests", MAX_REQUESTS))
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::clock::timestamp_nanos;
+use chrono::{DateTime, Duration, Utc};
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Each key is counted in one counter per row, and its count is the lowest of them, so
+// it only runs high when every one of its counters is shared with a busier key
+const ROWS: usize = 4;
+
+// Counts requests per key in fixed windows, within a fixed amount of memory however
+// many keys it sees, like a count-min sketch. Keys sharing counters can only push
+// each other's counts up, so a key may be denied before its limit, but never admitted
+// over it. Each counter packs the number of its window in the high half and the
+// count in the low half, so a counter from an older window reads as zero.
+#[derive(Debug)]
+pub(crate) struct WindowSketch {
+    counters: Box<[AtomicU64]>,
+    width: usize,
+    hash_builder: RandomState,
+}
+
+impl WindowSketch {
+    // The widest sketch that fits in `bytes`, with at least one counter per row
+    pub(crate) fn with_bytes(bytes: usize) -> Self {
+        let width = (bytes / ROWS / size_of::<AtomicU64>()).max(1);
+        WindowSketch {
+            counters: (0..ROWS * width).map(|_| AtomicU64::new(0)).collect(),
+            width,
+            hash_builder: RandomState::new(),
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.counters.len() * size_of::<AtomicU64>()
+    }
+
+    // Admits up to `max_requests` per key in every fixed `window`, so up to twice that
+    // across the boundary of two windows, like RateLimiter7 without its weighting.
+    // The rows are updated one by one, so concurrent checks of a key can each see
+    // room for the last slot.
+    pub(crate) fn check<K: Hash>(
+        &self,
+        key: &K,
+        timestamp: DateTime<Utc>,
+        max_requests: usize,
+        window: Duration,
+        cost: u32,
+    ) -> bool {
+        let current = window_index(timestamp, window);
+        let count = self.count(key, current);
+        let Some(next) = count.checked_add(cost) else {
+            return false;
+        };
+        if next as usize > max_requests {
+            return false;
+        }
+
+        // Only raises counters to the key's new count, so keys sharing a counter with
+        // a busier one aren't pushed up further
+        for row in 0..ROWS {
+            let _ = self.counter(row, key).fetch_update(
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                |packed| {
+                    (count_in(packed, current) < next)
+                        .then_some(((current as u64) << 32) | next as u64)
+                },
+            );
+        }
+        true
+    }
+
+    // Whether check would admit a request, without counting it
+    pub(crate) fn peek<K: Hash>(
+        &self,
+        key: &K,
+        timestamp: DateTime<Utc>,
+        max_requests: usize,
+        window: Duration,
+    ) -> bool {
+        (self.count(key, window_index(timestamp, window)) as usize) < max_requests
+    }
+
+    fn count<K: Hash>(&self, key: &K, current: u32) -> u32 {
+        (0..ROWS)
+            .map(|row| count_in(self.counter(row, key).load(Ordering::Acquire), current))
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn counter<K: Hash>(&self, row: usize, key: &K) -> &AtomicU64 {
+        let column = self.hash_builder.hash_one((row, key)) as usize % self.width;
+        &self.counters[row * self.width + column]
+    }
+}
+
+// Wraps after 2^32 windows, far longer than a counter goes untouched
+fn window_index(timestamp: DateTime<Utc>, window: Duration) -> u32 {
+    let window_ns = window.num_nanoseconds().unwrap_or(i64::MAX).max(1);
+    timestamp_nanos(timestamp).div_euclid(window_ns) as u32
+}
+
+// The count of a counter, which reads as zero when last counted in another window
+fn count_in(packed: u64, current: u32) -> u32 {
+    if (packed >> 32) as u32 == current {
+        packed as u32
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_window_sketch_limits_each_key_per_window() {
+        let sketch = WindowSketch::with_bytes(4096);
+        let window = Duration::seconds(10);
+        let (ip, other_ip): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let admitted = |ip, at| {
+            (0..5)
+                .filter(|_| sketch.check(&ip, at, 3, window, 1))
+                .count()
+        };
+        assert_eq!(admitted(ip, start), 3);
+        assert_eq!(admitted(other_ip, start), 3);
+        assert_eq!(admitted(ip, start + Duration::seconds(9)), 0);
+        assert_eq!(admitted(ip, start + window), 3);
+    }
+
+    #[test]
+    fn test_window_sketch_peek_does_not_count() {
+        let sketch = WindowSketch::with_bytes(4096);
+        let window = Duration::seconds(10);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        assert!((0..5).all(|_| sketch.peek(&ip, start, 1, window)));
+        assert_eq!(sketch.check(&ip, start, 1, window, 1), true);
+        assert_eq!(sketch.peek(&ip, start, 1, window), false);
+        assert_eq!(sketch.peek(&ip, start + window, 1, window), true);
+        assert_eq!(sketch.peek(&ip, start, 0, window), false);
+    }
+
+    #[test]
+    fn test_window_sketch_keys_sharing_counters_are_denied_early() {
+        // A single counter per row, so every key shares them
+        let sketch = WindowSketch::with_bytes(0);
+        assert_eq!(sketch.bytes(), ROWS * size_of::<AtomicU64>());
+        let now = Utc::now();
+
+        let admitted = (0..100u8)
+            .filter(|&i| {
+                sketch.check(
+                    &IpAddr::from([10, 0, 0, i]),
+                    now,
+                    10,
+                    Duration::seconds(1),
+                    1,
+                )
+            })
+            .count();
+        assert_eq!(admitted, 10);
+    }
+
+    // Timestamps an i64 of nanoseconds can't hold saturate rather than panicking
+    #[test]
+    fn test_window_sketch_out_of_range_timestamps() {
+        let sketch = WindowSketch::with_bytes(4096);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        for timestamp in [DateTime::<Utc>::MIN_UTC, DateTime::<Utc>::MAX_UTC] {
+            assert_eq!(
+                sketch.check(&ip, timestamp, 1, Duration::seconds(1), 1),
+                true
+            );
+            assert_eq!(
+                sketch.check(&ip, timestamp, 1, Duration::seconds(1), 1),
+                false
+            );
+        }
+    }
+}
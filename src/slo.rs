@@ -0,0 +1,223 @@
+use crate::RateLimit;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Latency samples the p99 is taken over before deciding whether to switch modes
+const SAMPLES_PER_DECISION: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SloEvent {
+    // The p99 of the precise limiter went over the SLO, so the fallback took over
+    Degraded { p99: Duration },
+    // The p99 of the precise limiter is back within the SLO
+    Recovered { p99: Duration },
+}
+
+// Keeps the limiter from becoming the bottleneck it is meant to prevent. Checks go
+// to the precise limiter until the p99 of its sampled latencies exceeds the SLO, and
+// to the cheaper fallback until it is back within it. Sampled checks always go to
+// the precise limiter, so its latency keeps being measured while degraded.
+//
+// Each limiter only sees the requests routed to it, so around a switch a key may be
+// admitted by both up to their limits.
+pub struct SloGuard<P, F> {
+    precise: P,
+    fallback: F,
+    slo: Duration,
+    sample_every: u64,
+    checks: AtomicU64,
+    degraded: AtomicBool,
+    samples: Mutex<Vec<Duration>>,
+    on_transition: Option<Box<dyn Fn(SloEvent) + Send + Sync>>,
+}
+
+impl<P, F> SloGuard<P, F> {
+    pub fn new(precise: P, fallback: F, slo: Duration) -> Self {
+        SloGuard {
+            precise,
+            fallback,
+            slo,
+            sample_every: 64,
+            checks: AtomicU64::new(0),
+            degraded: AtomicBool::new(false),
+            samples: Mutex::new(Vec::with_capacity(SAMPLES_PER_DECISION)),
+            on_transition: None,
+        }
+    }
+
+    // Times every `sample_every`th check, 64 by default
+    pub fn with_sample_every(mut self, sample_every: u64) -> Self {
+        self.sample_every = sample_every.max(1);
+        self
+    }
+
+    pub fn on_transition(
+        mut self,
+        on_transition: impl Fn(SloEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_transition = Some(Box::new(on_transition));
+        self
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, latency: Duration) {
+        let p99 = {
+            let mut samples = self.samples.lock().unwrap();
+            samples.push(latency);
+            if samples.len() < SAMPLES_PER_DECISION {
+                return;
+            }
+            samples.sort_unstable();
+            let p99 = samples[(samples.len() * 99).div_ceil(100) - 1];
+            samples.clear();
+            p99
+        };
+
+        let degraded = p99 > self.slo;
+        if self.degraded.swap(degraded, Ordering::Relaxed) != degraded {
+            if let Some(on_transition) = &self.on_transition {
+                on_transition(if degraded {
+                    SloEvent::Degraded { p99 }
+                } else {
+                    SloEvent::Recovered { p99 }
+                });
+            }
+        }
+    }
+}
+
+impl<K, P: RateLimit<K>, F: RateLimit<K>> RateLimit<K> for SloGuard<P, F> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.check_with_cost(key, timestamp, 1)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let check = self.checks.fetch_add(1, Ordering::Relaxed);
+        if check.is_multiple_of(self.sample_every) {
+            let start = Instant::now();
+            let admitted = self.precise.check_with_cost(key, timestamp, cost);
+            self.record(start.elapsed());
+            admitted
+        } else if self.is_degraded() {
+            self.fallback.check_with_cost(key, timestamp, cost)
+        } else {
+            self.precise.check_with_cost(key, timestamp, cost)
+        }
+    }
+
+    // Peeks aren't sampled, so they go wherever unsampled checks go
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        if self.is_degraded() {
+            self.fallback.peek(key, timestamp)
+        } else {
+            self.precise.peek(key, timestamp)
+        }
+    }
+}
+
+impl<P, F> fmt::Debug for SloGuard<P, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SloGuard")
+            .field("slo", &self.slo)
+            .field("sample_every", &self.sample_every)
+            .field("degraded", &self.is_degraded())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RateLimiter2, RateLimiter6, MAX_REQUESTS};
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    // Admits everything, after stalling for as long as it is told to
+    struct Stalling {
+        stall: Mutex<Duration>,
+    }
+
+    impl RateLimit for Stalling {
+        fn check(&self, key: IpAddr, timestamp: DateTime<Utc>) -> bool {
+            self.check_with_cost(key, timestamp, 1)
+        }
+
+        fn check_with_cost(&self, _: IpAddr, _: DateTime<Utc>, _: u32) -> bool {
+            std::thread::sleep(*self.stall.lock().unwrap());
+            true
+        }
+
+        fn peek(&self, _: IpAddr, _: DateTime<Utc>) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_slo_guard_stays_precise_within_slo() {
+        let guard = SloGuard::new(
+            RateLimiter2::new(),
+            RateLimiter6::new(),
+            Duration::from_secs(1),
+        )
+        .with_sample_every(1);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = (0..SAMPLES_PER_DECISION * 2)
+            .filter(|_| guard.check(ip, now))
+            .count();
+        assert_eq!(admitted, MAX_REQUESTS);
+        assert!(!guard.is_degraded());
+    }
+
+    #[test]
+    fn test_slo_guard_degrades_and_recovers() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let precise = Stalling {
+            stall: Mutex::new(Duration::from_millis(2)),
+        };
+        let fallback = RateLimiter2::with_config(10, chrono::Duration::seconds(60));
+        let guard = SloGuard::new(precise, fallback, Duration::from_millis(1))
+            .with_sample_every(2)
+            .on_transition({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event)
+            });
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..SAMPLES_PER_DECISION * 2 {
+            guard.check(ip, now);
+        }
+        assert!(guard.is_degraded());
+        let degraded_p99 = match events.lock().unwrap()[..] {
+            [SloEvent::Degraded { p99 }] => p99,
+            ref events => panic!("Expected a single Degraded event, got {events:?}"),
+        };
+        assert!(degraded_p99 > Duration::from_millis(1));
+
+        // The unsampled checks now go to the fallback, which only admits 10
+        let denied = (0..40).filter(|_| !guard.check(ip, now)).count();
+        assert!(denied >= 10, "Only {denied} checks were denied");
+
+        // The batch of samples in progress still holds slow ones, so it takes until
+        // the next one to recover
+        *guard.precise.stall.lock().unwrap() = Duration::ZERO;
+        for _ in 0..SAMPLES_PER_DECISION * 4 {
+            guard.check(ip, now);
+        }
+        assert!(!guard.is_degraded());
+        assert_eq!(events.lock().unwrap().len(), 2);
+        assert!(matches!(
+            events.lock().unwrap()[1],
+            SloEvent::Recovered { .. }
+        ));
+    }
+}
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+// The sliding logs of a limiter, to carry its quotas over a restart. Serialized with
+// any serde format, written to disk before shutting down and restored with the
+// version's `from_snapshot` after starting again, every key is admitted exactly as if
+// the limiter had kept running. Versions 5 to 7 have no sliding logs to snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimiterSnapshot<K> {
+    // The limiter's own config, which keys it hasn't seen yet get
+    pub max_requests: usize,
+    #[serde(rename = "window_ns", with = "nanoseconds")]
+    pub window: Duration,
+    pub keys: Vec<KeySnapshot<K>>,
+}
+
+// The limits a key was created with, see LimitProvider, and the requests in its log
+// from the oldest to the newest. Requests that expired since aren't pruned until the
+// key's next check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeySnapshot<K> {
+    pub key: K,
+    pub max_requests: usize,
+    #[serde(rename = "window_ns", with = "nanoseconds")]
+    pub window: Duration,
+    pub requests: Vec<DateTime<Utc>>,
+}
+
+// Taken key by key while checks go on, so a check racing the snapshot may or may not
+// be in it. RateLimiter3 is the exception, as its snapshot changes its queues, so
+// checks have to be stopped first. RateLimiter4 answers asynchronously, so it has
+// snapshot4 instead.
+pub trait Snapshot<K> {
+    fn snapshot(&self) -> RateLimiterSnapshot<K>;
+}
+
+// chrono has no serde support for durations, so windows are kept in nanoseconds,
+// which covers about 292 years
+mod nanoseconds {
+    use chrono::Duration;
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(window: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        let nanoseconds = window
+            .num_nanoseconds()
+            .ok_or_else(|| ser::Error::custom("window too long to serialize"))?;
+        serializer.serialize_i64(nanoseconds)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let nanoseconds = i64::deserialize(deserializer)?;
+        if nanoseconds < 0 {
+            return Err(de::Error::custom("window can't be negative"));
+        }
+        Ok(Duration::nanoseconds(nanoseconds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    // Writes the snapshot out and reads it back, like a restart would
+    fn restart<K: Serialize + for<'de> Deserialize<'de>>(
+        snapshot: RateLimiterSnapshot<K>,
+    ) -> RateLimiterSnapshot<K> {
+        let json = serde_json::to_string(&snapshot).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_serializes_windows_in_nanoseconds() {
+        let rate_limiter = RateLimiter2::with_config(3, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = "2024-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        rate_limiter.ratelimit2(ip, now);
+
+        let json = serde_json::to_value(rate_limiter.snapshot()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "max_requests": 3,
+                "window_ns": 60_000_000_000i64,
+                "keys": [{
+                    "key": "127.0.0.1",
+                    "max_requests": 3,
+                    "window_ns": 60_000_000_000i64,
+                    "requests": ["2024-01-01T00:00:00Z"],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_snapshot_rejects_negative_windows() {
+        let json = r#"{"max_requests": 3, "window_ns": -1, "keys": []}"#;
+        assert!(serde_json::from_str::<RateLimiterSnapshot<IpAddr>>(json).is_err());
+    }
+
+    // Every version restored from a snapshot denies what the original would have
+    #[test]
+    fn test_from_snapshot_keeps_every_quota() {
+        let (ip, other_ip): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let now = Utc::now();
+        let fill = |rate_limiter: &dyn RateLimit| {
+            (0..3).for_each(|_| {
+                rate_limiter.check(ip, now);
+            });
+            rate_limiter.check(other_ip, now);
+        };
+        let admitted = |rate_limiter: &dyn RateLimit, ip| {
+            (0..5)
+                .filter(|_| rate_limiter.check(ip, now + Duration::seconds(1)))
+                .count()
+        };
+
+        let rate_limiter0 = RateLimiter0::with_config(3, Duration::seconds(60));
+        let rate_limiter1 = RateLimiter1::with_config(3, Duration::seconds(60));
+        let rate_limiter2 = RateLimiter2::with_config(3, Duration::seconds(60));
+        let rate_limiter3 = RateLimiter3::with_config(3, Duration::seconds(60));
+        let rate_limiter8 = RateLimiter8::with_config(3, Duration::seconds(60));
+        let rate_limiter9 = RateLimiter9::with_config(3, Duration::seconds(60));
+        fill(&rate_limiter0);
+        fill(&rate_limiter1);
+        fill(&rate_limiter2);
+        fill(&rate_limiter3);
+        fill(&rate_limiter8);
+        fill(&rate_limiter9);
+
+        let restored: Vec<(&str, Box<dyn RateLimit>)> = vec![
+            (
+                "RateLimiter0",
+                Box::new(RateLimiter0::from_snapshot(restart(
+                    rate_limiter0.snapshot(),
+                ))),
+            ),
+            (
+                "RateLimiter1",
+                Box::new(RateLimiter1::from_snapshot(restart(
+                    rate_limiter1.snapshot(),
+                ))),
+            ),
+            (
+                "RateLimiter2",
+                Box::new(RateLimiter2::from_snapshot(restart(
+                    rate_limiter2.snapshot(),
+                ))),
+            ),
+            (
+                "RateLimiter3",
+                Box::new(RateLimiter3::from_snapshot(restart(
+                    rate_limiter3.snapshot(),
+                ))),
+            ),
+            (
+                "RateLimiter8",
+                Box::new(RateLimiter8::from_snapshot(restart(
+                    rate_limiter8.snapshot(),
+                ))),
+            ),
+            (
+                "RateLimiter9",
+                Box::new(RateLimiter9::from_snapshot(restart(
+                    rate_limiter9.snapshot(),
+                ))),
+            ),
+        ];
+        for (name, rate_limiter) in restored {
+            let new_ip = "127.0.0.3".parse::<IpAddr>().unwrap();
+            assert_eq!(
+                (
+                    admitted(&*rate_limiter, ip),
+                    admitted(&*rate_limiter, other_ip),
+                    admitted(&*rate_limiter, new_ip)
+                ),
+                (0, 2, 3),
+                "{}",
+                name
+            );
+        }
+    }
+
+    // Any version's snapshot can be restored by any other, and some accept a limit of 0
+    // that RateLimiter3::with_config rejects
+    #[test]
+    fn test_from_snapshot_with_a_limit_of_zero() {
+        let snapshot = RateLimiterSnapshot::<IpAddr> {
+            max_requests: 0,
+            window: Duration::seconds(60),
+            keys: Vec::new(),
+        };
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        let restored = RateLimiter3::from_snapshot(restart(snapshot));
+        assert_eq!(restored.ratelimit3(ip, Utc::now()), false);
+    }
+
+    #[test]
+    fn test_from_snapshot_keeps_the_limits_of_each_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter2::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let now = Utc::now();
+        rate_limiter.ratelimit2(premium, now);
+
+        // The provider isn't carried over, but the key's limits are
+        let restored = RateLimiter2::from_snapshot(restart(rate_limiter.snapshot()));
+        let admitted = (0..10)
+            .filter(|_| restored.ratelimit2(premium, now))
+            .count();
+        assert_eq!(admitted, 4);
+    }
+}
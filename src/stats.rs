@@ -0,0 +1,188 @@
+use crossbeam_utils::CachePadded;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// A counter split over several cache-line-padded atomics, each thread sticks to
+// one shard so concurrent increments don't bounce the same cache line between
+// cores. Reads sum all the shards, so they're only as consistent as Relaxed allows.
+#[derive(Debug)]
+pub struct ShardedCounter {
+    shards: Box<[CachePadded<AtomicU64>]>,
+}
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+impl ShardedCounter {
+    pub fn new() -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(parallelism.next_power_of_two())
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        ShardedCounter {
+            shards: (0..shards.max(1))
+                .map(|_| CachePadded::new(AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        let shard = SHARD.with(|shard| *shard) % self.shards.len();
+        self.shards[shard].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+impl Default for ShardedCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    allowed: ShardedCounter,
+    denied: ShardedCounter,
+    duplicates: ShardedCounter,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub allowed: u64,
+    pub denied: u64,
+    // Requests submitted for a key at the same timestamp as its previous one
+    pub duplicates: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats::default()
+    }
+
+    pub fn record(&self, permitted: bool) {
+        if permitted {
+            self.allowed.increment();
+        } else {
+            self.denied.increment();
+        }
+    }
+
+    pub fn record_duplicate(&self) {
+        self.duplicates.increment();
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            allowed: self.allowed.sum(),
+            denied: self.denied.sum(),
+            duplicates: self.duplicates.sum(),
+        }
+    }
+}
+
+// Renders a snapshot in the OpenMetrics text exposition format, for serving as a
+// scrape endpoint without pulling in a metrics crate. Content-Type:
+// application/openmetrics-text; version=1.0.0; charset=utf-8
+pub fn render_openmetrics(snapshot: &StatsSnapshot) -> String {
+    format!(
+        "# TYPE ratelimit_decisions counter\n\
+         # HELP ratelimit_decisions Rate limit decisions, by whether the request was allowed.\n\
+         ratelimit_decisions_total{{decision=\"allowed\"}} {}\n\
+         ratelimit_decisions_total{{decision=\"denied\"}} {}\n\
+         # TYPE ratelimit_duplicates counter\n\
+         # HELP ratelimit_duplicates Requests submitted for a key at the same timestamp as its previous one.\n\
+         ratelimit_duplicates_total {}\n\
+         # EOF\n",
+        snapshot.allowed, snapshot.denied, snapshot.duplicates
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn test_sharded_counter_sums_all_shards() {
+        const NUM_THREADS: usize = 8;
+        const INCREMENTS: u64 = 10_000;
+        let counter = Arc::new(ShardedCounter::with_shards(4));
+
+        (0..NUM_THREADS)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..INCREMENTS {
+                        counter.increment();
+                    }
+                })
+            })
+            .for_each(|thread| {
+                thread.join().expect("Thread failed");
+            });
+
+        assert_eq!(counter.sum(), NUM_THREADS as u64 * INCREMENTS);
+    }
+
+    #[test]
+    fn test_sharded_counter_shards_are_padded() {
+        let counter = ShardedCounter::with_shards(2);
+        let first = &*counter.shards[0] as *const AtomicU64 as usize;
+        let second = &*counter.shards[1] as *const AtomicU64 as usize;
+
+        assert!(second - first >= 64);
+    }
+
+    #[test]
+    fn test_stats_records_decisions() {
+        let stats = Stats::new();
+        stats.record(true);
+        stats.record(true);
+        stats.record(false);
+        stats.record_duplicate();
+
+        assert_eq!(
+            stats.snapshot(),
+            StatsSnapshot {
+                allowed: 2,
+                denied: 1,
+                duplicates: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_render_openmetrics() {
+        let snapshot = StatsSnapshot {
+            allowed: 2,
+            denied: 1,
+            duplicates: 3,
+        };
+
+        assert_eq!(
+            render_openmetrics(&snapshot),
+            "# TYPE ratelimit_decisions counter\n\
+             # HELP ratelimit_decisions Rate limit decisions, by whether the request was allowed.\n\
+             ratelimit_decisions_total{decision=\"allowed\"} 2\n\
+             ratelimit_decisions_total{decision=\"denied\"} 1\n\
+             # TYPE ratelimit_duplicates counter\n\
+             # HELP ratelimit_duplicates Requests submitted for a key at the same timestamp as its previous one.\n\
+             ratelimit_duplicates_total 3\n\
+             # EOF\n"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{RateLimit, MAX_REQUESTS, MAX_REQUESTS_DURATION_SECONDS};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::sync::{Arc, RwLock};
+
+// Where the sliding logs of a StoredRateLimiter live. Replicas sharing a storage
+// can't lock each other out, so the whole check has to happen atomically inside it.
+pub trait Storage {
+    type Error;
+
+    // Drops the requests of `key` made before `timestamp - window`, then records the
+    // request as `cost` requests if they fit within `max_requests`, returning whether
+    // it did
+    fn check(
+        &self,
+        key: &str,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+        max_requests: usize,
+        window: Duration,
+    ) -> Result<bool, Self::Error>;
+}
+
+impl<S: Storage + ?Sized> Storage for &S {
+    type Error = S::Error;
+
+    fn check(
+        &self,
+        key: &str,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+        max_requests: usize,
+        window: Duration,
+    ) -> Result<bool, S::Error> {
+        (**self).check(key, timestamp, cost, max_requests, window)
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    type Error = S::Error;
+
+    fn check(
+        &self,
+        key: &str,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+        max_requests: usize,
+        window: Duration,
+    ) -> Result<bool, S::Error> {
+        (**self).check(key, timestamp, cost, max_requests, window)
+    }
+}
+
+// Keeps the logs in process, like RateLimiter0. Mostly useful to run the same code
+// against in tests that runs against a shared storage in production.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    requests: RwLock<HashMap<String, VecDeque<DateTime<Utc>>>>,
+}
+
+impl Storage for MemoryStorage {
+    type Error = Infallible;
+
+    fn check(
+        &self,
+        key: &str,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+        max_requests: usize,
+        window: Duration,
+    ) -> Result<bool, Infallible> {
+        let cutoff_time = timestamp - window;
+        let mut requests = self.requests.write().unwrap();
+        let current_requests = requests.entry(key.to_string()).or_default();
+
+        while let Some(front_time) = current_requests.front() {
+            if *front_time < cutoff_time {
+                current_requests.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if current_requests.len() + cost as usize > max_requests {
+            return Ok(false);
+        }
+        current_requests.extend(std::iter::repeat_n(timestamp, cost as usize));
+        Ok(true)
+    }
+}
+
+// A sliding log limiter keeping its state in a Storage, so replicas sharing the
+// storage enforce the limit together. Keys are stored by their Display form.
+#[derive(Debug)]
+pub struct StoredRateLimiter<S> {
+    storage: S,
+    max_requests: usize,
+    window: Duration,
+}
+
+impl<S: Storage> StoredRateLimiter<S> {
+    pub fn new(storage: S) -> Self {
+        Self::with_config(
+            storage,
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    pub fn with_config(storage: S, max_requests: usize, window: Duration) -> Self {
+        StoredRateLimiter {
+            storage,
+            max_requests,
+            window,
+        }
+    }
+
+    pub fn try_check<K: Display>(
+        &self,
+        key: K,
+        timestamp: DateTime<Utc>,
+    ) -> Result<bool, S::Error> {
+        self.try_check_with_cost(key, timestamp, 1)
+    }
+
+    pub fn try_check_with_cost<K: Display>(
+        &self,
+        key: K,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+    ) -> Result<bool, S::Error> {
+        self.storage.check(
+            &key.to_string(),
+            timestamp,
+            cost,
+            self.max_requests,
+            self.window,
+        )
+    }
+
+    // Whether try_check would admit the request, without recording it. A check of
+    // cost 0 records nothing, and fits within one slot less than the limit exactly
+    // when a request of cost 1 fits within the limit.
+    pub fn try_peek<K: Display>(&self, key: K, timestamp: DateTime<Utc>) -> Result<bool, S::Error> {
+        let Some(max_requests) = self.max_requests.checked_sub(1) else {
+            return Ok(false);
+        };
+        self.storage
+            .check(&key.to_string(), timestamp, 0, max_requests, self.window)
+    }
+}
+
+// Admits requests the storage failed to check, so an outage of a shared storage
+// doesn't take the service down with it. Use try_check to handle errors otherwise.
+impl<K: Display, S: Storage> RateLimit<K> for StoredRateLimiter<S> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.try_check(key, timestamp).unwrap_or(true)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.try_check_with_cost(key, timestamp, cost)
+            .unwrap_or(true)
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.try_peek(key, timestamp).unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    // Fails every check, like a storage that can't be reached
+    struct Unreachable;
+
+    impl Storage for Unreachable {
+        type Error = &'static str;
+
+        fn check(
+            &self,
+            _: &str,
+            _: DateTime<Utc>,
+            _: u32,
+            _: usize,
+            _: Duration,
+        ) -> Result<bool, Self::Error> {
+            Err("unreachable")
+        }
+    }
+
+    #[test]
+    fn test_stored_ratelimiter_over_denied() {
+        let rate_limiter = StoredRateLimiter::new(MemoryStorage::default());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.check(ip, now), true);
+        }
+        assert_eq!(rate_limiter.check(ip, now), false);
+    }
+
+    #[test]
+    fn test_stored_ratelimiters_share_storage() {
+        let storage = MemoryStorage::default();
+        let replicas = [
+            StoredRateLimiter::with_config(&storage, 4, Duration::seconds(1)),
+            StoredRateLimiter::with_config(&storage, 4, Duration::seconds(1)),
+        ];
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = (0..8).filter(|i| replicas[i % 2].check(ip, now)).count();
+        assert_eq!(admitted, 4);
+    }
+
+    #[test]
+    fn test_stored_ratelimiter_with_cost() {
+        let rate_limiter =
+            StoredRateLimiter::with_config(MemoryStorage::default(), 4, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 3), true);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 2), false);
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 1), true);
+        assert_eq!(rate_limiter.check(ip, now), false);
+    }
+
+    #[test]
+    fn test_stored_ratelimiter_fails_open() {
+        let rate_limiter = StoredRateLimiter::new(Unreachable);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        assert_eq!(rate_limiter.try_check(ip, Utc::now()), Err("unreachable"));
+        assert_eq!(rate_limiter.check(ip, Utc::now()), true);
+    }
+
+    #[test]
+    fn test_stored_ratelimiter_peeks_with_a_check_of_cost_zero() {
+        let rate_limiter =
+            StoredRateLimiter::with_config(MemoryStorage::default(), 1, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.try_peek(ip, now), Ok(true));
+        assert_eq!(rate_limiter.try_peek(ip, now), Ok(true));
+        assert_eq!(rate_limiter.try_check(ip, now), Ok(true));
+        assert_eq!(rate_limiter.try_peek(ip, now), Ok(false));
+
+        let blocked =
+            StoredRateLimiter::with_config(MemoryStorage::default(), 0, Duration::seconds(60));
+        assert_eq!(blocked.try_peek(ip, now), Ok(false));
+    }
+}
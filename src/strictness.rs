@@ -0,0 +1,134 @@
+// How exactly an implementation enforces the limit when a key is hammered from
+// several threads at once. Single-threaded use is always exact.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strictness {
+    // Never admits more than the limit within a window
+    Strict,
+    // May over-admit by at most `epsilon * limit` (rounded up) within a window, in
+    // exchange for avoiding synchronization
+    Relaxed { epsilon: f64 },
+    // Races between callers can admit any number of requests
+    Unbounded,
+}
+
+impl Strictness {
+    pub fn max_admitted(&self, limit: usize) -> Option<usize> {
+        match self {
+            Strictness::Strict => Some(limit),
+            // Saturates, as a huge epsilon over-admits more than a usize can count
+            Strictness::Relaxed { epsilon } => {
+                Some(limit.saturating_add((limit as f64 * epsilon.max(0.0)).ceil() as usize))
+            }
+            Strictness::Unbounded => None,
+        }
+    }
+
+    // The share of the limit that may be over-admitted, None when unbounded
+    pub fn epsilon(&self) -> Option<f64> {
+        match self {
+            Strictness::Strict => Some(0.0),
+            Strictness::Relaxed { epsilon } => Some(epsilon.max(0.0)),
+            Strictness::Unbounded => None,
+        }
+    }
+
+    pub fn permits(&self, admitted: usize, limit: usize) -> bool {
+        self.max_admitted(limit)
+            .is_none_or(|max_admitted| admitted <= max_admitted)
+    }
+}
+
+// Hammers a single key from several threads within one window, returning how many
+// of the requests were admitted
+#[cfg(test)]
+pub(crate) fn admitted_under_contention(
+    check: impl Fn(std::net::IpAddr, chrono::DateTime<chrono::Utc>) -> bool + Send + Sync,
+    threads: usize,
+    requests_per_thread: usize,
+) -> usize {
+    let ip = "127.0.0.1".parse().unwrap();
+    let now = chrono::Utc::now();
+    let check = &check;
+
+    std::thread::scope(|scope| {
+        (0..threads)
+            .map(|_| {
+                scope.spawn(move || (0..requests_per_thread).filter(|_| check(ip, now)).count())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|thread| thread.join().expect("Thread failed"))
+            .sum()
+    })
+}
+
+// The most of the `admitted` times, in order, that fall within one closed window
+#[cfg(test)]
+pub(crate) fn max_within_a_window(
+    admitted: &[chrono::DateTime<chrono::Utc>],
+    window: chrono::Duration,
+) -> usize {
+    let mut start = 0;
+    (0..admitted.len())
+        .map(|end| {
+            while admitted[start] < admitted[end] - window {
+                start += 1;
+            }
+            end - start + 1
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_strict_allows_exactly_the_limit() {
+        assert_eq!(Strictness::Strict.max_admitted(100), Some(100));
+        assert!(Strictness::Strict.permits(100, 100));
+        assert!(!Strictness::Strict.permits(101, 100));
+    }
+
+    #[test]
+    fn test_relaxed_allows_epsilon_rounded_up() {
+        let relaxed = Strictness::Relaxed { epsilon: 0.01 };
+
+        assert_eq!(relaxed.max_admitted(100), Some(101));
+        assert_eq!(relaxed.max_admitted(150), Some(152));
+        assert!(relaxed.permits(101, 100));
+        assert!(!relaxed.permits(102, 100));
+    }
+
+    #[test]
+    fn test_relaxed_saturates_a_huge_epsilon() {
+        assert_eq!(
+            Strictness::Relaxed { epsilon: 1e20 }.max_admitted(100),
+            Some(usize::MAX)
+        );
+        assert_eq!(
+            Strictness::Relaxed {
+                epsilon: f64::INFINITY
+            }
+            .max_admitted(100),
+            Some(usize::MAX)
+        );
+        assert!(Strictness::Relaxed { epsilon: 1e20 }.permits(usize::MAX, 100));
+    }
+
+    #[test]
+    fn test_epsilon_of_each_strictness() {
+        assert_eq!(Strictness::Strict.epsilon(), Some(0.0));
+        assert_eq!(Strictness::Relaxed { epsilon: 0.1 }.epsilon(), Some(0.1));
+        assert_eq!(Strictness::Relaxed { epsilon: -1.0 }.epsilon(), Some(0.0));
+        assert_eq!(Strictness::Unbounded.epsilon(), None);
+    }
+
+    #[test]
+    fn test_unbounded_permits_anything() {
+        assert_eq!(Strictness::Unbounded.max_admitted(100), None);
+        assert!(Strictness::Unbounded.permits(usize::MAX, 100));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::Policy;
+use chrono::Duration;
+
+// Policies tuned for common endpoints, to start from rather than picking raw numbers.
+// Each is a plain Policy, so it can be given to a PolicyRateLimiter as it is, or
+// extended with further rules through `with_rule`. Every template pairs a short
+// window that caps bursts with longer ones that cap sustained use, each allowing
+// more requests but a lower rate than the one before it.
+
+// For login, password reset and other credential checks, keyed by account or client.
+// A person mistyping their password retries a handful of times within a minute, then
+// gives up or resets it, so 5 attempts per minute never gets in their way. Credential
+// stuffing and password guessing pace themselves under that, which the hourly and
+// daily rules stop at 20 and 50 attempts.
+pub fn login_protection() -> Policy {
+    Policy::new()
+        .with_rule(5, Duration::minutes(1))
+        .with_rule(20, Duration::hours(1))
+        .with_rule(50, Duration::days(1))
+}
+
+// For a public API keyed by client or API key. Clients tend to fan out a few calls
+// at once, such as when loading a page, so bursts of 20 within a second are fine,
+// while sustained use is held to 10 per second. The hourly rule of 10,000 stops a
+// client from running at that rate all day, as batch jobs and runaway retry loops do.
+pub fn public_api_default() -> Policy {
+    Policy::new()
+        .with_rule(20, Duration::seconds(1))
+        .with_rule(600, Duration::minutes(1))
+        .with_rule(10_000, Duration::hours(1))
+}
+
+// For an endpoint receiving webhooks, keyed by sender. Providers deliver in bursts,
+// and redeliver everything queued at once after an outage on either side, while a
+// denied delivery is retried later, or dropped for good. So bursts of 50 per second
+// are allowed, and the limit only protects against a misbehaving sender, at 1,000
+// per minute.
+pub fn webhook_receiver() -> Policy {
+    Policy::new()
+        .with_rule(50, Duration::seconds(1))
+        .with_rule(1000, Duration::minutes(1))
+}
+
+// For pages browsed by people, keyed by client, to slow down scrapers. A person
+// rarely opens more than a couple of pages within a second, or a page a second for a
+// minute, and reads for most of an hour. A crawler keeps going, so it runs into the
+// 500 pages per hour.
+pub fn scraper_defense() -> Policy {
+    Policy::new()
+        .with_rule(2, Duration::seconds(1))
+        .with_rule(60, Duration::minutes(1))
+        .with_rule(500, Duration::hours(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PolicyRateLimiter, RateLimit, Violation};
+    use chrono::Utc;
+    use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
+
+    fn templates() -> [(&'static str, Policy); 4] {
+        [
+            ("login_protection", login_protection()),
+            ("public_api_default", public_api_default()),
+            ("webhook_receiver", webhook_receiver()),
+            ("scraper_defense", scraper_defense()),
+        ]
+    }
+
+    // A rule that admits fewer requests than a shorter one, or at a higher rate than
+    // it, could never deny anything
+    #[test]
+    fn test_templates_longer_windows_allow_more_requests_at_a_lower_rate() {
+        for (name, policy) in templates() {
+            for pair in policy.rules().windows(2) {
+                let (shorter, longer) = (pair[0], pair[1]);
+                assert!(shorter.window < longer.window, "{}: {}", name, longer);
+                assert!(
+                    shorter.max_requests < longer.max_requests,
+                    "{}: {} after {}",
+                    name,
+                    longer,
+                    shorter
+                );
+                let rate = |max_requests: usize, window: Duration| {
+                    max_requests as f64 / window.num_seconds() as f64
+                };
+                assert!(
+                    rate(longer.max_requests, longer.window)
+                        < rate(shorter.max_requests, shorter.window),
+                    "{}: {} after {}",
+                    name,
+                    longer,
+                    shorter
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_login_protection_stops_paced_guessing() {
+        let rate_limiter = PolicyRateLimiter::new(login_protection());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |at| (0..10).filter(|_| rate_limiter.check(ip, at)).count();
+        assert_eq!(admitted(now), 5);
+
+        // Guessing at the per minute limit runs into the hourly one after four bursts
+        let guessed: usize = (1..=10)
+            .map(|burst| admitted(now + Duration::seconds(61 * burst)))
+            .sum();
+        assert_eq!(guessed, 15);
+        assert_eq!(
+            rate_limiter.check_policy(ip, now + Duration::minutes(15)),
+            Err(Violation {
+                rule: login_protection().rules()[1]
+            })
+        );
+    }
+
+    #[test]
+    fn test_public_api_default_allows_bursts_above_the_sustained_rate() {
+        let rate_limiter = PolicyRateLimiter::new(public_api_default());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |at| (0..30).filter(|_| rate_limiter.check(ip, at)).count();
+        assert_eq!(admitted(now), 20);
+
+        // Bursting every second, the minute still admits 10 per second on average
+        let seconds: usize = (1..60)
+            .map(|second| admitted(now + Duration::seconds(second)))
+            .sum();
+        assert_eq!(20 + seconds, 600);
+    }
+
+    #[test]
+    fn test_webhook_receiver_admits_a_redelivery_burst() {
+        let rate_limiter = PolicyRateLimiter::new(webhook_receiver());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.check_with_cost(ip, now, 50), true);
+        assert_eq!(rate_limiter.check(ip, now), false);
+        assert_eq!(rate_limiter.check(ip, now + Duration::seconds(2)), true);
+    }
+
+    #[test]
+    fn test_scraper_defense_admits_browsing_and_stops_crawling() {
+        let rate_limiter = PolicyRateLimiter::new(scraper_defense());
+        let (person, crawler): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let now = Utc::now();
+
+        // A page every ten seconds for an hour
+        let browsed = (0..360)
+            .filter(|&i| rate_limiter.check(person, now + Duration::seconds(i * 10)))
+            .count();
+        assert_eq!(browsed, 360);
+
+        // A page a second for an hour
+        let crawled = (0..3600)
+            .filter(|&i| rate_limiter.check(crawler, now + Duration::seconds(i)))
+            .count();
+        assert_eq!(crawled, 500);
+    }
+}
@@ -0,0 +1,148 @@
+// Runs every sliding log version against the canonical decision vectors in testdata,
+// which pin down the exact semantics at the boundaries. RateLimiter5 to RateLimiter7
+// deliberately admit differently, so they aren't among them.
+use crate::*;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+const VECTORS: &str = include_str!("../testdata/sliding_window.json");
+
+#[derive(Debug, Deserialize)]
+struct Vector {
+    name: String,
+    max_requests: usize,
+    window_ns: i64,
+    // Inclusive unless stated otherwise
+    #[serde(default)]
+    boundary: VectorBoundary,
+    requests: Vec<Request>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum VectorBoundary {
+    #[default]
+    Inclusive,
+    Exclusive,
+}
+
+impl From<VectorBoundary> for Boundary {
+    fn from(boundary: VectorBoundary) -> Self {
+        match boundary {
+            VectorBoundary::Inclusive => Boundary::Inclusive,
+            VectorBoundary::Exclusive => Boundary::Exclusive,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    key: IpAddr,
+    at_ns: i64,
+    #[serde(default = "default_cost")]
+    cost: u32,
+    admitted: bool,
+}
+
+fn default_cost() -> u32 {
+    1
+}
+
+fn vectors() -> Vec<Vector> {
+    serde_json::from_str(VECTORS).expect("Malformed test vectors")
+}
+
+// Runs every vector against a fresh limiter, panicking on the first decision that
+// doesn't match
+fn check_vectors(
+    implementation: &str,
+    mut new_limiter: impl FnMut(
+        usize,
+        Duration,
+        Boundary,
+    ) -> Box<dyn FnMut(IpAddr, DateTime<Utc>, u32) -> bool>,
+) {
+    let epoch = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+
+    for vector in vectors() {
+        let mut check = new_limiter(
+            vector.max_requests,
+            Duration::nanoseconds(vector.window_ns),
+            vector.boundary.into(),
+        );
+        for (i, request) in vector.requests.iter().enumerate() {
+            let at = epoch + Duration::nanoseconds(request.at_ns);
+            assert_eq!(
+                check(request.key, at, request.cost),
+                request.admitted,
+                "{implementation}: {}: request {i} ({} at {}ns)",
+                vector.name,
+                request.key,
+                request.at_ns
+            );
+        }
+    }
+}
+
+#[test]
+fn test_vectors_ratelimiter0() {
+    check_vectors("ratelimiter0", |max_requests, window, boundary| {
+        let rate_limiter = RateLimiter0::with_config(max_requests, window).with_boundary(boundary);
+        Box::new(move |ip, ts, cost| rate_limiter.check_with_cost(ip, ts, cost))
+    });
+}
+
+#[test]
+fn test_vectors_ratelimiter1() {
+    check_vectors("ratelimiter1", |max_requests, window, boundary| {
+        let rate_limiter = RateLimiter1::with_config(max_requests, window).with_boundary(boundary);
+        Box::new(move |ip, ts, cost| rate_limiter.check_with_cost(ip, ts, cost))
+    });
+}
+
+#[test]
+fn test_vectors_ratelimiter2() {
+    check_vectors("ratelimiter2", |max_requests, window, boundary| {
+        let rate_limiter = RateLimiter2::with_config(max_requests, window).with_boundary(boundary);
+        Box::new(move |ip, ts, cost| rate_limiter.check_with_cost(ip, ts, cost))
+    });
+}
+
+#[test]
+fn test_vectors_ratelimiter3() {
+    check_vectors("ratelimiter3", |max_requests, window, boundary| {
+        let rate_limiter = RateLimiter3::with_config(max_requests, window).with_boundary(boundary);
+        Box::new(move |ip, ts, cost| rate_limiter.check_with_cost(ip, ts, cost))
+    });
+}
+
+#[test]
+fn test_vectors_ratelimiter4() {
+    let runtime = std::sync::Arc::new(tokio::runtime::Runtime::new().unwrap());
+    check_vectors("ratelimiter4", |max_requests, window, boundary| {
+        let runtime = std::sync::Arc::clone(&runtime);
+        let rate_limiter = runtime.block_on(async {
+            RateLimiter4::with_config(max_requests, window).with_boundary(boundary)
+        });
+        Box::new(move |ip, ts, cost| {
+            runtime.block_on(rate_limiter.ratelimit_with_cost4(ip, ts, cost))
+        })
+    });
+}
+
+#[test]
+fn test_vectors_ratelimiter8() {
+    check_vectors("ratelimiter8", |max_requests, window, boundary| {
+        let rate_limiter = RateLimiter8::with_config(max_requests, window).with_boundary(boundary);
+        Box::new(move |ip, ts, cost| rate_limiter.check_with_cost(ip, ts, cost))
+    });
+}
+
+#[test]
+fn test_vectors_ratelimiter9() {
+    check_vectors("ratelimiter9", |max_requests, window, boundary| {
+        let rate_limiter = RateLimiter9::with_config(max_requests, window).with_boundary(boundary);
+        Box::new(move |ip, ts, cost| rate_limiter.check_with_cost(ip, ts, cost))
+    });
+}
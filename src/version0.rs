@@ -1,44 +1,389 @@
 use super::*;
 use chrono::{DateTime, Duration, Utc};
 use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash, RandomState};
 use std::net::IpAddr;
 use std::sync::RwLock;
 
-#[derive(Debug, Default)]
-pub struct RateLimiter0 {
-    requests: RwLock<HashMap<IpAddr, VecDeque<DateTime<Utc>>>>,
+// Keyed by source IP by default, but any hashable key such as a Fingerprint works.
+// Keys are hashed with SipHash under random keys by default, so clients can't craft
+// keys that collide. Faster hashers can be plugged in through `S` when the keys are
+// trusted. Limits can be overridden per key through a LimitProvider `L`.
+#[derive(Debug)]
+pub struct RateLimiter0<K = IpAddr, S = RandomState, L = GlobalLimits> {
+    requests: RwLock<HashMap<K, VecDeque<DateTime<Utc>>, S>>,
+    max_requests: usize,
+    window: Duration,
+    boundary: Boundary,
+    limits: L,
 }
 
 impl RateLimiter0 {
+    // The whole map is behind one lock, so the check and the push are atomic
+    pub const STRICTNESS: Strictness = Strictness::Strict;
+}
+
+impl<K: Hash + Eq, S: BuildHasher + Default> Default for RateLimiter0<K, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K: Hash + Eq> RateLimiter0<K> {
     pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to `max_requests` per key within any `window`, instead of the crate
+    // wide MAX_REQUESTS per MAX_REQUESTS_DURATION_SECONDS
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        Self::with_config_and_hasher(max_requests, window, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, S: BuildHasher> RateLimiter0<K, S> {
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_config_and_hasher(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+            hash_builder,
+        )
+    }
+
+    pub fn with_config_and_hasher(max_requests: usize, window: Duration, hash_builder: S) -> Self {
+        RateLimiter0 {
+            requests: RwLock::new(HashMap::with_hasher(hash_builder)),
+            max_requests,
+            window,
+            boundary: Boundary::default(),
+            limits: GlobalLimits,
+        }
+    }
+}
+
+impl<K: Hash + Eq, S: BuildHasher, L: LimitProvider<K>> RateLimiter0<K, S, L> {
+    // Whether a request made exactly `window` before a check still counts against it,
+    // which is the case by default
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    // Resolves the limit of every key through `limits` at check time, falling back to
+    // the limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter0<K, S, P> {
         RateLimiter0 {
-            requests: RwLock::new(HashMap::new()),
+            requests: self.requests,
+            max_requests: self.max_requests,
+            window: self.window,
+            boundary: self.boundary,
+            limits,
         }
     }
 
-    pub fn ratelimit0(&self, src_ip: IpAddr, timestamp: DateTime<Utc>) -> bool {
-        let cutoff_time = timestamp - Duration::seconds(MAX_REQUESTS_DURATION_SECONDS);
+    fn limits_of(&self, key: &K) -> (usize, Duration) {
+        self.limits
+            .limits(key)
+            .unwrap_or((self.max_requests, self.window))
+    }
+
+    pub fn ratelimit0(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost0(src_ip, timestamp, 1)
+    }
+
+    // Records the request as `cost` requests at the same timestamp, if all of them fit
+    pub fn ratelimit_with_cost0(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let (max_requests, window) = self.limits_of(&src_ip);
+        let cutoff_time = timestamp - window;
 
         let mut requests = self.requests.write().unwrap(); // In production code we'd handle
                                                            // the case of a poisoned lock
         let current_requests = requests.entry(src_ip).or_default();
 
         while let Some(front_time) = current_requests.front() {
-            if *front_time < cutoff_time {
+            if !self.boundary.contains(cutoff_time, *front_time) {
                 current_requests.pop_front();
             } else {
                 break;
             }
         }
 
-        if current_requests.len() >= MAX_REQUESTS {
+        if current_requests.len() + cost as usize > max_requests {
             return false;
         }
 
-        current_requests.push_back(timestamp);
+        current_requests.extend(std::iter::repeat_n(timestamp, cost as usize));
 
         true
     }
+
+    // Takes `cost` slots like ratelimit_with_cost0, but only holds them until the
+    // reservation is committed or aborted, so a caller admitting an operation through
+    // several limiters can give back what it took when a later one denies it. Returns
+    // None when the slots don't fit.
+    pub fn reserve0(
+        &self,
+        src_ip: K,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+    ) -> Option<Reservation<'_, K, S, L>>
+    where
+        K: Clone,
+    {
+        // Built only once admitted, as dropping it frees the slots
+        if !self.ratelimit_with_cost0(src_ip.clone(), timestamp, cost) {
+            return None;
+        }
+        Some(Reservation {
+            limiter: self,
+            key: src_ip,
+            timestamp,
+            cost,
+        })
+    }
+
+    pub fn peek0(&self, src_ip: K, timestamp: DateTime<Utc>) -> Quota {
+        let (max_requests, window) = self.limits_of(&src_ip);
+        let cutoff_time = timestamp - window;
+
+        let requests = self.requests.read().unwrap();
+        let in_window = || {
+            requests
+                .get(&src_ip)
+                .into_iter()
+                .flatten()
+                .filter(|&&time| self.boundary.contains(cutoff_time, time))
+        };
+
+        Quota {
+            limit: max_requests,
+            remaining: max_requests.saturating_sub(in_window().count()),
+            reset: in_window()
+                .min()
+                .map_or(timestamp, |&oldest| self.boundary.expiry(oldest, window)),
+        }
+    }
+
+    // Estimates when the source IP will run out of quota if it keeps up the rate of the
+    // requests currently in its window. Returns None when that rate never reaches the
+    // limit, or when there is nothing to estimate the rate from.
+    pub fn projected_exhaustion0(
+        &self,
+        src_ip: K,
+        timestamp: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let (max_requests, window) = self.limits_of(&src_ip);
+        let cutoff_time = timestamp - window;
+
+        let requests = self.requests.read().unwrap();
+        let (count, oldest) = requests
+            .get(&src_ip)?
+            .iter()
+            .filter(|&&time| self.boundary.contains(cutoff_time, time))
+            .fold((0, timestamp), |(count, oldest), &time| {
+                (count + 1, oldest.min(time))
+            });
+
+        if count == 0 {
+            return None;
+        }
+        if count >= max_requests {
+            return Some(timestamp);
+        }
+
+        // count requests over span means count * window / span requests per window
+        let span = (timestamp - oldest).num_nanoseconds()?.max(1) as i128;
+        let window = window.num_nanoseconds()? as i128;
+        if (count as i128) * window < (max_requests as i128) * span {
+            return None;
+        }
+
+        let remaining = (max_requests - count) as i128;
+        let until_exhausted = span * remaining / count as i128;
+        Some(timestamp + Duration::nanoseconds(until_exhausted as i64))
+    }
+}
+
+// Slots taken by reserve0. They count against the key like any request until the
+// reservation is settled: committing keeps them, while aborting or dropping it frees
+// them again, so an early return can't leak quota.
+#[derive(Debug)]
+#[must_use = "dropping a reservation aborts it"]
+pub struct Reservation<'a, K: Hash + Eq, S: BuildHasher = RandomState, L = GlobalLimits> {
+    limiter: &'a RateLimiter0<K, S, L>,
+    key: K,
+    timestamp: DateTime<Utc>,
+    cost: u32,
+}
+
+impl<K: Hash + Eq, S: BuildHasher, L> Reservation<'_, K, S, L> {
+    pub fn commit(mut self) {
+        // Nothing is left to free once dropped
+        self.cost = 0;
+    }
+
+    pub fn abort(self) {}
+}
+
+impl<K: Hash + Eq, S: BuildHasher, L> Drop for Reservation<'_, K, S, L> {
+    fn drop(&mut self) {
+        if self.cost == 0 {
+            return;
+        }
+        let mut requests = self.limiter.requests.write().unwrap();
+        // The slots may have expired from the log already, in which case there is
+        // nothing to free. Requests at the same timestamp are interchangeable.
+        if let Some(current_requests) = requests.get_mut(&self.key) {
+            let mut cost = self.cost;
+            current_requests.retain(|&time| {
+                let reserved = cost > 0 && time == self.timestamp;
+                cost -= reserved as u32;
+                !reserved
+            });
+        }
+    }
+}
+
+// The source IP wouldn't be admitted before the deadline, so until_ready_by0 gave up
+// without waiting. A key with a limit of 0 is never admitted, which next_admission
+// reports as DateTime::<Utc>::MAX_UTC.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    pub next_admission: DateTime<Utc>,
+}
+
+#[cfg(feature = "tokio")]
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no request admitted before {}", self.next_admission)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl std::error::Error for DeadlineExceeded {}
+
+#[cfg(feature = "tokio")]
+impl<K: Hash + Eq + Clone, S: BuildHasher, L: LimitProvider<K>> RateLimiter0<K, S, L> {
+    // Waits until the source IP is admitted and records the request, for clients
+    // throttling themselves rather than handling denials. Sleeps until the oldest
+    // request in the window expires, and checks again, as other callers may have
+    // taken the slot in the meantime.
+    //
+    // Panics if the key's limit is 0, as it would never be admitted. Use
+    // until_ready_by0 where a LimitProvider may block keys that way.
+    pub async fn until_ready0(&self, src_ip: K) {
+        loop {
+            let now = Utc::now();
+            if self.ratelimit0(src_ip.clone(), now) {
+                return;
+            }
+            let quota = self.peek0(src_ip.clone(), now);
+            assert!(
+                quota.limit > 0,
+                "until_ready0 would wait forever for a key with a limit of 0"
+            );
+            sleep_until(quota.next_admission(now), now).await;
+        }
+    }
+
+    // Like until_ready0, but gives up as soon as the next admission is known to be
+    // after the deadline, rather than sleeping until then
+    pub async fn until_ready_by0(
+        &self,
+        src_ip: K,
+        deadline: DateTime<Utc>,
+    ) -> Result<(), DeadlineExceeded> {
+        loop {
+            let now = Utc::now();
+            if self.ratelimit0(src_ip.clone(), now) {
+                return Ok(());
+            }
+            let quota = self.peek0(src_ip.clone(), now);
+            if quota.limit == 0 {
+                return Err(DeadlineExceeded {
+                    next_admission: DateTime::<Utc>::MAX_UTC,
+                });
+            }
+            let next_admission = quota.next_admission(now);
+            if next_admission > deadline {
+                return Err(DeadlineExceeded { next_admission });
+            }
+            sleep_until(next_admission, now).await;
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn sleep_until(at: DateTime<Utc>, now: DateTime<Utc>) {
+    tokio::time::sleep((at - now).to_std().unwrap_or_default()).await;
+}
+
+impl<K: Hash + Eq, S: BuildHasher, L: LimitProvider<K>> RateLimit<K> for RateLimiter0<K, S, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit0(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost0(key, timestamp, cost)
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.peek0(key, timestamp).remaining > 0
+    }
+}
+
+impl<K, S, L> TrackedKeys for RateLimiter0<K, S, L> {
+    fn tracked_keys(&self) -> usize {
+        self.requests.read().unwrap().len()
+    }
+}
+
+// The limits of each key are those the provider gives it now, as they are asked for
+// at every check
+#[cfg(feature = "serde")]
+impl<K: Hash + Eq + Clone, S: BuildHasher, L: LimitProvider<K>> Snapshot<K>
+    for RateLimiter0<K, S, L>
+{
+    fn snapshot(&self) -> RateLimiterSnapshot<K> {
+        let requests = self.requests.read().unwrap();
+        RateLimiterSnapshot {
+            max_requests: self.max_requests,
+            window: self.window,
+            keys: requests
+                .iter()
+                .map(|(key, requests)| {
+                    let (max_requests, window) = self.limits_of(key);
+                    KeySnapshot {
+                        key: key.clone(),
+                        max_requests,
+                        window,
+                        requests: requests.iter().copied().collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Hash + Eq> RateLimiter0<K> {
+    // Restores the logs of every key. The limits of the keys aren't, as they are
+    // resolved at every check, so a LimitProvider has to be given again through
+    // with_limits.
+    pub fn from_snapshot(snapshot: RateLimiterSnapshot<K>) -> Self {
+        let rate_limiter = Self::with_config(snapshot.max_requests, snapshot.window);
+        rate_limiter.requests.write().unwrap().extend(
+            snapshot
+                .keys
+                .into_iter()
+                .map(|key| (key.key, key.requests.into())),
+        );
+        rate_limiter
+    }
 }
 
 #[cfg(test)]
@@ -102,6 +447,224 @@ mod tests {
         assert_eq!(rate_limiter.ratelimit0(ip, later), true);
     }
 
+    #[test]
+    fn test_ratelimit0_with_config() {
+        let rate_limiter = RateLimiter0::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit0(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit0(ip, now), false);
+        assert_eq!(
+            rate_limiter.ratelimit0(ip, now + Duration::seconds(1)),
+            false
+        );
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit0(ip, later), true);
+    }
+
+    #[test]
+    fn test_ratelimit0_with_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let rate_limiter =
+            RateLimiter0::<IpAddr, _>::with_hasher(BuildHasherDefault::<DefaultHasher>::default());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit0(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit0(ip, now), false);
+    }
+
+    #[test]
+    fn test_reserve0_frees_the_slots_unless_committed() {
+        let rate_limiter = RateLimiter0::with_config(5, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let reservation = rate_limiter.reserve0(ip, now, 3).unwrap();
+        assert_eq!(rate_limiter.peek0(ip, now).remaining, 2);
+        assert!(rate_limiter.reserve0(ip, now, 3).is_none());
+        reservation.abort();
+        assert_eq!(rate_limiter.peek0(ip, now).remaining, 5);
+
+        // Dropping a reservation aborts it as well
+        drop(rate_limiter.reserve0(ip, now, 3));
+        assert_eq!(rate_limiter.peek0(ip, now).remaining, 5);
+
+        rate_limiter.reserve0(ip, now, 3).unwrap().commit();
+        assert_eq!(rate_limiter.peek0(ip, now).remaining, 2);
+    }
+
+    #[test]
+    fn test_reserve0_across_limiters_takes_all_or_nothing() {
+        let per_ip = RateLimiter0::with_config(10, Duration::seconds(60));
+        let per_account = RateLimiter0::with_config(1, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let account = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admit = || {
+            let by_ip = per_ip.reserve0(ip, now, 1)?;
+            let by_account = per_account.reserve0(account, now, 1)?;
+            by_ip.commit();
+            by_account.commit();
+            Some(())
+        };
+
+        assert_eq!(admit(), Some(()));
+        // The account denies these, so the IP keeps its quota
+        for _ in 0..5 {
+            assert_eq!(admit(), None);
+        }
+        assert_eq!(per_ip.peek0(ip, now).remaining, 9);
+    }
+
+    #[test]
+    fn test_peek0_does_not_record() {
+        let rate_limiter = RateLimiter0::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS * 2 {
+            assert_eq!(rate_limiter.peek0(ip, now).remaining, MAX_REQUESTS);
+        }
+        assert_eq!(rate_limiter.peek0(ip, now).reset, now);
+    }
+
+    #[test]
+    fn test_peek0_reports_remaining_and_reset() {
+        let rate_limiter = RateLimiter0::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+        let window = Duration::seconds(MAX_REQUESTS_DURATION_SECONDS);
+
+        rate_limiter.ratelimit0(ip, now);
+        for _ in 1..MAX_REQUESTS {
+            rate_limiter.ratelimit0(ip, now + Duration::seconds(1));
+        }
+
+        let quota = rate_limiter.peek0(ip, now + Duration::seconds(1));
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset, now + window + Duration::nanoseconds(1));
+
+        let next_admission = quota.next_admission(now + Duration::seconds(1));
+        assert_eq!(
+            rate_limiter.ratelimit0(ip, next_admission - Duration::nanoseconds(1)),
+            false
+        );
+        assert_eq!(rate_limiter.ratelimit0(ip, next_admission), true);
+    }
+
+    #[test]
+    fn test_projected_exhaustion0_at_current_rate() {
+        let rate_limiter = RateLimiter0::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.projected_exhaustion0(ip, now), None);
+
+        // 5 requests per second, so the remaining half of the quota lasts 10 seconds
+        for i in 0..MAX_REQUESTS / 2 {
+            rate_limiter.ratelimit0(ip, now + Duration::milliseconds(200 * i as i64));
+        }
+
+        let later = now + Duration::seconds(10);
+        assert_eq!(
+            rate_limiter.projected_exhaustion0(ip, later),
+            Some(later + Duration::seconds(10))
+        );
+    }
+
+    #[test]
+    fn test_projected_exhaustion0_sustainable_rate() {
+        let rate_limiter = RateLimiter0::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for i in 0..10 {
+            rate_limiter.ratelimit0(ip, now + Duration::seconds(5 * i));
+        }
+
+        assert_eq!(
+            rate_limiter.projected_exhaustion0(ip, now + Duration::seconds(50)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_peek0_reports_configured_limit() {
+        let rate_limiter = RateLimiter0::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..3 {
+            rate_limiter.ratelimit0(ip, now);
+        }
+
+        let quota = rate_limiter.peek0(ip, now);
+        assert_eq!((quota.limit, quota.remaining), (5, 2));
+        assert_eq!(
+            quota.reset,
+            now + Duration::seconds(1) + Duration::nanoseconds(1)
+        );
+    }
+
+    #[test]
+    fn test_ratelimit0_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter0::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit0(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+
+        let quota = rate_limiter.peek0(premium, later);
+        assert_eq!((quota.limit, quota.remaining), (5, 0));
+    }
+
+    #[test]
+    fn test_peek0_honours_exclusive_boundary() {
+        let rate_limiter =
+            RateLimiter0::with_config(1, Duration::seconds(1)).with_boundary(Boundary::Exclusive);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        rate_limiter.ratelimit0(ip, now);
+
+        let later = now + Duration::seconds(1);
+        assert_eq!(rate_limiter.peek0(ip, now).reset, later);
+        assert_eq!(rate_limiter.peek0(ip, later).remaining, 1);
+        assert_eq!(rate_limiter.ratelimit0(ip, later), true);
+    }
+
+    #[test]
+    fn test_projected_exhaustion0_already_exhausted() {
+        let rate_limiter = RateLimiter0::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            rate_limiter.ratelimit0(ip, now);
+        }
+
+        assert_eq!(rate_limiter.projected_exhaustion0(ip, now), Some(now));
+    }
+
     #[test]
     fn test_ratelimit0_concurrent_access_respects_max_requests_limit() {
         const NUM_THREADS: usize = 10;
@@ -129,6 +692,25 @@ mod tests {
         assert_eq!(total_requests.load(Ordering::SeqCst), MAX_REQUESTS);
     }
 
+    #[test]
+    fn test_ratelimit0_honours_strictness() {
+        const NUM_THREADS: usize = 10;
+        let rate_limiter = RateLimiter0::new();
+
+        let admitted = crate::strictness::admitted_under_contention(
+            |ip, ts| rate_limiter.ratelimit0(ip, ts),
+            NUM_THREADS,
+            MAX_REQUESTS,
+        );
+
+        assert!(
+            RateLimiter0::STRICTNESS.permits(admitted, MAX_REQUESTS),
+            "Admitted {} requests with a limit of {}",
+            admitted,
+            MAX_REQUESTS
+        );
+    }
+
     #[test]
     fn test_ratelimiter0_request_overlimit() {
         const THREAD_REQUESTS: usize = 60;
@@ -162,4 +744,74 @@ mod tests {
             total_denials
         );
     }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_until_ready0_waits_for_the_oldest_request_to_expire() {
+        let window = Duration::milliseconds(50);
+        let rate_limiter = RateLimiter0::with_config(2, window);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let start = Utc::now();
+
+        rate_limiter.until_ready0(ip).await;
+        rate_limiter.until_ready0(ip).await;
+        assert!(Utc::now() - start < window);
+
+        rate_limiter.until_ready0(ip).await;
+        assert!(Utc::now() - start >= window);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_until_ready_by0_gives_up_before_the_deadline() {
+        let rate_limiter = RateLimiter0::with_config(1, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let start = Utc::now();
+
+        assert_eq!(
+            rate_limiter
+                .until_ready_by0(ip, start + Duration::seconds(1))
+                .await,
+            Ok(())
+        );
+        let Err(exceeded) = rate_limiter
+            .until_ready_by0(ip, start + Duration::seconds(1))
+            .await
+        else {
+            panic!("Admitted a request over the limit");
+        };
+        assert!(exceeded.next_admission >= start + Duration::seconds(60));
+        assert!(Utc::now() - start < Duration::seconds(1));
+    }
+
+    // A limit of 0 leaves remaining at 0 with nothing to expire, so waiting for the
+    // next admission would spin forever
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    #[should_panic(expected = "until_ready0 would wait forever for a key with a limit of 0")]
+    async fn test_until_ready0_panics_on_a_limit_of_zero() {
+        let rate_limiter = RateLimiter0::with_config(0, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        rate_limiter.until_ready0(ip).await;
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_until_ready_by0_gives_up_on_a_limit_of_zero() {
+        let blocked = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter0::with_config(1, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == blocked).then(|| (0, Duration::seconds(60))));
+        let start = Utc::now();
+
+        assert_eq!(
+            rate_limiter
+                .until_ready_by0(blocked, start + Duration::seconds(1))
+                .await,
+            Err(DeadlineExceeded {
+                next_admission: DateTime::<Utc>::MAX_UTC
+            })
+        );
+        assert!(Utc::now() - start < Duration::seconds(1));
+    }
 }
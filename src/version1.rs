@@ -4,47 +4,192 @@ use crossbeam_skiplist::SkipMap;
 use std::collections::VecDeque;
 use std::net::IpAddr;
 
-#[derive(Debug, Default)]
-pub struct RateLimiter1 {
-    requests: SkipMap<IpAddr, VecDeque<DateTime<Utc>>>,
+#[derive(Debug)]
+pub struct RateLimiter1<K: Ord = IpAddr, L = GlobalLimits> {
+    requests: SkipMap<K, Log>,
+    max_requests: usize,
+    window: Duration,
+    boundary: Boundary,
+    limits: L,
+}
+
+// The limits are those the key was created with, see LimitProvider
+#[derive(Debug, Clone)]
+struct Log {
+    max_requests: usize,
+    window: Duration,
+    requests: VecDeque<DateTime<Utc>>,
 }
 
 impl RateLimiter1 {
+    // The queue is cloned, modified and reinserted, so concurrent callers overwrite
+    // each other's requests
+    pub const STRICTNESS: Strictness = Strictness::Unbounded;
+}
+
+impl<K: Ord + Clone + Send + 'static> Default for RateLimiter1<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone + Send + 'static> RateLimiter1<K> {
     pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to `max_requests` per key within any `window`, instead of the crate
+    // wide MAX_REQUESTS per MAX_REQUESTS_DURATION_SECONDS
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
         RateLimiter1 {
             requests: SkipMap::new(),
+            max_requests,
+            window,
+            boundary: Boundary::default(),
+            limits: GlobalLimits,
         }
     }
+}
 
-    pub fn ratelimit1(&self, src_ip: IpAddr, timestamp: DateTime<Utc>) -> bool {
-        let mut current_requests = self
-            .requests
-            .get(&src_ip)
-            .map(|r| r.value().clone())
-            .unwrap_or_default();
-
-        let cutoff_time = timestamp - Duration::seconds(MAX_REQUESTS_DURATION_SECONDS);
-        while let Some(front_time) = current_requests.front() {
-            if *front_time < cutoff_time {
-                current_requests.pop_front();
+impl<K: Ord + Clone + Send + 'static, L: LimitProvider<K>> RateLimiter1<K, L> {
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter1<K, P> {
+        RateLimiter1 {
+            requests: self.requests,
+            max_requests: self.max_requests,
+            window: self.window,
+            boundary: self.boundary,
+            limits,
+        }
+    }
+
+    pub fn ratelimit1(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost1(src_ip, timestamp, 1)
+    }
+
+    // Records the request as `cost` requests at the same timestamp, if all of them fit
+    pub fn ratelimit_with_cost1(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let mut log = match self.requests.get(&src_ip) {
+            Some(entry) => entry.value().clone(),
+            None => {
+                let (max_requests, window) = self
+                    .limits
+                    .limits(&src_ip)
+                    .unwrap_or((self.max_requests, self.window));
+                Log {
+                    max_requests,
+                    window,
+                    requests: VecDeque::new(),
+                }
+            }
+        };
+
+        let cutoff_time = timestamp - log.window;
+        while let Some(front_time) = log.requests.front() {
+            if !self.boundary.contains(cutoff_time, *front_time) {
+                log.requests.pop_front();
             } else {
                 break;
             }
         }
 
-        if current_requests.len() >= MAX_REQUESTS {
-            self.requests.insert(src_ip, current_requests);
+        if log.requests.len() + cost as usize > log.max_requests {
+            self.requests.insert(src_ip.clone(), log);
             return false;
         }
 
-        current_requests.push_back(timestamp);
-        self.requests.insert(src_ip, current_requests);
+        log.requests
+            .extend(std::iter::repeat_n(timestamp, cost as usize));
+        self.requests.insert(src_ip, log);
         true
     }
 
     #[cfg(test)]
-    pub fn requests(&self) -> &SkipMap<IpAddr, VecDeque<DateTime<Utc>>> {
-        &self.requests
+    fn logged(&self, key: &K) -> usize {
+        self.requests
+            .get(key)
+            .map_or(0, |entry| entry.value().requests.len())
+    }
+}
+
+impl<K: Ord + Clone + Send + 'static, L: LimitProvider<K>> RateLimit<K> for RateLimiter1<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit1(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost1(key, timestamp, cost)
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let Some(entry) = self.requests.get(&key) else {
+            return max_requests_of(&self.limits, &key, self.max_requests) > 0;
+        };
+        let log = entry.value();
+        let cutoff_time = timestamp - log.window;
+        let in_window = log
+            .requests
+            .iter()
+            .filter(|&&time| self.boundary.contains(cutoff_time, time))
+            .count();
+        in_window < log.max_requests
+    }
+}
+
+impl<K: Ord, L> TrackedKeys for RateLimiter1<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.requests.len()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Clone, L> Snapshot<K> for RateLimiter1<K, L> {
+    fn snapshot(&self) -> RateLimiterSnapshot<K> {
+        RateLimiterSnapshot {
+            max_requests: self.max_requests,
+            window: self.window,
+            keys: self
+                .requests
+                .iter()
+                .map(|entry| {
+                    let log = entry.value();
+                    KeySnapshot {
+                        key: entry.key().clone(),
+                        max_requests: log.max_requests,
+                        window: log.window,
+                        requests: log.requests.iter().copied().collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Clone + Send + 'static> RateLimiter1<K> {
+    // Restores the log of every key along with the limits it was created with
+    pub fn from_snapshot(snapshot: RateLimiterSnapshot<K>) -> Self {
+        let rate_limiter = Self::with_config(snapshot.max_requests, snapshot.window);
+        for key in snapshot.keys {
+            rate_limiter.requests.insert(
+                key.key,
+                Log {
+                    max_requests: key.max_requests,
+                    window: key.window,
+                    requests: key.requests.into(),
+                },
+            );
+        }
+        rate_limiter
     }
 }
 
@@ -103,6 +248,25 @@ mod tests {
         assert_eq!(rate_limiter.ratelimit1(ip, later), true);
     }
 
+    #[test]
+    fn test_ratelimit1_with_config() {
+        let rate_limiter = RateLimiter1::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit1(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit1(ip, now), false);
+        assert_eq!(
+            rate_limiter.ratelimit1(ip, now + Duration::seconds(1)),
+            false
+        );
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit1(ip, later), true);
+    }
+
     #[test]
     fn test_ratelimit1_concurrent_ratelimit() {
         const NUM_THREADS: usize = 10;
@@ -123,11 +287,7 @@ mod tests {
                 thread.join().expect("Thread failed");
             });
 
-        let total_requests = rate_limiter
-            .requests()
-            .get(&ip)
-            .map(|r| r.value().len())
-            .unwrap_or(0);
+        let total_requests = rate_limiter.logged(&ip);
         assert!(
             total_requests <= MAX_REQUESTS * NUM_THREADS,
             "Number of requests exceeded expected limit"
@@ -167,4 +327,22 @@ mod tests {
             total_denials
         );
     }
+
+    #[test]
+    fn test_ratelimit1_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter1::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit1(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
 }
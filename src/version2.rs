@@ -5,44 +5,186 @@ use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::sync::RwLock;
 
-#[derive(Debug, Default)]
-pub struct RateLimiter2 {
-    requests: SkipMap<IpAddr, RwLock<VecDeque<DateTime<Utc>>>>,
+#[derive(Debug)]
+pub struct RateLimiter2<K: Ord = IpAddr, L = GlobalLimits> {
+    requests: SkipMap<K, Log>,
+    max_requests: usize,
+    window: Duration,
+    boundary: Boundary,
+    limits: L,
+}
+
+// The limits are those the key was created with, see LimitProvider
+#[derive(Debug)]
+struct Log {
+    max_requests: usize,
+    window: Duration,
+    requests: RwLock<VecDeque<DateTime<Utc>>>,
 }
 
 impl RateLimiter2 {
+    // Each key's queue is behind its own lock, so the check and the push are atomic
+    pub const STRICTNESS: Strictness = Strictness::Strict;
+}
+
+impl<K: Ord + Send + 'static> Default for RateLimiter2<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static> RateLimiter2<K> {
     pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to `max_requests` per key within any `window`, instead of the crate
+    // wide MAX_REQUESTS per MAX_REQUESTS_DURATION_SECONDS
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
         RateLimiter2 {
             requests: SkipMap::new(),
+            max_requests,
+            window,
+            boundary: Boundary::default(),
+            limits: GlobalLimits,
         }
     }
+}
 
-    pub fn ratelimit2(&self, src_ip: IpAddr, timestamp: DateTime<Utc>) -> bool {
-        let cutoff_time = timestamp - Duration::seconds(MAX_REQUESTS_DURATION_SECONDS);
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimiter2<K, L> {
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
 
-        let request_queue = self
-            .requests
-            .get_or_insert_with(src_ip, || RwLock::new(VecDeque::new()));
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter2<K, P> {
+        RateLimiter2 {
+            requests: self.requests,
+            max_requests: self.max_requests,
+            window: self.window,
+            boundary: self.boundary,
+            limits,
+        }
+    }
 
-        let mut locked_queue = request_queue.value().write().unwrap();
+    pub fn ratelimit2(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost2(src_ip, timestamp, 1)
+    }
+
+    // Records the request as `cost` requests at the same timestamp, if all of them fit
+    pub fn ratelimit_with_cost2(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let entry = get_or_insert_with_limits(
+            &self.requests,
+            src_ip,
+            &self.limits,
+            (self.max_requests, self.window),
+            |max_requests, window| Log {
+                max_requests,
+                window,
+                requests: RwLock::new(VecDeque::new()),
+            },
+        );
+        let log = entry.value();
+        let cutoff_time = timestamp - log.window;
+
+        let mut locked_queue = log.requests.write().unwrap();
 
         while let Some(front_time) = locked_queue.front() {
-            if *front_time < cutoff_time {
+            if !self.boundary.contains(cutoff_time, *front_time) {
                 locked_queue.pop_front();
             } else {
                 break;
             }
         }
 
-        if locked_queue.len() >= MAX_REQUESTS {
+        if locked_queue.len() + cost as usize > log.max_requests {
             return false;
         }
 
-        locked_queue.push_back(timestamp);
+        locked_queue.extend(std::iter::repeat_n(timestamp, cost as usize));
         true
     }
 }
 
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimit<K> for RateLimiter2<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit2(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost2(key, timestamp, cost)
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let Some(entry) = self.requests.get(&key) else {
+            return max_requests_of(&self.limits, &key, self.max_requests) > 0;
+        };
+        let log = entry.value();
+        let cutoff_time = timestamp - log.window;
+        let in_window = log
+            .requests
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|&&time| self.boundary.contains(cutoff_time, time))
+            .count();
+        in_window < log.max_requests
+    }
+}
+
+impl<K: Ord, L> TrackedKeys for RateLimiter2<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.requests.len()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Clone, L> Snapshot<K> for RateLimiter2<K, L> {
+    fn snapshot(&self) -> RateLimiterSnapshot<K> {
+        RateLimiterSnapshot {
+            max_requests: self.max_requests,
+            window: self.window,
+            keys: self
+                .requests
+                .iter()
+                .map(|entry| {
+                    let log = entry.value();
+                    KeySnapshot {
+                        key: entry.key().clone(),
+                        max_requests: log.max_requests,
+                        window: log.window,
+                        requests: log.requests.read().unwrap().iter().copied().collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Send + 'static> RateLimiter2<K> {
+    // Restores the log of every key along with the limits it was created with
+    pub fn from_snapshot(snapshot: RateLimiterSnapshot<K>) -> Self {
+        let rate_limiter = Self::with_config(snapshot.max_requests, snapshot.window);
+        for key in snapshot.keys {
+            rate_limiter.requests.insert(
+                key.key,
+                Log {
+                    max_requests: key.max_requests,
+                    window: key.window,
+                    requests: RwLock::new(key.requests.into()),
+                },
+            );
+        }
+        rate_limiter
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,6 +240,25 @@ mod tests {
         assert_eq!(rate_limiter.ratelimit2(ip, later), true);
     }
 
+    #[test]
+    fn test_ratelimit2_with_config() {
+        let rate_limiter = RateLimiter2::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit2(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit2(ip, now), false);
+        assert_eq!(
+            rate_limiter.ratelimit2(ip, now + Duration::seconds(1)),
+            false
+        );
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit2(ip, later), true);
+    }
+
     #[test]
     fn test_concurrent_ratelimit2() {
         const NUM_THREADS: usize = 10;
@@ -122,7 +283,7 @@ mod tests {
         let total_requests = {
             let rl = rate_limiter.read().unwrap();
             let x = match rl.requests.get(&ip) {
-                Some(queue) => queue.value().read().unwrap().len(),
+                Some(queue) => queue.value().requests.read().unwrap().len(),
                 None => 0,
             };
             x
@@ -167,4 +328,22 @@ mod tests {
             total_denials
         );
     }
+
+    #[test]
+    fn test_ratelimit2_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter2::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit2(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
 }
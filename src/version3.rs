@@ -1,3 +1,7 @@
+use crate::limits::{get_or_insert_with_limits, max_requests_of};
+use crate::{Boundary, GlobalLimits, LimitProvider, RateLimit, Strictness, TrackedKeys};
+#[cfg(feature = "serde")]
+use crate::{KeySnapshot, RateLimiterSnapshot, Snapshot};
 use chrono::{DateTime, Duration, Utc};
 use crossbeam_queue::ArrayQueue;
 use crossbeam_skiplist::SkipMap;
@@ -6,44 +10,119 @@ use std::net::IpAddr;
 const MAX_REQUESTS: usize = 100;
 const MAX_REQUESTS_DURATION_SECONDS: i64 = 60;
 
-#[derive(Debug, Default)]
-pub struct RateLimiter3 {
-    requests: SkipMap<IpAddr, ArrayQueue<DateTime<Utc>>>,
+#[derive(Debug)]
+pub struct RateLimiter3<K: Ord = IpAddr, L = GlobalLimits> {
+    requests: SkipMap<K, Queue>,
+    max_requests: usize,
+    window: Duration,
+    boundary: Boundary,
+    limits: L,
+}
+
+// The limits are those the key was created with, see LimitProvider. A key given a
+// max_requests of 0 still gets a queue of one, which stays empty.
+#[derive(Debug)]
+struct Queue {
+    max_requests: usize,
+    window: Duration,
+    requests: ArrayQueue<DateTime<Utc>>,
 }
 
 impl RateLimiter3 {
+    // Checking for space, pushing and pruning are separate operations on the queue, so
+    // concurrent callers can evict each other's still valid requests
+    pub const STRICTNESS: Strictness = Strictness::Unbounded;
+}
+
+impl<K: Ord + Send + 'static> Default for RateLimiter3<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static> RateLimiter3<K> {
     pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to `max_requests` per key within any `window`, instead of the crate
+    // wide MAX_REQUESTS per MAX_REQUESTS_DURATION_SECONDS. The requests are kept in a
+    // bounded queue, which can't have a capacity of zero, so neither can max_requests.
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        assert!(
+            max_requests > 0,
+            "RateLimiter3 needs a max_requests of at least 1"
+        );
         RateLimiter3 {
             requests: SkipMap::new(),
+            max_requests,
+            window,
+            boundary: Boundary::default(),
+            limits: GlobalLimits,
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimiter3<K, L> {
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter3<K, P> {
+        RateLimiter3 {
+            requests: self.requests,
+            max_requests: self.max_requests,
+            window: self.window,
+            boundary: self.boundary,
+            limits,
         }
     }
 
-    pub fn ratelimit3(&self, src_ip: IpAddr, timestamp: DateTime<Utc>) -> bool {
-        let cutoff_time = timestamp - Duration::seconds(MAX_REQUESTS_DURATION_SECONDS);
+    pub fn ratelimit3(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost3(src_ip, timestamp, 1)
+    }
 
-        let entry = self
-            .requests
-            .get_or_insert_with(src_ip, || ArrayQueue::new(MAX_REQUESTS));
-        let request_queue = entry.value();
+    // Records the request as `cost` requests at the same timestamp, if all of them fit.
+    // The queue can't hold more than the limit, so a cost over it is denied up front.
+    pub fn ratelimit_with_cost3(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let entry = get_or_insert_with_limits(
+            &self.requests,
+            src_ip,
+            &self.limits,
+            (self.max_requests, self.window),
+            |max_requests, window| Queue {
+                max_requests,
+                window,
+                requests: ArrayQueue::new(max_requests.max(1)),
+            },
+        );
+        let Queue {
+            max_requests,
+            window,
+            requests: request_queue,
+        } = entry.value();
 
-        // Return early if the queue isn't full yet
-        if !request_queue.is_full() {
-            request_queue.push(timestamp).unwrap();
-            return true;
+        let cost = cost as usize;
+        if cost > *max_requests {
+            return false;
         }
+        let cutoff_time = timestamp - *window;
 
-        let mut removed = 0;
-        let mut valid_count = 0;
-        while let Some(front_time) = request_queue.pop() {
-            removed += 1;
-            if front_time >= cutoff_time {
-                request_queue.force_push(front_time);
-                valid_count += 1;
-            }
+        // Return early if the request fits without pruning
+        if request_queue.len() + cost <= *max_requests {
+            push_cost(request_queue, timestamp, cost);
+            return true;
         }
 
-        if removed > valid_count {
-            request_queue.force_push(timestamp);
+        prune(request_queue, cutoff_time, self.boundary);
+        if request_queue.len() + cost <= *max_requests {
+            push_cost(request_queue, timestamp, cost);
             true
         } else {
             false
@@ -51,6 +130,129 @@ impl RateLimiter3 {
     }
 }
 
+// Drops the requests made before `cutoff_time`. A queue can't be read in place, so
+// the still valid ones are popped and pushed back onto it.
+fn prune(queue: &ArrayQueue<DateTime<Utc>>, cutoff_time: DateTime<Utc>, boundary: Boundary) {
+    // Only cycle through the entries that were present when we started, otherwise
+    // re-pushing the still valid timestamps would keep the loop going forever
+    for _ in 0..queue.len() {
+        let Some(front_time) = queue.pop() else {
+            break;
+        };
+        if boundary.contains(cutoff_time, front_time) {
+            queue.force_push(front_time);
+        }
+    }
+}
+
+// A racing caller may fill the queue between checking for room and pushing, in which
+// case the oldest requests are evicted rather than failing halfway through the cost
+fn push_cost(queue: &ArrayQueue<DateTime<Utc>>, timestamp: DateTime<Utc>, cost: usize) {
+    for _ in 0..cost {
+        queue.force_push(timestamp);
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimit<K> for RateLimiter3<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit3(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost3(key, timestamp, cost)
+    }
+
+    // A full queue has to be pruned to tell whether a request fits, like a check
+    // prunes it, which records nothing but races checks the same way
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let Some(entry) = self.requests.get(&key) else {
+            return max_requests_of(&self.limits, &key, self.max_requests) > 0;
+        };
+        let queue = entry.value();
+        if queue.requests.len() < queue.max_requests {
+            return true;
+        }
+        prune(&queue.requests, timestamp - queue.window, self.boundary);
+        queue.requests.len() < queue.max_requests
+    }
+}
+
+impl<K: Ord, L> TrackedKeys for RateLimiter3<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.requests.len()
+    }
+}
+
+// An ArrayQueue can't be read in place, so the snapshot pops every request off each
+// queue and pushes it back, which changes the live queues. A check racing it may find
+// requests missing and admit more than the limit, and its own push can land among
+// the cycled requests, so they are no longer oldest first, or fill the queue, so
+// pushing one back evicts another. Unlike the other versions, the checks have to be
+// stopped before taking a snapshot, such as on shutdown.
+#[cfg(feature = "serde")]
+impl<K: Ord + Clone, L> Snapshot<K> for RateLimiter3<K, L> {
+    fn snapshot(&self) -> RateLimiterSnapshot<K> {
+        RateLimiterSnapshot {
+            max_requests: self.max_requests,
+            window: self.window,
+            keys: self
+                .requests
+                .iter()
+                .map(|entry| {
+                    let queue = entry.value();
+                    let mut requests = Vec::with_capacity(queue.requests.len());
+                    for _ in 0..queue.requests.len() {
+                        let Some(time) = queue.requests.pop() else {
+                            break;
+                        };
+                        requests.push(time);
+                        queue.requests.force_push(time);
+                    }
+                    KeySnapshot {
+                        key: entry.key().clone(),
+                        max_requests: queue.max_requests,
+                        window: queue.window,
+                        requests,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Send + 'static> RateLimiter3<K> {
+    // Restores the queue of every key along with the limits it was created with. A
+    // queue only holds its key's limit, so only the newest requests of a log longer
+    // than that are kept. Snapshots of the other versions may have a max_requests of
+    // 0, which with_config rejects, so the limiter is built directly, and its keys get
+    // a queue of one that stays empty like keys given a limit of 0 by a LimitProvider.
+    pub fn from_snapshot(snapshot: RateLimiterSnapshot<K>) -> Self {
+        let rate_limiter = RateLimiter3 {
+            requests: SkipMap::new(),
+            max_requests: snapshot.max_requests,
+            window: snapshot.window,
+            boundary: Boundary::default(),
+            limits: GlobalLimits,
+        };
+        for key in snapshot.keys {
+            let requests = ArrayQueue::new(key.max_requests.max(1));
+            for time in key.requests {
+                requests.force_push(time);
+            }
+            rate_limiter.requests.insert(
+                key.key,
+                Queue {
+                    max_requests: key.max_requests,
+                    window: key.window,
+                    requests,
+                },
+            );
+        }
+        rate_limiter
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -91,6 +293,32 @@ mod tests {
         assert_eq!(rate_limiter.ratelimit3(ip, now), false);
     }
 
+    // Pruning a queue full of valid requests pushes each back as it goes, which once
+    // kept the prune going forever. Checked on another thread, so a regression fails
+    // instead of hanging the tests.
+    #[test]
+    fn test_ratelimit3_prunes_a_full_queue_of_valid_requests() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let rate_limiter = RateLimiter3::with_config(3, Duration::seconds(60));
+            let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+            let now = Utc::now();
+
+            let admitted = |at| (0..5).filter(|_| rate_limiter.ratelimit3(ip, at)).count();
+            let decisions = [
+                admitted(now),
+                admitted(now + Duration::seconds(30)),
+                admitted(now + Duration::seconds(61)),
+            ];
+            sender.send(decisions).unwrap();
+        });
+
+        let decisions = receiver
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .expect("Pruning a full queue didn't finish");
+        assert_eq!(decisions, [3, 0, 3]);
+    }
+
     #[test]
     fn test_ratelimit3_after_enough_time_allowed() {
         let rate_limiter = RateLimiter3::new();
@@ -105,6 +333,25 @@ mod tests {
         assert_eq!(rate_limiter.ratelimit3(ip, later), true);
     }
 
+    #[test]
+    fn test_ratelimit3_with_config() {
+        let rate_limiter = RateLimiter3::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit3(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit3(ip, now), false);
+        assert_eq!(
+            rate_limiter.ratelimit3(ip, now + Duration::seconds(1)),
+            false
+        );
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit3(ip, later), true);
+    }
+
     #[test]
     fn test_concurrent_ratelimit3() {
         const NUM_THREADS: usize = 10;
@@ -127,7 +374,7 @@ mod tests {
 
         let total_requests = {
             let x = match rate_limiter.requests.get(&ip) {
-                Some(queue) => queue.value().len(),
+                Some(queue) => queue.value().requests.len(),
                 None => 0,
             };
             x
@@ -171,4 +418,22 @@ mod tests {
             total_denials
         );
     }
+
+    #[test]
+    fn test_ratelimit3_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter3::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit3(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
 }
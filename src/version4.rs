@@ -0,0 +1,743 @@
+use super::*;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, RwLock};
+
+// Checks that can be queued per shard before callers have to wait for the shard to
+// catch up
+const SHARD_QUEUE_LEN: usize = 1024;
+
+// The keys are split over shards, whose state is owned by a dedicated tokio task
+// each. Checks are messages to the task owning the key, answered over a oneshot
+// channel, so no key's log is ever shared or locked. Both picking the shard and the
+// shards' maps hash keys with `S`, see RateLimiter0. The shards can be changed while
+// running with reshard4, which moves every key's log over to its new shard.
+//
+// Getting the message to the shard does share state though. The senders are behind a
+// tokio RwLock for reshard4 to swap them, so every check takes a read lock, which is
+// an atomic on a semaphore all callers share, and bumps its shard's load counter.
+// Both bounce a cache line between the cores checking at once, and while a reshard
+// waits for or holds the write lock, new checks queue behind it until every key is
+// moved. Eliminating all locks was the aim of the actor design, and it gave way here
+// to resharding at runtime.
+#[derive(Debug)]
+pub struct RateLimiter4<K = IpAddr, S = RandomState, L = GlobalLimits> {
+    // Checks only read, so they only wait for a reshard
+    shards: RwLock<Shards<K, S>>,
+    boundary: Boundary,
+    max_requests: usize,
+    window: Duration,
+    limits: Arc<L>,
+}
+
+#[derive(Debug)]
+struct Shards<K, S> {
+    senders: Box<[mpsc::Sender<Message<K, S>>]>,
+    hash_builder: S,
+    // The checks sent to every shard since they were spawned
+    loads: Box<[AtomicU64]>,
+}
+
+// The limits are those the key was created with, see LimitProvider
+#[derive(Debug)]
+struct Log {
+    max_requests: usize,
+    window: Duration,
+    requests: VecDeque<DateTime<Utc>>,
+}
+
+enum Message<K, S> {
+    Check(Check<K>),
+    // Hands the shard's logs over and stops it
+    Drain(oneshot::Sender<HashMap<K, Log, S>>),
+    // Runs on the shard's logs between two checks, such as to copy them out for
+    // snapshot4
+    #[cfg(feature = "serde")]
+    Inspect(Inspect<K, S>),
+}
+
+#[cfg(feature = "serde")]
+type Inspect<K, S> = Box<dyn FnOnce(&HashMap<K, Log, S>) + Send>;
+
+#[derive(Debug)]
+struct Check<K> {
+    key: K,
+    timestamp: DateTime<Utc>,
+    cost: u32,
+    boundary: Boundary,
+    reply: oneshot::Sender<bool>,
+}
+
+impl RateLimiter4 {
+    // Every key is owned by a single task that handles its checks one at a time
+    pub const STRICTNESS: Strictness = Strictness::Strict;
+}
+
+impl<K, S> Default for RateLimiter4<K, S>
+where
+    K: Hash + Eq + Send + 'static,
+    S: BuildHasher + Clone + Default + Send + 'static,
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K: Hash + Eq + Send + 'static> RateLimiter4<K> {
+    // Spawns a shard per available core. Like tokio::spawn, this panics when called
+    // outside of a tokio runtime.
+    pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to `max_requests` per key within any `window`, instead of the crate
+    // wide MAX_REQUESTS per MAX_REQUESTS_DURATION_SECONDS
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        Self::with_config_and_hasher(max_requests, window, RandomState::new())
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Self::spawn(
+            shards,
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+            RandomState::new(),
+            GlobalLimits,
+        )
+    }
+}
+
+impl<K, S> RateLimiter4<K, S>
+where
+    K: Hash + Eq + Send + 'static,
+    S: BuildHasher + Clone + Send + 'static,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_config_and_hasher(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+            hash_builder,
+        )
+    }
+
+    pub fn with_config_and_hasher(max_requests: usize, window: Duration, hash_builder: S) -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::spawn(
+            parallelism,
+            max_requests,
+            window,
+            hash_builder,
+            GlobalLimits,
+        )
+    }
+}
+
+impl<K, S, L> RateLimiter4<K, S, L>
+where
+    K: Hash + Eq + Send + 'static,
+    S: BuildHasher + Clone + Send + 'static,
+    L: LimitProvider<K> + Send + Sync + 'static,
+{
+    fn spawn(
+        shards: usize,
+        max_requests: usize,
+        window: Duration,
+        hash_builder: S,
+        limits: L,
+    ) -> Self {
+        let limits = Arc::new(limits);
+        let maps = (0..shards.max(1))
+            .map(|_| HashMap::with_hasher(hash_builder.clone()))
+            .collect();
+        RateLimiter4 {
+            shards: RwLock::new(Shards::spawn(
+                maps,
+                hash_builder,
+                &limits,
+                (max_requests, window),
+            )),
+            boundary: Boundary::default(),
+            max_requests,
+            window,
+            limits,
+        }
+    }
+
+    // The shards are already running, so the boundary travels along with every check
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for. The shards own the limits, so
+    // they are respawned with it, and the keys seen so far are forgotten.
+    pub fn with_limits<P>(self, limits: P) -> RateLimiter4<K, S, P>
+    where
+        P: LimitProvider<K> + Send + Sync + 'static,
+    {
+        let shards = self.shards.into_inner();
+        let rate_limiter = RateLimiter4::spawn(
+            shards.senders.len(),
+            self.max_requests,
+            self.window,
+            shards.hash_builder,
+            limits,
+        );
+        rate_limiter.with_boundary(self.boundary)
+    }
+
+    pub async fn ratelimit4(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost4(src_ip, timestamp, 1).await
+    }
+
+    // Records the request as `cost` requests at the same timestamp, if all of them fit
+    pub async fn ratelimit_with_cost4(
+        &self,
+        src_ip: K,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+    ) -> bool {
+        let (reply, admitted) = oneshot::channel();
+        {
+            let shards = self.shards.read().await;
+            let shard = shards.shard_of(&src_ip);
+            shards.loads[shard].fetch_add(1, Ordering::Relaxed);
+
+            // The shard tasks only stop once every sender is dropped, when drained by
+            // a reshard, which waits for the check to be sent, or when their runtime
+            // shuts down, in which case there is nobody left to admit requests
+            shards.senders[shard]
+                .send(Message::Check(Check {
+                    key: src_ip,
+                    timestamp,
+                    cost,
+                    boundary: self.boundary,
+                    reply,
+                }))
+                .await
+                .expect("Shard task stopped");
+        }
+        admitted.await.expect("Shard task stopped")
+    }
+
+    // The checks sent to every shard since they were last spawned
+    pub async fn shard_loads4(&self) -> Vec<u64> {
+        let shards = self.shards.read().await;
+        shards
+            .loads
+            .iter()
+            .map(|load| load.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    // The shards that took more than `factor` times the mean load, such as a shard
+    // owning a /16 that dominates the traffic under a weak hasher
+    pub async fn hot_shards4(&self, factor: f64) -> Vec<usize> {
+        let loads = self.shard_loads4().await;
+        let mean = loads.iter().sum::<u64>() as f64 / loads.len() as f64;
+        (0..loads.len())
+            .filter(|&shard| loads[shard] as f64 > factor * mean)
+            .collect()
+    }
+
+    // Spreads the keys over `shards` new shards, hashed with `hash_builder`, such as
+    // more shards to split the hot ones, or a RandomState with a new salt. Every key
+    // keeps its log, so no quota is reset. Checks wait for the move, and every check
+    // sent before it is answered by the old shards first.
+    pub async fn reshard4(&self, shards: usize, hash_builder: S) {
+        let mut current = self.shards.write().await;
+
+        let mut maps: Vec<HashMap<K, Log, S>> = (0..shards.max(1))
+            .map(|_| HashMap::with_hasher(hash_builder.clone()))
+            .collect();
+        for sender in current.senders.iter() {
+            let (reply, drained) = oneshot::channel();
+            sender
+                .send(Message::Drain(reply))
+                .await
+                .expect("Shard task stopped");
+            for (key, log) in drained.await.expect("Shard task stopped") {
+                let shard = hash_builder.hash_one(&key) as usize % maps.len();
+                maps[shard].insert(key, log);
+            }
+        }
+
+        *current = Shards::spawn(
+            maps,
+            hash_builder,
+            &self.limits,
+            (self.max_requests, self.window),
+        );
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, S, L> RateLimiter4<K, S, L>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    S: BuildHasher + Clone + Send + 'static,
+    L: LimitProvider<K> + Send + Sync + 'static,
+{
+    // The logs of every shard, each copied out between two of its checks, see
+    // Snapshot
+    pub async fn snapshot4(&self) -> RateLimiterSnapshot<K> {
+        let shards = self.shards.read().await;
+        let mut keys = Vec::new();
+        for sender in shards.senders.iter() {
+            let (reply, copied) = oneshot::channel();
+            let copy = move |requests: &HashMap<K, Log, S>| {
+                let _ = reply.send(
+                    requests
+                        .iter()
+                        .map(|(key, log)| KeySnapshot {
+                            key: key.clone(),
+                            max_requests: log.max_requests,
+                            window: log.window,
+                            requests: log.requests.iter().copied().collect(),
+                        })
+                        .collect::<Vec<_>>(),
+                );
+            };
+            sender
+                .send(Message::Inspect(Box::new(copy)))
+                .await
+                .expect("Shard task stopped");
+            keys.extend(copied.await.expect("Shard task stopped"));
+        }
+        RateLimiterSnapshot {
+            max_requests: self.max_requests,
+            window: self.window,
+            keys,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Hash + Eq + Send + 'static> RateLimiter4<K> {
+    // Restores the log of every key along with the limits it was created with, over a
+    // shard per available core. with_limits respawns the shards and forgets the keys,
+    // so the restored limiter keeps the limiter's own config for new keys.
+    pub fn from_snapshot(snapshot: RateLimiterSnapshot<K>) -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let hash_builder = RandomState::new();
+        let mut maps: Vec<HashMap<K, Log>> = (0..parallelism)
+            .map(|_| HashMap::with_hasher(hash_builder.clone()))
+            .collect();
+        for key in snapshot.keys {
+            let shard = hash_builder.hash_one(&key.key) as usize % maps.len();
+            maps[shard].insert(
+                key.key,
+                Log {
+                    max_requests: key.max_requests,
+                    window: key.window,
+                    requests: key.requests.into(),
+                },
+            );
+        }
+
+        let limits = Arc::new(GlobalLimits);
+        RateLimiter4 {
+            shards: RwLock::new(Shards::spawn(
+                maps,
+                hash_builder,
+                &limits,
+                (snapshot.max_requests, snapshot.window),
+            )),
+            boundary: Boundary::default(),
+            max_requests: snapshot.max_requests,
+            window: snapshot.window,
+            limits,
+        }
+    }
+}
+
+impl<K, S> Shards<K, S>
+where
+    K: Hash + Eq + Send + 'static,
+    S: BuildHasher + Send + 'static,
+{
+    fn spawn<L: LimitProvider<K> + Send + Sync + 'static>(
+        maps: Vec<HashMap<K, Log, S>>,
+        hash_builder: S,
+        limits: &Arc<L>,
+        default: (usize, Duration),
+    ) -> Self {
+        Shards {
+            loads: maps.iter().map(|_| AtomicU64::new(0)).collect(),
+            senders: maps
+                .into_iter()
+                .map(|requests| {
+                    let (sender, receiver) = mpsc::channel(SHARD_QUEUE_LEN);
+                    tokio::spawn(run_shard(receiver, requests, Arc::clone(limits), default));
+                    sender
+                })
+                .collect(),
+            hash_builder,
+        }
+    }
+
+    fn shard_of(&self, key: &K) -> usize {
+        self.hash_builder.hash_one(key) as usize % self.senders.len()
+    }
+}
+
+async fn run_shard<K: Hash + Eq, S: BuildHasher, L: LimitProvider<K>>(
+    mut messages: mpsc::Receiver<Message<K, S>>,
+    mut requests: HashMap<K, Log, S>,
+    limits: Arc<L>,
+    default: (usize, Duration),
+) {
+    while let Some(message) = messages.recv().await {
+        let check = match message {
+            Message::Check(check) => check,
+            Message::Drain(reply) => {
+                let _ = reply.send(requests);
+                return;
+            }
+            #[cfg(feature = "serde")]
+            Message::Inspect(inspect) => {
+                inspect(&requests);
+                continue;
+            }
+        };
+        let log = match requests.entry(check.key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let (max_requests, window) = limits.limits(entry.key()).unwrap_or(default);
+                entry.insert(Log {
+                    max_requests,
+                    window,
+                    requests: VecDeque::new(),
+                })
+            }
+        };
+        // Saturates, as a panic here would stop the shard for every key hashed onto it
+        let cutoff_time = check
+            .timestamp
+            .checked_sub_signed(log.window)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let current_requests = &mut log.requests;
+
+        while let Some(front_time) = current_requests.front() {
+            if !check.boundary.contains(cutoff_time, *front_time) {
+                current_requests.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let admitted = current_requests.len() + check.cost as usize <= log.max_requests;
+        if admitted {
+            current_requests.extend(std::iter::repeat_n(check.timestamp, check.cost as usize));
+        }
+
+        // The caller may have stopped waiting for the answer, which is fine
+        let _ = check.reply.send(admitted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_ratelimit4_under_max() {
+        let rate_limiter = RateLimiter4::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS - 1 {
+            assert_eq!(rate_limiter.ratelimit4(ip, now).await, true);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ratelimit4_over_denied() {
+        let rate_limiter = RateLimiter4::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit4(ip, now).await, true);
+        }
+        assert_eq!(rate_limiter.ratelimit4(ip, now).await, false);
+    }
+
+    #[tokio::test]
+    async fn test_ratelimit4_after_enough_time_allowed() {
+        let rate_limiter = RateLimiter4::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit4(ip, now).await, true);
+        }
+
+        let later = now + Duration::seconds(MAX_REQUESTS_DURATION_SECONDS + 1);
+        assert_eq!(rate_limiter.ratelimit4(ip, later).await, true);
+    }
+
+    #[tokio::test]
+    async fn test_ratelimit4_with_config() {
+        let rate_limiter = RateLimiter4::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit4(ip, now).await, true);
+        }
+        assert_eq!(rate_limiter.ratelimit4(ip, now).await, false);
+        assert_eq!(
+            rate_limiter
+                .ratelimit4(ip, now + Duration::seconds(1))
+                .await,
+            false
+        );
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit4(ip, later).await, true);
+    }
+
+    // Out-of-range timestamps don't stop the shard of the key
+    #[tokio::test]
+    async fn test_ratelimit4_out_of_range_timestamps() {
+        let rate_limiter = RateLimiter4::with_config(1, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        assert_eq!(
+            rate_limiter.ratelimit4(ip, DateTime::<Utc>::MIN_UTC).await,
+            true
+        );
+        assert_eq!(
+            rate_limiter.ratelimit4(ip, DateTime::<Utc>::MIN_UTC).await,
+            false
+        );
+        assert_eq!(rate_limiter.ratelimit4(ip, Utc::now()).await, true);
+        assert_eq!(
+            rate_limiter.ratelimit4(ip, DateTime::<Utc>::MAX_UTC).await,
+            true
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ratelimit4_with_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let rate_limiter =
+            RateLimiter4::<IpAddr, _>::with_hasher(BuildHasherDefault::<DefaultHasher>::default());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit4(ip, now).await, true);
+        }
+        assert_eq!(rate_limiter.ratelimit4(ip, now).await, false);
+    }
+
+    #[tokio::test]
+    async fn test_ratelimit4_keys_are_independent() {
+        let rate_limiter = RateLimiter4::with_shards(4);
+        let now = Utc::now();
+        let ips: Vec<IpAddr> = (0..16)
+            .map(|i| format!("10.0.0.{i}").parse().unwrap())
+            .collect();
+
+        for &ip in &ips {
+            for _ in 0..MAX_REQUESTS {
+                assert_eq!(rate_limiter.ratelimit4(ip, now).await, true);
+            }
+        }
+        for &ip in &ips {
+            assert_eq!(rate_limiter.ratelimit4(ip, now).await, false);
+        }
+    }
+
+    // Hashes keys by their value shifted right, so a shift of 16 puts keys sharing
+    // their upper bits on one shard, like a hasher that only sees an IP's /16
+    #[derive(Debug, Clone, Copy)]
+    struct ByPrefix(u32);
+
+    impl BuildHasher for ByPrefix {
+        type Hasher = PrefixHasher;
+
+        fn build_hasher(&self) -> PrefixHasher {
+            PrefixHasher(self.0, 0)
+        }
+    }
+
+    struct PrefixHasher(u32, u64);
+
+    impl std::hash::Hasher for PrefixHasher {
+        fn finish(&self) -> u64 {
+            self.1
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {
+            unreachable!("Only u64 keys are hashed");
+        }
+
+        fn write_u64(&mut self, n: u64) {
+            self.1 = n >> self.0;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reshard4_spreads_a_hot_shard_and_keeps_every_log() {
+        let rate_limiter =
+            RateLimiter4::with_config_and_hasher(2, Duration::seconds(60), ByPrefix(0));
+        rate_limiter.reshard4(4, ByPrefix(16)).await;
+        let now = Utc::now();
+
+        for key in 0..8u64 {
+            assert_eq!(rate_limiter.ratelimit4(key, now).await, true);
+            assert_eq!(rate_limiter.ratelimit4(key, now).await, true);
+        }
+        assert_eq!(rate_limiter.shard_loads4().await, [16, 0, 0, 0]);
+        assert_eq!(rate_limiter.hot_shards4(2.0).await, [0]);
+
+        rate_limiter.reshard4(4, ByPrefix(0)).await;
+        // The keys moved shards with their logs, so they are still out of quota
+        for key in 0..8u64 {
+            assert_eq!(rate_limiter.ratelimit4(key, now).await, false);
+        }
+        assert_eq!(rate_limiter.shard_loads4().await, [2, 2, 2, 2]);
+        assert_eq!(rate_limiter.hot_shards4(2.0).await, Vec::<usize>::new());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_reshard4_while_checking_admits_the_limit() {
+        const NUM_TASKS: usize = 10;
+        let rate_limiter = Arc::new(RateLimiter4::with_shards(2));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let tasks: Vec<_> = (0..NUM_TASKS)
+            .map(|_| {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                tokio::spawn(async move {
+                    let mut admitted = 0;
+                    for _ in 0..MAX_REQUESTS {
+                        admitted += rate_limiter.ratelimit4(ip, now).await as usize;
+                    }
+                    admitted
+                })
+            })
+            .collect();
+        for shards in [3, 8, 1] {
+            rate_limiter.reshard4(shards, RandomState::new()).await;
+        }
+
+        let admitted: usize = futures::future::try_join_all(tasks)
+            .await
+            .expect("Task failed")
+            .into_iter()
+            .sum();
+        assert_eq!(admitted, MAX_REQUESTS);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_ratelimit4_concurrent_tasks_respect_max_requests_limit() {
+        const NUM_TASKS: usize = 10;
+        let rate_limiter = Arc::new(RateLimiter4::new());
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let tasks: Vec<_> = (0..NUM_TASKS)
+            .map(|_| {
+                let rate_limiter = Arc::clone(&rate_limiter);
+                tokio::spawn(async move {
+                    let mut admitted = 0;
+                    for _ in 0..MAX_REQUESTS + 1 {
+                        if rate_limiter.ratelimit4(ip, now).await {
+                            admitted += 1;
+                        }
+                    }
+                    admitted
+                })
+            })
+            .collect();
+
+        let admitted: usize = futures::future::try_join_all(tasks)
+            .await
+            .expect("Task failed")
+            .into_iter()
+            .sum();
+        assert_eq!(admitted, MAX_REQUESTS);
+    }
+
+    #[test]
+    fn test_ratelimit4_honours_strictness() {
+        const NUM_THREADS: usize = 10;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let rate_limiter = runtime.block_on(async { RateLimiter4::new() });
+
+        let admitted = crate::strictness::admitted_under_contention(
+            |ip, ts| runtime.block_on(rate_limiter.ratelimit4(ip, ts)),
+            NUM_THREADS,
+            MAX_REQUESTS,
+        );
+
+        assert!(
+            RateLimiter4::STRICTNESS.permits(admitted, MAX_REQUESTS),
+            "Admitted {} requests with a limit of {}",
+            admitted,
+            MAX_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ratelimit4_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter4::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let mut admitted = [0, 0];
+        for _ in 0..10 {
+            admitted[0] += rate_limiter.ratelimit4(free, now).await as usize;
+            admitted[1] += rate_limiter.ratelimit4(premium, now).await as usize;
+        }
+        assert_eq!(admitted, [2, 5]);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(rate_limiter.ratelimit4(free, later).await, false);
+        assert_eq!(rate_limiter.ratelimit4(premium, later).await, true);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_snapshot4_restores_every_log_and_limit() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter4::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(60))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+        rate_limiter.ratelimit4(free, now).await;
+        rate_limiter.ratelimit4(premium, now).await;
+
+        let snapshot = rate_limiter.snapshot4().await;
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored = RateLimiter4::from_snapshot(serde_json::from_str(&json).unwrap());
+
+        let mut admitted = [0, 0];
+        for _ in 0..10 {
+            admitted[0] += restored.ratelimit4(free, now).await as usize;
+            admitted[1] += restored.ratelimit4(premium, now).await as usize;
+        }
+        assert_eq!(admitted, [1, 4]);
+    }
+}
@@ -0,0 +1,322 @@
+use super::*;
+use chrono::{DateTime, Duration, Utc};
+use crossbeam_skiplist::SkipMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// A token bucket per key instead of a log of requests. Every key starts with a full
+// bucket of `burst` tokens, each admitted request takes one, and one is added back
+// every `refill_interval` until the bucket is full again.
+#[derive(Debug)]
+pub struct RateLimiter5<K: Ord = IpAddr, L = GlobalLimits> {
+    buckets: SkipMap<K, Mutex<Bucket>>,
+    burst: usize,
+    refill_interval: Duration,
+    limits: L,
+}
+
+// The size and rate are those the key was created with, see LimitProvider
+#[derive(Debug)]
+struct Bucket {
+    tokens: usize,
+    refilled_at: DateTime<Utc>,
+    burst: usize,
+    refill_interval: Duration,
+}
+
+impl RateLimiter5 {
+    // Each bucket is behind its own lock, but a full bucket can be drained at the
+    // start of a window and refilled by its end, admitting up to twice the limit
+    pub const STRICTNESS: Strictness = Strictness::Relaxed { epsilon: 1.0 };
+}
+
+impl<K: Ord + Send + 'static> Default for RateLimiter5<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static> RateLimiter5<K> {
+    pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Allows bursts of `max_requests` and refills at `max_requests` per `window`, so
+    // the sustained rate matches the sliding log versions with the same config
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        Self::with_bucket(max_requests, refill_interval(max_requests, window))
+    }
+
+    pub fn with_bucket(burst: usize, refill_interval: Duration) -> Self {
+        assert!(
+            refill_interval > Duration::zero(),
+            "RateLimiter5 needs a refill_interval above zero"
+        );
+        RateLimiter5 {
+            buckets: SkipMap::new(),
+            burst,
+            refill_interval,
+            limits: GlobalLimits,
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimiter5<K, L> {
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own bucket for keys it has no limit for. Limits are turned into
+    // buckets like with_config does.
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter5<K, P> {
+        RateLimiter5 {
+            buckets: self.buckets,
+            burst: self.burst,
+            refill_interval: self.refill_interval,
+            limits,
+        }
+    }
+
+    pub fn ratelimit5(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost5(src_ip, timestamp, 1)
+    }
+
+    // Takes `cost` tokens at once, if the bucket holds that many
+    pub fn ratelimit_with_cost5(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let entry = match self.buckets.get(&src_ip) {
+            Some(entry) => entry,
+            None => {
+                let (burst, refill_interval) = self.limits.limits(&src_ip).map_or(
+                    (self.burst, self.refill_interval),
+                    |(max_requests, window)| (max_requests, refill_interval(max_requests, window)),
+                );
+                self.buckets.get_or_insert_with(src_ip, || {
+                    Mutex::new(Bucket {
+                        tokens: burst,
+                        refilled_at: timestamp,
+                        burst,
+                        refill_interval,
+                    })
+                })
+            }
+        };
+        let mut bucket = entry.value().lock().unwrap();
+
+        // Only whole tokens are added, and refilled_at only moves forward by the time
+        // they took, so progress towards the next token isn't lost
+        let interval = bucket.refill_interval.num_nanoseconds().unwrap_or(i64::MAX);
+        let refills = bucket.refills(timestamp);
+        if refills >= (bucket.burst - bucket.tokens) as u64 {
+            bucket.tokens = bucket.burst;
+            bucket.refilled_at = bucket.refilled_at.max(timestamp);
+        } else if refills > 0 {
+            bucket.tokens += refills as usize;
+            bucket.refilled_at += Duration::nanoseconds(refills as i64 * interval);
+        }
+
+        if bucket.tokens < cost as usize {
+            return false;
+        }
+        bucket.tokens -= cost as usize;
+        true
+    }
+}
+
+impl Bucket {
+    // The whole tokens to add by `timestamp`. Timestamps from before the last refill
+    // add nothing.
+    fn refills(&self, timestamp: DateTime<Utc>) -> u64 {
+        let interval = self.refill_interval.num_nanoseconds().unwrap_or(i64::MAX);
+        let elapsed = (timestamp - self.refilled_at)
+            .num_nanoseconds()
+            .unwrap_or(i64::MAX);
+        (elapsed.max(0) / interval) as u64
+    }
+}
+
+// Refills `max_requests` per `window`, so the sustained rate matches a sliding log.
+// Windows shorter than a nanosecond per request refill every nanosecond.
+fn refill_interval(max_requests: usize, window: Duration) -> Duration {
+    let window = window.num_nanoseconds().unwrap_or(i64::MAX);
+    Duration::nanoseconds((window / max_requests.max(1) as i64).max(1))
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimit<K> for RateLimiter5<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit5(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost5(key, timestamp, cost)
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let Some(entry) = self.buckets.get(&key) else {
+            return max_requests_of(&self.limits, &key, self.burst) > 0;
+        };
+        let bucket = entry.value().lock().unwrap();
+        let tokens = (bucket.tokens as u64).saturating_add(bucket.refills(timestamp));
+        tokens.min(bucket.burst as u64) > 0
+    }
+}
+
+impl<K: Ord, L> TrackedKeys for RateLimiter5<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_ratelimit5_max_limit_still_permitted() {
+        let rate_limiter = RateLimiter5::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit5(ip, now), true);
+        }
+    }
+
+    #[test]
+    fn test_ratelimit5_over_denied() {
+        let rate_limiter = RateLimiter5::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit5(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit5(ip, now), false);
+    }
+
+    #[test]
+    fn test_ratelimit5_refills_one_token_per_interval() {
+        let rate_limiter = RateLimiter5::with_bucket(2, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit5(ip, now), true);
+        assert_eq!(rate_limiter.ratelimit5(ip, now), true);
+        assert_eq!(rate_limiter.ratelimit5(ip, now), false);
+
+        // Partial intervals add nothing, but aren't lost either
+        let later = now + Duration::milliseconds(600);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), false);
+        let later = now + Duration::seconds(1);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), true);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), false);
+        let later = now + Duration::seconds(2);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), true);
+    }
+
+    #[test]
+    fn test_ratelimit5_burst_is_capped() {
+        let rate_limiter = RateLimiter5::with_bucket(3, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit5(ip, now), true);
+
+        // An idle hour only refills the bucket up to the burst
+        let later = now + Duration::hours(1);
+        for _ in 0..3 {
+            assert_eq!(rate_limiter.ratelimit5(ip, later), true);
+        }
+        assert_eq!(rate_limiter.ratelimit5(ip, later), false);
+    }
+
+    #[test]
+    fn test_ratelimit5_with_config() {
+        let rate_limiter = RateLimiter5::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit5(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit5(ip, now), false);
+
+        let later = now + Duration::milliseconds(200);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), true);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), false);
+    }
+
+    // A window shorter than a nanosecond per request refills every nanosecond rather
+    // than panicking on a zero refill_interval
+    #[test]
+    fn test_ratelimit5_with_config_tiny_window() {
+        let rate_limiter = RateLimiter5::with_config(1_000, Duration::nanoseconds(10));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = (0..2_000)
+            .filter(|_| rate_limiter.ratelimit5(ip, now))
+            .count();
+        assert_eq!(admitted, 1_000);
+
+        let later = now + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), true);
+        assert_eq!(rate_limiter.ratelimit5(ip, later), false);
+    }
+
+    #[test]
+    fn test_ratelimit5_with_cost_takes_several_tokens() {
+        let rate_limiter = RateLimiter5::with_bucket(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit_with_cost5(ip, now, 3), true);
+        // Only 2 tokens are left, and a denied request takes none of them
+        assert_eq!(rate_limiter.ratelimit_with_cost5(ip, now, 3), false);
+        assert_eq!(rate_limiter.ratelimit_with_cost5(ip, now, 2), true);
+        assert_eq!(rate_limiter.ratelimit5(ip, now), false);
+
+        let later = now + Duration::seconds(2);
+        assert_eq!(rate_limiter.ratelimit_with_cost5(ip, later, 2), true);
+        // More than the burst never fits
+        let much_later = now + Duration::hours(1);
+        assert_eq!(rate_limiter.ratelimit_with_cost5(ip, much_later, 6), false);
+    }
+
+    #[test]
+    fn test_ratelimit5_honours_strictness() {
+        const NUM_THREADS: usize = 10;
+        let rate_limiter = RateLimiter5::new();
+
+        let admitted = crate::strictness::admitted_under_contention(
+            |ip, ts| rate_limiter.ratelimit5(ip, ts),
+            NUM_THREADS,
+            MAX_REQUESTS,
+        );
+
+        assert!(
+            RateLimiter5::STRICTNESS.permits(admitted, MAX_REQUESTS),
+            "Admitted {} requests with a limit of {}",
+            admitted,
+            MAX_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_ratelimit5_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter5::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit5(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
+}
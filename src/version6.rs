@@ -0,0 +1,422 @@
+use super::*;
+use crate::clock::timestamp_nanos;
+use chrono::{DateTime, Duration, Utc};
+use crossbeam_skiplist::SkipMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+// The generic cell rate algorithm. Instead of a log of requests, each key only keeps
+// its theoretical arrival time (TAT): when its next request would be due if it sent
+// them evenly spaced at the sustained rate. A request is admitted as long as that
+// doesn't put the TAT more than one window ahead, which allows bursts of up to
+// `max_requests`.
+#[derive(Debug)]
+pub struct RateLimiter6<K: Ord = IpAddr, L = GlobalLimits> {
+    tats: SkipMap<K, Tat>,
+    max_requests: usize,
+    window: Duration,
+    strictness: Strictness,
+    // The spacing between requests at the sustained rate, in nanoseconds
+    emission_interval_ns: i64,
+    // How far ahead of a request the TAT may run, in nanoseconds
+    tolerance_ns: i64,
+    limits: L,
+}
+
+// The interval and tolerance are those the key was created with, see LimitProvider
+#[derive(Debug)]
+struct Tat {
+    // Nanoseconds since the epoch, updated with compare and swap
+    tat: AtomicI64,
+    emission_interval_ns: i64,
+    tolerance_ns: i64,
+}
+
+impl RateLimiter6 {
+    // The TAT is only ever moved by compare and swap, but like a token bucket a key
+    // can burst at the start of a window and keep up the sustained rate until its end,
+    // admitting up to twice the limit by default, see with_strictness
+    pub const STRICTNESS: Strictness = Strictness::Relaxed { epsilon: 1.0 };
+}
+
+impl<K: Ord + Send + 'static> Default for RateLimiter6<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static> RateLimiter6<K> {
+    pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Allows bursts of `max_requests`, and sustains `max_requests` per `window`
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        let (emission_interval_ns, tolerance_ns) = gcra(max_requests, window);
+        RateLimiter6 {
+            tats: SkipMap::new(),
+            max_requests,
+            window,
+            strictness: RateLimiter6::STRICTNESS,
+            emission_interval_ns,
+            tolerance_ns,
+            limits: GlobalLimits,
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimiter6<K, L> {
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter6<K, P> {
+        RateLimiter6 {
+            tats: self.tats,
+            max_requests: self.max_requests,
+            window: self.window,
+            strictness: self.strictness,
+            emission_interval_ns: self.emission_interval_ns,
+            tolerance_ns: self.tolerance_ns,
+            limits,
+        }
+    }
+
+    // Trades the burst for how much may be over-admitted within a window. A relaxed
+    // epsilon of 1 is the default, bursting the whole limit. A smaller one bursts
+    // `epsilon * max_requests` (rounded up), and Strict bursts a single request, while
+    // every key still sustains about `max_requests` per window. Unbounded is taken as the
+    // default, as the TAT never races. Applies to keys created from then on.
+    pub fn with_strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        (self.emission_interval_ns, self.tolerance_ns) =
+            relaxed_gcra(self.max_requests, self.window, self.epsilon());
+        self
+    }
+
+    // How far this limiter may over-admit within a window
+    pub fn strictness(&self) -> Strictness {
+        let epsilon = self.epsilon();
+        if epsilon == 0.0 {
+            Strictness::Strict
+        } else {
+            Strictness::Relaxed { epsilon }
+        }
+    }
+
+    fn epsilon(&self) -> f64 {
+        self.strictness.epsilon().unwrap_or(1.0)
+    }
+
+    pub fn ratelimit6(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost6(src_ip, timestamp, 1)
+    }
+
+    // Pushes the TAT back by `cost` emission intervals at once, if it stays within the
+    // tolerance
+    pub fn ratelimit_with_cost6(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let now = timestamp_nanos(timestamp);
+
+        let entry = match self.tats.get(&src_ip) {
+            Some(entry) => entry,
+            None => {
+                let (emission_interval_ns, tolerance_ns) = self.limits.limits(&src_ip).map_or(
+                    (self.emission_interval_ns, self.tolerance_ns),
+                    |(max_requests, window)| relaxed_gcra(max_requests, window, self.epsilon()),
+                );
+                self.tats.get_or_insert_with(src_ip, || Tat {
+                    tat: AtomicI64::new(i64::MIN),
+                    emission_interval_ns,
+                    tolerance_ns,
+                })
+            }
+        };
+        let Tat {
+            tat,
+            emission_interval_ns,
+            tolerance_ns,
+        } = entry.value();
+        let increment = emission_interval_ns.saturating_mul(cost as i64);
+
+        let mut current = tat.load(Ordering::Acquire);
+        loop {
+            let next = current.max(now).saturating_add(increment);
+            if next.saturating_sub(now) > *tolerance_ns {
+                return false;
+            }
+            match tat.compare_exchange_weak(current, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+// The emission interval and tolerance admitting bursts of `max_requests`, and
+// sustaining `max_requests` per `window`
+pub(crate) fn gcra(max_requests: usize, window: Duration) -> (i64, i64) {
+    relaxed_gcra(max_requests, window, 1.0)
+}
+
+// The emission interval T and tolerance admitting at most `max_requests` plus
+// `epsilon * max_requests` (rounded up) within any window W. Requests admitted within
+// W each push the TAT back by T, and it never runs more than the tolerance ahead, so
+// at most W / T + tolerance / T of them fit. The tolerance is the burst times T, and
+// T is at least W / max_requests, and longer when that leaves no room for the burst.
+pub(crate) fn relaxed_gcra(max_requests: usize, window: Duration, epsilon: f64) -> (i64, i64) {
+    let window = window.num_nanoseconds().unwrap_or(i64::MAX).max(1);
+    if max_requests == 0 {
+        return (window, 0);
+    }
+    let max_admitted = Strictness::Relaxed { epsilon }
+        .max_admitted(max_requests)
+        .unwrap_or(usize::MAX);
+    // At least one request has to fit, and bursting more than the limit would only
+    // take from the sustained rate
+    let burst = (max_admitted - max_requests).clamp(1, max_requests);
+    // W / T rounded down must not exceed the rest, so T must be over W / (rest + 1)
+    let rest = i64::try_from(max_admitted - burst).unwrap_or(i64::MAX);
+    let max_requests = max_requests as i64;
+    let emission_interval_ns = (window / max_requests + (window % max_requests != 0) as i64)
+        .max((window / rest.saturating_add(1)).saturating_add(1));
+    (
+        emission_interval_ns,
+        emission_interval_ns.saturating_mul(burst as i64),
+    )
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimit<K> for RateLimiter6<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit6(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost6(key, timestamp, cost)
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let Some(entry) = self.tats.get(&key) else {
+            return max_requests_of(&self.limits, &key, self.max_requests) > 0;
+        };
+        let Tat {
+            tat,
+            emission_interval_ns,
+            tolerance_ns,
+        } = entry.value();
+        let now = timestamp_nanos(timestamp);
+        let next = tat
+            .load(Ordering::Acquire)
+            .max(now)
+            .saturating_add(*emission_interval_ns);
+        next.saturating_sub(now) <= *tolerance_ns
+    }
+}
+
+impl<K: Ord, L> TrackedKeys for RateLimiter6<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.tats.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_ratelimit6_max_limit_still_permitted() {
+        let rate_limiter = RateLimiter6::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit6(ip, now), true);
+        }
+    }
+
+    #[test]
+    fn test_ratelimit6_over_denied() {
+        let rate_limiter = RateLimiter6::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit6(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit6(ip, now), false);
+    }
+
+    #[test]
+    fn test_ratelimit6_admits_at_sustained_rate_after_burst() {
+        let rate_limiter = RateLimiter6::with_config(2, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit6(ip, now), true);
+        assert_eq!(rate_limiter.ratelimit6(ip, now), true);
+        assert_eq!(rate_limiter.ratelimit6(ip, now), false);
+
+        let interval = Duration::milliseconds(500);
+        let later = now + interval - Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit6(ip, later), false);
+        assert_eq!(rate_limiter.ratelimit6(ip, now + interval), true);
+        assert_eq!(rate_limiter.ratelimit6(ip, now + interval), false);
+        assert_eq!(rate_limiter.ratelimit6(ip, now + interval * 2), true);
+    }
+
+    #[test]
+    fn test_ratelimit6_idle_time_only_restores_the_burst() {
+        let rate_limiter = RateLimiter6::with_config(3, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit6(ip, now), true);
+
+        let later = now + Duration::hours(1);
+        for _ in 0..3 {
+            assert_eq!(rate_limiter.ratelimit6(ip, later), true);
+        }
+        assert_eq!(rate_limiter.ratelimit6(ip, later), false);
+    }
+
+    #[test]
+    fn test_ratelimit6_keys_are_independent() {
+        let rate_limiter = RateLimiter6::with_config(1, Duration::seconds(1));
+        let now = Utc::now();
+        let ips: Vec<IpAddr> = (0..16)
+            .map(|i| format!("10.0.0.{i}").parse().unwrap())
+            .collect();
+
+        for &ip in &ips {
+            assert_eq!(rate_limiter.ratelimit6(ip, now), true);
+        }
+        for &ip in &ips {
+            assert_eq!(rate_limiter.ratelimit6(ip, now), false);
+        }
+    }
+
+    // Timestamps an i64 of nanoseconds can't hold saturate rather than panicking
+    #[test]
+    fn test_ratelimit6_out_of_range_timestamps() {
+        let rate_limiter = RateLimiter6::with_config(1, Duration::seconds(1));
+        let (early, late): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        assert_eq!(
+            rate_limiter.ratelimit6(early, DateTime::<Utc>::MIN_UTC),
+            true
+        );
+        assert_eq!(
+            rate_limiter.ratelimit6(early, DateTime::<Utc>::MIN_UTC),
+            false
+        );
+        assert_eq!(
+            rate_limiter.ratelimit6(late, DateTime::<Utc>::MAX_UTC),
+            true
+        );
+    }
+
+    #[test]
+    fn test_ratelimit6_with_cost_takes_several_emission_intervals() {
+        let rate_limiter = RateLimiter6::with_config(5, Duration::seconds(5));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit_with_cost6(ip, now, 3), true);
+        assert_eq!(rate_limiter.ratelimit_with_cost6(ip, now, 3), false);
+        assert_eq!(rate_limiter.ratelimit_with_cost6(ip, now, 2), true);
+        assert_eq!(rate_limiter.ratelimit6(ip, now), false);
+
+        // Every second frees up one interval
+        let later = now + Duration::seconds(2);
+        assert_eq!(rate_limiter.ratelimit_with_cost6(ip, later, 3), false);
+        assert_eq!(rate_limiter.ratelimit_with_cost6(ip, later, 2), true);
+        // More than the burst never fits
+        let much_later = now + Duration::hours(1);
+        assert_eq!(rate_limiter.ratelimit_with_cost6(ip, much_later, 6), false);
+    }
+
+    #[test]
+    fn test_ratelimit6_honours_strictness() {
+        const NUM_THREADS: usize = 10;
+        let rate_limiter = RateLimiter6::new();
+
+        let admitted = crate::strictness::admitted_under_contention(
+            |ip, ts| rate_limiter.ratelimit6(ip, ts),
+            NUM_THREADS,
+            MAX_REQUESTS,
+        );
+
+        assert!(
+            RateLimiter6::STRICTNESS.permits(admitted, MAX_REQUESTS),
+            "Admitted {} requests with a limit of {}",
+            admitted,
+            MAX_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_ratelimit6_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter6::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit6(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
+
+    #[test]
+    fn test_ratelimit6_with_strictness_bounds_every_window() {
+        let window = Duration::seconds(10);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let start = Utc::now();
+
+        for strictness in [
+            Strictness::Strict,
+            Strictness::Relaxed { epsilon: 0.2 },
+            Strictness::Relaxed { epsilon: 1.0 },
+        ] {
+            let rate_limiter = RateLimiter6::with_config(10, window).with_strictness(strictness);
+            assert_eq!(rate_limiter.strictness(), strictness);
+
+            // A request every 10ms for three windows
+            let admitted: Vec<_> = (0..3000)
+                .map(|i| start + Duration::milliseconds(10 * i))
+                .filter(|&at| rate_limiter.ratelimit6(ip, at))
+                .collect();
+            assert_eq!(
+                crate::strictness::max_within_a_window(&admitted, window),
+                strictness.max_admitted(10).unwrap(),
+                "{:?}",
+                strictness
+            );
+            // Only the burst differs, after it every mode admits a request per second
+            let burst = strictness.max_admitted(10).unwrap() - 10;
+            assert_eq!(admitted.len(), 30 + burst.max(1) - 1, "{:?}", strictness);
+        }
+    }
+    // Bursting more than the limit would only take from the sustained rate, so a huge
+    // epsilon bursts the limit, like the default
+    #[test]
+    fn test_ratelimit6_with_a_huge_epsilon() {
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for epsilon in [1e20, f64::INFINITY] {
+            let rate_limiter = RateLimiter6::with_config(10, Duration::seconds(1))
+                .with_strictness(Strictness::Relaxed { epsilon });
+
+            let admitted = (0..20).filter(|_| rate_limiter.ratelimit6(ip, now)).count();
+            assert_eq!(admitted, 10, "{}", epsilon);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use super::*;
+use crate::clock::timestamp_nanos;
+use chrono::{DateTime, Duration, Utc};
+use crossbeam_skiplist::SkipMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+// The sliding window counter approximation. Time is split into fixed windows, and
+// each key only counts its requests in the current and the previous one. The number
+// of requests in the sliding window is estimated by weighting the previous window's
+// count by how much of it the sliding window still overlaps, as if its requests were
+// spread evenly over it.
+#[derive(Debug)]
+pub struct RateLimiter7<K: Ord = IpAddr, L = GlobalLimits> {
+    counters: SkipMap<K, Mutex<Counter>>,
+    max_requests: usize,
+    window: Duration,
+    // The share of the previous window's count that may be left out, see
+    // with_strictness
+    epsilon: f64,
+    limits: L,
+}
+
+// The limits are those the key was created with, see LimitProvider
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    max_requests: usize,
+    window_ns: i64,
+    // The fixed window `current` counts, as the number of windows since the epoch
+    index: i64,
+    current: usize,
+    previous: usize,
+}
+
+impl RateLimiter7 {
+    // Each counter is behind its own lock, but the previous window's requests may have
+    // all been made at its end rather than spread over it, in which case up to twice
+    // the limit is admitted within a window by default, see with_strictness
+    pub const STRICTNESS: Strictness = Strictness::Relaxed { epsilon: 1.0 };
+}
+
+impl<K: Ord + Send + 'static> Default for RateLimiter7<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static> RateLimiter7<K> {
+    pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to an estimated `max_requests` per key within any `window`. The fixed
+    // windows are aligned to the epoch.
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        assert!(
+            window > Duration::zero(),
+            "RateLimiter7 needs a window above zero"
+        );
+        RateLimiter7 {
+            counters: SkipMap::new(),
+            max_requests,
+            window,
+            epsilon: 1.0,
+            limits: GlobalLimits,
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimiter7<K, L> {
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter7<K, P> {
+        RateLimiter7 {
+            counters: self.counters,
+            max_requests: self.max_requests,
+            window: self.window,
+            epsilon: self.epsilon,
+            limits,
+        }
+    }
+
+    // Weighs at least `1 - epsilon` of the previous window's count in, however little
+    // of it the sliding window overlaps. Its requests within the sliding window are
+    // then underestimated by at most `epsilon` of a count within the limit, so at most
+    // `epsilon * max_requests` are over-admitted. Strict weighs it in fully, which can
+    // deny requests the sliding window has room for. A relaxed epsilon of 1 is the
+    // default, and Unbounded or a larger one are taken as that.
+    pub fn with_strictness(mut self, strictness: Strictness) -> Self {
+        self.epsilon = strictness.epsilon().unwrap_or(1.0).min(1.0);
+        self
+    }
+
+    // How far this limiter may over-admit within a window
+    pub fn strictness(&self) -> Strictness {
+        if self.epsilon == 0.0 {
+            Strictness::Strict
+        } else {
+            Strictness::Relaxed {
+                epsilon: self.epsilon,
+            }
+        }
+    }
+
+    pub fn ratelimit7(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost7(src_ip, timestamp, 1)
+    }
+
+    // Counts the request `cost` times, if the estimate stays within the limit
+    pub fn ratelimit_with_cost7(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let now = timestamp_nanos(timestamp);
+
+        let entry = get_or_insert_with_limits(
+            &self.counters,
+            src_ip,
+            &self.limits,
+            (self.max_requests, self.window),
+            |max_requests, window| {
+                Mutex::new(Counter {
+                    max_requests,
+                    // A window under a nanosecond would divide by zero below
+                    window_ns: window.num_nanoseconds().unwrap_or(i64::MAX).max(1),
+                    index: i64::MIN,
+                    current: 0,
+                    previous: 0,
+                })
+            },
+        );
+        let mut counter = entry.value().lock().unwrap();
+        let elapsed = counter.roll(now);
+        if !counter.admits(elapsed, cost, self.epsilon) {
+            return false;
+        }
+        counter.current += cost as usize;
+        true
+    }
+}
+
+impl Counter {
+    // Moves the counts on to the fixed window of `now`, and returns how far into the
+    // current window `now` is. Timestamps from before the current window are checked
+    // as if at its start, where the previous window still weighs in fully.
+    fn roll(&mut self, now: i64) -> i64 {
+        let index = now.div_euclid(self.window_ns);
+        if index > self.index {
+            self.previous = if index == self.index.saturating_add(1) {
+                self.current
+            } else {
+                0
+            };
+            self.current = 0;
+            self.index = index;
+        }
+        if index < self.index {
+            0
+        } else {
+            now.rem_euclid(self.window_ns)
+        }
+    }
+
+    // Whether `cost` more requests `elapsed` into the current window keep the estimate
+    // within the limit
+    fn admits(&self, elapsed: i64, cost: u32, epsilon: f64) -> bool {
+        // previous * (window - elapsed) / window + current + cost <= max_requests,
+        // multiplied out so the fractions don't need rounding
+        let min_overlap = ((1.0 - epsilon) * self.window_ns as f64).ceil() as i64;
+        let overlap = (self.window_ns - elapsed).max(min_overlap.min(self.window_ns));
+        let window_ns = self.window_ns as i128;
+        let estimate = self.previous as i128 * overlap as i128
+            + (self.current as i128 + cost as i128) * window_ns;
+        estimate <= self.max_requests as i128 * window_ns
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimit<K> for RateLimiter7<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit7(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost7(key, timestamp, cost)
+    }
+
+    // Rolls a copy of the counter, so the key's own counts are left as they are
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let Some(entry) = self.counters.get(&key) else {
+            return max_requests_of(&self.limits, &key, self.max_requests) > 0;
+        };
+        let mut counter = *entry.value().lock().unwrap();
+        let elapsed = counter.roll(timestamp_nanos(timestamp));
+        counter.admits(elapsed, 1, self.epsilon)
+    }
+}
+
+impl<K: Ord, L> TrackedKeys for RateLimiter7<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.counters.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+
+    // The start of a fixed window of any whole number of seconds
+    fn window_start() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 10, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_ratelimit7_over_denied() {
+        let rate_limiter = RateLimiter7::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit7(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit7(ip, now), false);
+    }
+
+    #[test]
+    fn test_ratelimit7_weights_the_previous_window_by_its_overlap() {
+        let rate_limiter = RateLimiter7::with_config(10, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let start = window_start();
+
+        for _ in 0..10 {
+            assert_eq!(rate_limiter.ratelimit7(ip, start), true);
+        }
+
+        // A quarter into the next window, the previous one still counts for 7.5
+        let quarter = start + Duration::seconds(75);
+        assert_eq!(rate_limiter.ratelimit7(ip, quarter), true);
+        assert_eq!(rate_limiter.ratelimit7(ip, quarter), true);
+        assert_eq!(rate_limiter.ratelimit7(ip, quarter), false);
+
+        // Halfway, it counts for 5, next to the 2 already admitted
+        let half = start + Duration::seconds(90);
+        for _ in 0..3 {
+            assert_eq!(rate_limiter.ratelimit7(ip, half), true);
+        }
+        assert_eq!(rate_limiter.ratelimit7(ip, half), false);
+    }
+
+    #[test]
+    fn test_ratelimit7_forgets_windows_older_than_the_previous_one() {
+        let rate_limiter = RateLimiter7::with_config(10, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let start = window_start();
+
+        for _ in 0..10 {
+            rate_limiter.ratelimit7(ip, start);
+        }
+
+        let later = start + Duration::seconds(120);
+        let admitted = (0..20)
+            .filter(|_| rate_limiter.ratelimit7(ip, later))
+            .count();
+        assert_eq!(admitted, 10);
+    }
+
+    // Timestamps an i64 of nanoseconds can't hold saturate rather than panicking
+    #[test]
+    fn test_ratelimit7_out_of_range_timestamps() {
+        let rate_limiter = RateLimiter7::with_config(1, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        for timestamp in [DateTime::<Utc>::MIN_UTC, DateTime::<Utc>::MAX_UTC] {
+            assert_eq!(rate_limiter.ratelimit7(ip, timestamp), true);
+            assert_eq!(rate_limiter.ratelimit7(ip, timestamp), false);
+        }
+    }
+
+    #[test]
+    fn test_ratelimit7_with_cost() {
+        let rate_limiter = RateLimiter7::with_config(10, Duration::seconds(60));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit_with_cost7(ip, now, 11), false);
+        assert_eq!(rate_limiter.ratelimit_with_cost7(ip, now, 8), true);
+        assert_eq!(rate_limiter.ratelimit_with_cost7(ip, now, 3), false);
+        assert_eq!(rate_limiter.ratelimit_with_cost7(ip, now, 2), true);
+    }
+
+    #[test]
+    fn test_ratelimit7_honours_strictness() {
+        const NUM_THREADS: usize = 10;
+        let rate_limiter = RateLimiter7::new();
+
+        let admitted = crate::strictness::admitted_under_contention(
+            |ip, ts| rate_limiter.ratelimit7(ip, ts),
+            NUM_THREADS,
+            MAX_REQUESTS,
+        );
+
+        assert!(
+            RateLimiter7::STRICTNESS.permits(admitted, MAX_REQUESTS),
+            "Admitted {} requests with a limit of {}",
+            admitted,
+            MAX_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_ratelimit7_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter7::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = window_start();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit7(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
+
+    #[test]
+    fn test_ratelimit7_with_strictness_bounds_every_window() {
+        let window = Duration::seconds(10);
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        for strictness in [
+            Strictness::Strict,
+            Strictness::Relaxed { epsilon: 0.2 },
+            Strictness::Relaxed { epsilon: 1.0 },
+        ] {
+            let rate_limiter = RateLimiter7::with_config(10, window).with_strictness(strictness);
+            assert_eq!(rate_limiter.strictness(), strictness);
+
+            // The whole limit at the end of one fixed window, then a request every
+            // 10ms through the next
+            let end = window_start() - Duration::milliseconds(1);
+            let admitted: Vec<_> = std::iter::repeat_n(end, 10)
+                .chain((0..1000).map(|i| window_start() + Duration::milliseconds(10 * i)))
+                .filter(|&at| rate_limiter.ratelimit7(ip, at))
+                .collect();
+            let max_within = crate::strictness::max_within_a_window(&admitted, window);
+            assert!(
+                strictness.permits(max_within, 10),
+                "{:?} admitted {} within a window",
+                strictness,
+                max_within
+            );
+        }
+    }
+}
@@ -0,0 +1,573 @@
+use super::*;
+use crate::budget::MemoryBudget;
+use crate::lifecycle::{KeySummary, Lifecycle};
+use chrono::{DateTime, Duration, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The sliding logs of RateLimiter0, in a DashMap rather than behind a single lock.
+// The map is split into shards, each a HashMap behind its own RwLock, so checks of
+// keys in different shards don't wait for each other. The algorithm never iterates
+// the keys in order, so it doesn't need the SkipMap of versions 1 to 3 either.
+#[derive(Debug)]
+pub struct RateLimiter8<K: Hash + Eq = IpAddr, L = GlobalLimits> {
+    requests: DashMap<K, Log>,
+    max_requests: usize,
+    window: Duration,
+    boundary: Boundary,
+    limits: L,
+    eviction: Eviction,
+    // The number of tracked keys over which the next check sweeps out idle keys
+    next_sweep: AtomicUsize,
+    lifecycle: Lifecycle<K>,
+    budget: Option<MemoryBudget>,
+}
+
+// The limits are those the key was created with, see LimitProvider
+#[derive(Debug)]
+struct Log {
+    max_requests: usize,
+    window: Duration,
+    requests: VecDeque<DateTime<Utc>>,
+    summary: KeySummary,
+}
+
+impl RateLimiter8 {
+    // The key's shard is write locked for the whole check, so the check and the push
+    // are atomic
+    pub const STRICTNESS: Strictness = Strictness::Strict;
+}
+
+impl<K: Hash + Eq> Default for RateLimiter8<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq> RateLimiter8<K> {
+    pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to `max_requests` per key within any `window`, instead of the crate
+    // wide MAX_REQUESTS per MAX_REQUESTS_DURATION_SECONDS
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        Self::with_map(DashMap::new(), max_requests, window)
+    }
+
+    // Allocates room for `capacity` keys up front, so the map doesn't grow while the
+    // first keys come in
+    pub fn with_capacity(max_requests: usize, window: Duration, capacity: usize) -> Self {
+        Self::with_map(DashMap::with_capacity(capacity), max_requests, window)
+    }
+
+    // Like with_capacity, split over `shards` shards rather than 4 per core. DashMap
+    // panics unless it is a power of two greater than 1, which RateLimiterBuilder
+    // checks before building.
+    pub fn with_capacity_and_shards(
+        max_requests: usize,
+        window: Duration,
+        capacity: usize,
+        shards: usize,
+    ) -> Self {
+        Self::with_map(
+            DashMap::with_capacity_and_shard_amount(capacity, shards),
+            max_requests,
+            window,
+        )
+    }
+
+    fn with_map(requests: DashMap<K, Log>, max_requests: usize, window: Duration) -> Self {
+        RateLimiter8 {
+            requests,
+            max_requests,
+            window,
+            boundary: Boundary::default(),
+            limits: GlobalLimits,
+            eviction: Eviction::Never,
+            next_sweep: AtomicUsize::new(usize::MAX),
+            lifecycle: Lifecycle::default(),
+            budget: None,
+        }
+    }
+}
+
+impl<K: Hash + Eq, L: LimitProvider<K>> RateLimiter8<K, L> {
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter8<K, P> {
+        RateLimiter8 {
+            requests: self.requests,
+            max_requests: self.max_requests,
+            window: self.window,
+            boundary: self.boundary,
+            limits,
+            eviction: self.eviction,
+            next_sweep: self.next_sweep,
+            lifecycle: self.lifecycle,
+            budget: self.budget,
+        }
+    }
+
+    // Drops the keys all of whose requests left their window once more than
+    // `max_keys` are tracked, see Eviction. An evicted key that comes back asks the
+    // LimitProvider for its limits again.
+    pub fn with_eviction(mut self, eviction: Eviction) -> Self {
+        let next_sweep = match eviction {
+            Eviction::Never => usize::MAX,
+            Eviction::Idle { max_keys } => max_keys,
+        };
+        self.eviction = eviction;
+        self.next_sweep = AtomicUsize::new(next_sweep);
+        self
+    }
+
+    // Called with every key the first time it is checked, and again if it comes back
+    // after being evicted. It runs under the lock of the key's shard, so it must not
+    // check this limiter, and should hand slow work over to another task.
+    pub fn with_on_first_seen(mut self, callback: impl Fn(&K) + Send + Sync + 'static) -> Self {
+        self.lifecycle = self.lifecycle.with_on_first_seen(callback);
+        self
+    }
+
+    // Called with every key evicted, see with_eviction, and a summary of its checks.
+    // Like with_on_first_seen, it runs under the lock of the key's shard.
+    pub fn with_on_evicted(
+        mut self,
+        callback: impl Fn(&K, KeySummary) + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle = self.lifecycle.with_on_evicted(callback);
+        self
+    }
+
+    // Caps the bytes the keys take up, estimated from the sizes of the types stored
+    // like planning does, and counting the keys tracked so far. A new key that finds
+    // the budget spent first evicts every key all of whose requests left their window,
+    // at most once per window. If that doesn't free enough, new keys are counted in a
+    // fixed size sketch of fixed windows, set aside from the budget, until it does.
+    // The sketch can admit up to twice the limit across the boundary of two windows,
+    // and deny a key early when it shares counters with busier ones. Keys already
+    // tracked keep their logs, which grow up to their limit, so the budget is enforced
+    // as keys are added, not as their logs grow.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        let used_bytes = self
+            .requests
+            .iter()
+            .map(|entry| Self::bytes_of(entry.value()))
+            .sum();
+        self.budget = Some(MemoryBudget::new(bytes, used_bytes));
+        self
+    }
+
+    // Called as the keys run into the memory budget, see MemoryEvent
+    pub fn with_on_memory_event(
+        mut self,
+        callback: impl Fn(MemoryEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle = self.lifecycle.with_on_memory_event(callback);
+        self
+    }
+
+    // The bytes the keys take up, as counted against the memory budget
+    pub fn memory_used8(&self) -> usize {
+        match &self.budget {
+            Some(budget) => budget.used_bytes(),
+            None => self
+                .requests
+                .iter()
+                .map(|entry| Self::bytes_of(entry.value()))
+                .sum(),
+        }
+    }
+
+    fn bytes_of(log: &Log) -> usize {
+        size_of::<(K, Log)>() + log.requests.capacity() * size_of::<DateTime<Utc>>()
+    }
+
+    // Replaces every callback at once, for RateLimiterBuilder
+    pub(crate) fn with_lifecycle(mut self, lifecycle: Lifecycle<K>) -> Self {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    pub fn ratelimit8(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost8(src_ip, timestamp, 1)
+    }
+
+    // Records the request as `cost` requests at the same timestamp, if all of them fit
+    pub fn ratelimit_with_cost8(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        if let Some(budget) = &self.budget {
+            if budget.is_spent() && !self.requests.contains_key(&src_ip) {
+                return self.check_over_budget8(budget, src_ip, timestamp, cost);
+            }
+            if let Some(event) = budget.recover() {
+                self.lifecycle.memory_event(event);
+            }
+        }
+
+        let admitted = self.check8(src_ip, timestamp, cost);
+        // Counting and sweeping the keys lock every shard in turn, so the key's shard
+        // has to be unlocked by then
+        if let Eviction::Idle { max_keys } = self.eviction {
+            if self.requests.len() > self.next_sweep.load(Ordering::Relaxed) {
+                self.evict_idle8(timestamp, max_keys);
+            }
+        }
+        admitted
+    }
+
+    fn check8(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        // Holds the write lock of the key's shard until the end of the check
+        let mut log = match self.requests.entry(src_ip) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                let (max_requests, window) = self
+                    .limits
+                    .limits(entry.key())
+                    .unwrap_or((self.max_requests, self.window));
+                self.lifecycle.first_seen(entry.key());
+                if let Some(budget) = &self.budget {
+                    budget.grow(size_of::<(K, Log)>());
+                }
+                entry.insert(Log {
+                    max_requests,
+                    window,
+                    requests: VecDeque::new(),
+                    summary: KeySummary::new(timestamp),
+                })
+            }
+        };
+        let log = &mut *log;
+        let cutoff_time = timestamp - log.window;
+
+        while let Some(front_time) = log.requests.front() {
+            if !self.boundary.contains(cutoff_time, *front_time) {
+                log.requests.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let admitted = log.requests.len() + cost as usize <= log.max_requests;
+        if admitted {
+            let capacity = log.requests.capacity();
+            log.requests
+                .extend(std::iter::repeat_n(timestamp, cost as usize));
+            if let Some(budget) = &self.budget {
+                budget.grow((log.requests.capacity() - capacity) * size_of::<DateTime<Utc>>());
+            }
+        }
+        log.summary.record(timestamp, admitted);
+        admitted
+    }
+
+    // Drops every key whose requests all left its window, so it would be admitted
+    // like a new key anyway. The next sweep waits for the keys left to double, so
+    // sweeping stays linear in the number of checks even when few keys are idle.
+    fn evict_idle8(&self, timestamp: DateTime<Utc>, max_keys: usize) {
+        self.sweep8(timestamp);
+        let next_sweep = max_keys.max(self.requests.len().saturating_mul(2));
+        self.next_sweep.store(next_sweep, Ordering::Relaxed);
+    }
+
+    // Drops every key whose requests all left its window, and returns how many
+    fn sweep8(&self, timestamp: DateTime<Utc>) -> usize {
+        let mut evicted = 0;
+        self.requests.retain(|key, log| {
+            let active = log
+                .requests
+                .back()
+                .is_some_and(|&newest| self.boundary.contains(timestamp - log.window, newest));
+            if !active {
+                self.lifecycle.evicted(key, log.summary);
+                if let Some(budget) = &self.budget {
+                    budget.shrink(Self::bytes_of(log));
+                }
+                evicted += 1;
+            }
+            active
+        });
+        evicted
+    }
+
+    // A new key found the memory budget spent, so it is only tracked if evicting
+    // frees enough, and counted in the sketch otherwise
+    fn check_over_budget8(
+        &self,
+        budget: &MemoryBudget,
+        src_ip: K,
+        timestamp: DateTime<Utc>,
+        cost: u32,
+    ) -> bool {
+        if budget.should_evict(timestamp, self.window) {
+            let evicted = self.sweep8(timestamp);
+            self.lifecycle.memory_event(MemoryEvent::EmergencyEviction {
+                used_bytes: budget.used_bytes(),
+                evicted,
+            });
+            if !budget.is_spent() {
+                if let Some(event) = budget.recover() {
+                    self.lifecycle.memory_event(event);
+                }
+                return self.check8(src_ip, timestamp, cost);
+            }
+        }
+
+        if let Some(event) = budget.degrade() {
+            self.lifecycle.memory_event(event);
+        }
+        let (max_requests, window) = self
+            .limits
+            .limits(&src_ip)
+            .unwrap_or((self.max_requests, self.window));
+        budget
+            .sketch()
+            .check(&src_ip, timestamp, max_requests, window, cost)
+    }
+}
+
+impl<K: Hash + Eq, L: LimitProvider<K>> RateLimit<K> for RateLimiter8<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit8(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost8(key, timestamp, cost)
+    }
+
+    // Keys without a log are checked in the sketch once the budget is spent, so they
+    // are peeked at there too
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        if let Some(log) = self.requests.get(&key) {
+            let cutoff_time = timestamp - log.window;
+            let in_window = log
+                .requests
+                .iter()
+                .filter(|&&time| self.boundary.contains(cutoff_time, time))
+                .count();
+            return in_window < log.max_requests;
+        }
+        let (max_requests, window) = self
+            .limits
+            .limits(&key)
+            .unwrap_or((self.max_requests, self.window));
+        match &self.budget {
+            Some(budget) if budget.is_spent() => {
+                budget.sketch().peek(&key, timestamp, max_requests, window)
+            }
+            _ => max_requests > 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq, L> TrackedKeys for RateLimiter8<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.requests.len()
+    }
+}
+
+// Each shard is read locked while its keys are copied
+#[cfg(feature = "serde")]
+impl<K: Hash + Eq + Clone, L> Snapshot<K> for RateLimiter8<K, L> {
+    fn snapshot(&self) -> RateLimiterSnapshot<K> {
+        RateLimiterSnapshot {
+            max_requests: self.max_requests,
+            window: self.window,
+            keys: self
+                .requests
+                .iter()
+                .map(|entry| {
+                    let log = entry.value();
+                    KeySnapshot {
+                        key: entry.key().clone(),
+                        max_requests: log.max_requests,
+                        window: log.window,
+                        requests: log.requests.iter().copied().collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Hash + Eq> RateLimiter8<K> {
+    // Restores the log of every key along with the limits it was created with
+    pub fn from_snapshot(snapshot: RateLimiterSnapshot<K>) -> Self {
+        let rate_limiter = Self::with_config(snapshot.max_requests, snapshot.window);
+        for key in snapshot.keys {
+            rate_limiter.requests.insert(
+                key.key,
+                Log {
+                    max_requests: key.max_requests,
+                    window: key.window,
+                    // Counts checks since the restore, and starts at the oldest request
+                    // logged, as that is the earliest the key is known to be seen
+                    summary: KeySummary::new(key.requests.first().copied().unwrap_or_default()),
+                    requests: key.requests.into(),
+                },
+            );
+        }
+        rate_limiter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_ratelimit8_over_denied() {
+        let rate_limiter = RateLimiter8::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit8(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit8(ip, now), false);
+    }
+
+    #[test]
+    fn test_ratelimit8_with_config() {
+        let rate_limiter = RateLimiter8::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit8(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit8(ip, now), false);
+        assert_eq!(
+            rate_limiter.ratelimit8(ip, now + Duration::seconds(1)),
+            false
+        );
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit8(ip, later), true);
+    }
+
+    #[test]
+    fn test_ratelimit8_honours_strictness() {
+        const NUM_THREADS: usize = 10;
+        let rate_limiter = RateLimiter8::new();
+
+        let admitted = crate::strictness::admitted_under_contention(
+            |ip, ts| rate_limiter.ratelimit8(ip, ts),
+            NUM_THREADS,
+            MAX_REQUESTS,
+        );
+
+        assert!(
+            RateLimiter8::STRICTNESS.permits(admitted, MAX_REQUESTS),
+            "Admitted {} requests with a limit of {}",
+            admitted,
+            MAX_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_ratelimit8_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter8::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit8(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
+
+    #[test]
+    fn test_ratelimit8_with_eviction_keeps_limited_keys() {
+        let rate_limiter = RateLimiter8::with_config(1, Duration::seconds(10))
+            .with_eviction(Eviction::Idle { max_keys: 2 });
+        let (ip, other_ip): (IpAddr, IpAddr) =
+            ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+        let now = Utc::now();
+
+        assert_eq!(rate_limiter.ratelimit8(ip, now), true);
+        let later = now + Duration::seconds(5);
+        assert_eq!(rate_limiter.ratelimit8(other_ip, later), true);
+
+        // Over max_keys, but only the first key's request left its window
+        let last_ip = "127.0.0.3".parse::<IpAddr>().unwrap();
+        let after_first = now + Duration::seconds(11);
+        assert_eq!(rate_limiter.ratelimit8(last_ip, after_first), true);
+        assert_eq!(rate_limiter.tracked_keys(), 2);
+        assert_eq!(rate_limiter.ratelimit8(other_ip, after_first), false);
+    }
+
+    #[test]
+    fn test_ratelimit8_over_memory_budget_falls_back_to_the_sketch() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let rate_limiter = RateLimiter8::with_config(1, Duration::seconds(10))
+            .with_memory_budget(8 * 1024)
+            .with_on_memory_event({
+                let events = Arc::clone(&events);
+                move |event| events.lock().unwrap().push(event)
+            });
+        let now = Utc::now();
+
+        // Fills the budget, less the eighth set aside for the sketch
+        let mut i = 0u32;
+        while rate_limiter.memory_used8() < 7 * 1024 {
+            assert_eq!(
+                rate_limiter.ratelimit8(IpAddr::from(i.to_be_bytes()), now),
+                true
+            );
+            i += 1;
+        }
+        let (tracked, used_bytes) = (rate_limiter.tracked_keys(), rate_limiter.memory_used8());
+
+        // Every key is still limited, so a new one is counted in the sketch
+        let ip = IpAddr::from(u32::MAX.to_be_bytes());
+        let later = now + Duration::seconds(1);
+        assert_eq!(rate_limiter.ratelimit8(ip, later), true);
+        assert_eq!(rate_limiter.ratelimit8(ip, later), false);
+        assert_eq!(rate_limiter.tracked_keys(), tracked);
+
+        // Once their requests leave the window, the keys are evicted for new ones
+        let other_ip = IpAddr::from((u32::MAX - 1).to_be_bytes());
+        let after_window = now + Duration::seconds(11);
+        assert_eq!(rate_limiter.ratelimit8(other_ip, after_window), true);
+        assert_eq!(rate_limiter.tracked_keys(), 1);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                MemoryEvent::EmergencyEviction {
+                    used_bytes,
+                    evicted: 0
+                },
+                MemoryEvent::Degraded { used_bytes },
+                MemoryEvent::EmergencyEviction {
+                    used_bytes: 0,
+                    evicted: tracked
+                },
+                MemoryEvent::Recovered { used_bytes: 0 },
+            ]
+        );
+    }
+}
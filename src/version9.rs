@@ -0,0 +1,340 @@
+use super::*;
+use chrono::{DateTime, Duration, Utc};
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+use crossbeam_skiplist::SkipMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+
+// The copy-on-write design of RateLimiter1, without its lost updates. Each key points
+// at an immutable log, and a check builds the next log from a copy and swaps it in
+// with a compare and swap. When another caller swapped in a log first, the check
+// starts over from theirs, so no admitted request is ever overwritten. Logs swapped
+// out are freed once no caller can still be reading them, through crossbeam-epoch.
+#[derive(Debug)]
+pub struct RateLimiter9<K: Ord = IpAddr, L = GlobalLimits> {
+    requests: SkipMap<K, Log>,
+    max_requests: usize,
+    window: Duration,
+    boundary: Boundary,
+    limits: L,
+}
+
+// The limits are those the key was created with, see LimitProvider
+#[derive(Debug)]
+struct Log {
+    max_requests: usize,
+    window: Duration,
+    // Never null
+    requests: Atomic<VecDeque<DateTime<Utc>>>,
+}
+
+impl Drop for Log {
+    fn drop(&mut self) {
+        // SAFETY: the log is being dropped, so no check can be reading its requests
+        unsafe {
+            let requests = self.requests.load(Ordering::Relaxed, epoch::unprotected());
+            drop(requests.into_owned());
+        }
+    }
+}
+
+impl RateLimiter9 {
+    // A log is only replaced by one built from it, so a check either records its
+    // request on top of every admitted one, or starts over
+    pub const STRICTNESS: Strictness = Strictness::Strict;
+}
+
+impl<K: Ord + Send + 'static> Default for RateLimiter9<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Send + 'static> RateLimiter9<K> {
+    pub fn new() -> Self {
+        Self::with_config(
+            MAX_REQUESTS,
+            Duration::seconds(MAX_REQUESTS_DURATION_SECONDS),
+        )
+    }
+
+    // Admits up to `max_requests` per key within any `window`, instead of the crate
+    // wide MAX_REQUESTS per MAX_REQUESTS_DURATION_SECONDS
+    pub fn with_config(max_requests: usize, window: Duration) -> Self {
+        RateLimiter9 {
+            requests: SkipMap::new(),
+            max_requests,
+            window,
+            boundary: Boundary::default(),
+            limits: GlobalLimits,
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimiter9<K, L> {
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+
+    // Resolves the limit of every new key through `limits`, falling back to the
+    // limiter's own config for keys it has no limit for
+    pub fn with_limits<P: LimitProvider<K>>(self, limits: P) -> RateLimiter9<K, P> {
+        RateLimiter9 {
+            requests: self.requests,
+            max_requests: self.max_requests,
+            window: self.window,
+            boundary: self.boundary,
+            limits,
+        }
+    }
+
+    pub fn ratelimit9(&self, src_ip: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit_with_cost9(src_ip, timestamp, 1)
+    }
+
+    // Records the request as `cost` requests at the same timestamp, if all of them fit
+    pub fn ratelimit_with_cost9(&self, src_ip: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        let entry = get_or_insert_with_limits(
+            &self.requests,
+            src_ip,
+            &self.limits,
+            (self.max_requests, self.window),
+            |max_requests, window| Log {
+                max_requests,
+                window,
+                requests: Atomic::new(VecDeque::new()),
+            },
+        );
+        let log = entry.value();
+        let cutoff_time = timestamp - log.window;
+
+        let guard = epoch::pin();
+        let mut current = log.requests.load(Ordering::Acquire, &guard);
+        loop {
+            // SAFETY: the log is never null, and a log swapped out is only freed once
+            // every caller pinned before the swap, such as this one, has unpinned
+            let mut requests = unsafe { current.deref() }.clone();
+
+            while let Some(front_time) = requests.front() {
+                if !self.boundary.contains(cutoff_time, *front_time) {
+                    requests.pop_front();
+                } else {
+                    break;
+                }
+            }
+
+            // Denied against a log that was current when loaded, so there is nothing
+            // to swap in
+            if requests.len() + cost as usize > log.max_requests {
+                return false;
+            }
+
+            requests.extend(std::iter::repeat_n(timestamp, cost as usize));
+            match log.requests.compare_exchange(
+                current,
+                Owned::new(requests),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                &guard,
+            ) {
+                Ok(_) => {
+                    // SAFETY: the log is no longer reachable from the map, and callers
+                    // still reading it are pinned
+                    unsafe { guard.defer_destroy(current) };
+                    return true;
+                }
+                Err(error) => current = error.current,
+            }
+        }
+    }
+}
+
+impl<K: Ord + Send + 'static, L: LimitProvider<K>> RateLimit<K> for RateLimiter9<K, L> {
+    fn check(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        self.ratelimit9(key, timestamp)
+    }
+
+    fn check_with_cost(&self, key: K, timestamp: DateTime<Utc>, cost: u32) -> bool {
+        self.ratelimit_with_cost9(key, timestamp, cost)
+    }
+
+    fn peek(&self, key: K, timestamp: DateTime<Utc>) -> bool {
+        let Some(entry) = self.requests.get(&key) else {
+            return max_requests_of(&self.limits, &key, self.max_requests) > 0;
+        };
+        let log = entry.value();
+        let cutoff_time = timestamp - log.window;
+
+        let guard = epoch::pin();
+        // SAFETY: the log is never null, and isn't freed while this caller is pinned
+        let requests = unsafe { log.requests.load(Ordering::Acquire, &guard).deref() };
+        let in_window = requests
+            .iter()
+            .filter(|&&time| self.boundary.contains(cutoff_time, time))
+            .count();
+        in_window < log.max_requests
+    }
+}
+
+impl<K: Ord, L> TrackedKeys for RateLimiter9<K, L> {
+    fn tracked_keys(&self) -> usize {
+        self.requests.len()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Clone, L> Snapshot<K> for RateLimiter9<K, L> {
+    fn snapshot(&self) -> RateLimiterSnapshot<K> {
+        let guard = epoch::pin();
+        RateLimiterSnapshot {
+            max_requests: self.max_requests,
+            window: self.window,
+            keys: self
+                .requests
+                .iter()
+                .map(|entry| {
+                    let log = entry.value();
+                    let requests = log.requests.load(Ordering::Acquire, &guard);
+                    KeySnapshot {
+                        key: entry.key().clone(),
+                        max_requests: log.max_requests,
+                        window: log.window,
+                        // SAFETY: the log is never null, and isn't freed while pinned
+                        requests: unsafe { requests.deref() }.iter().copied().collect(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Send + 'static> RateLimiter9<K> {
+    // Restores the log of every key along with the limits it was created with
+    pub fn from_snapshot(snapshot: RateLimiterSnapshot<K>) -> Self {
+        let rate_limiter = Self::with_config(snapshot.max_requests, snapshot.window);
+        for key in snapshot.keys {
+            rate_limiter.requests.insert(
+                key.key,
+                Log {
+                    max_requests: key.max_requests,
+                    window: key.window,
+                    requests: Atomic::new(key.requests.into()),
+                },
+            );
+        }
+        rate_limiter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn test_ratelimit9_over_denied() {
+        let rate_limiter = RateLimiter9::new();
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..MAX_REQUESTS {
+            assert_eq!(rate_limiter.ratelimit9(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit9(ip, now), false);
+    }
+
+    #[test]
+    fn test_ratelimit9_with_config() {
+        let rate_limiter = RateLimiter9::with_config(5, Duration::seconds(1));
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.ratelimit9(ip, now), true);
+        }
+        assert_eq!(rate_limiter.ratelimit9(ip, now), false);
+        assert_eq!(
+            rate_limiter.ratelimit9(ip, now + Duration::seconds(1)),
+            false
+        );
+
+        let later = now + Duration::seconds(1) + Duration::nanoseconds(1);
+        assert_eq!(rate_limiter.ratelimit9(ip, later), true);
+    }
+
+    // Many threads released at once on a single key, repeated so the compare and swap
+    // fails often, where RateLimiter1 loses updates and over-admits
+    #[test]
+    fn test_ratelimit9_admits_exactly_the_limit_under_contention() {
+        const NUM_THREADS: usize = 16;
+        const ROUNDS: usize = 50;
+        let ip = "127.0.0.1".parse::<IpAddr>().unwrap();
+
+        for _ in 0..ROUNDS {
+            let rate_limiter = RateLimiter9::new();
+            let barrier = Barrier::new(NUM_THREADS);
+            let now = Utc::now();
+
+            let admitted: usize = thread::scope(|scope| {
+                let threads: Vec<_> = (0..NUM_THREADS)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            barrier.wait();
+                            (0..MAX_REQUESTS)
+                                .filter(|_| rate_limiter.ratelimit9(ip, now))
+                                .count()
+                        })
+                    })
+                    .collect();
+                threads
+                    .into_iter()
+                    .map(|thread| thread.join().unwrap())
+                    .sum()
+            });
+
+            assert_eq!(admitted, MAX_REQUESTS);
+        }
+    }
+
+    #[test]
+    fn test_ratelimit9_honours_strictness() {
+        const NUM_THREADS: usize = 10;
+        let rate_limiter = RateLimiter9::new();
+
+        let admitted = crate::strictness::admitted_under_contention(
+            |ip, ts| rate_limiter.ratelimit9(ip, ts),
+            NUM_THREADS,
+            MAX_REQUESTS,
+        );
+
+        assert!(
+            RateLimiter9::STRICTNESS.permits(admitted, MAX_REQUESTS),
+            "Admitted {} requests with a limit of {}",
+            admitted,
+            MAX_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_ratelimit9_with_limits_per_key() {
+        let premium = "127.0.0.2".parse::<IpAddr>().unwrap();
+        let rate_limiter = RateLimiter9::with_config(2, Duration::seconds(60))
+            .with_limits(move |ip: &IpAddr| (*ip == premium).then(|| (5, Duration::seconds(1))));
+        let free = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let now = Utc::now();
+
+        let admitted = |ip, at| (0..10).filter(|_| rate_limiter.ratelimit9(ip, at)).count();
+        assert_eq!(admitted(free, now), 2);
+        assert_eq!(admitted(premium, now), 5);
+
+        // Each key's window is its own too
+        let later = now + Duration::seconds(2);
+        assert_eq!(admitted(free, later), 0);
+        assert_eq!(admitted(premium, later), 5);
+    }
+}
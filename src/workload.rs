@@ -0,0 +1,258 @@
+use rand::Rng;
+use std::future::Future;
+use std::net::{AddrParseError, IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Distribution {
+    // Every request comes from a fresh random IPv4 address
+    Uniform,
+    // Requests are drawn from a pool of `keys` addresses, where the k-th most popular
+    // address is picked with a probability proportional to 1 / k^exponent
+    Zipf { keys: usize, exponent: f64 },
+    // Random addresses sending `burst_len` requests back to back
+    Bursty { burst_len: usize },
+    // Cycles through a recorded sequence of addresses
+    Replay(Vec<IpAddr>),
+}
+
+pub fn random_ip() -> IpAddr {
+    let mut rng = rand::thread_rng();
+
+    IpAddr::V4(Ipv4Addr::new(
+        rng.gen::<u8>(),
+        rng.gen::<u8>(),
+        rng.gen::<u8>(),
+        rng.gen::<u8>(),
+    ))
+}
+
+pub fn generate(distribution: &Distribution, requests: usize) -> Vec<IpAddr> {
+    match distribution {
+        Distribution::Uniform => (0..requests).map(|_| random_ip()).collect(),
+        Distribution::Zipf { keys, exponent } => {
+            assert!(*keys > 0, "Zipf workload needs at least one key");
+            let pool: Vec<IpAddr> = (0..*keys).map(|_| random_ip()).collect();
+
+            let mut cumulative = 0.0;
+            let cdf: Vec<f64> = (1..=*keys)
+                .map(|rank| {
+                    cumulative += 1.0 / (rank as f64).powf(*exponent);
+                    cumulative
+                })
+                .collect();
+
+            let mut rng = rand::thread_rng();
+            (0..requests)
+                .map(|_| {
+                    let sample = rng.gen::<f64>() * cumulative;
+                    let rank = cdf.partition_point(|&c| c < sample).min(keys - 1);
+                    pool[rank]
+                })
+                .collect()
+        }
+        Distribution::Bursty { burst_len } => {
+            assert!(*burst_len > 0, "Bursty workload needs a burst length");
+            (0..requests.div_ceil(*burst_len))
+                .flat_map(|_| std::iter::repeat_n(random_ip(), *burst_len))
+                .take(requests)
+                .collect()
+        }
+        Distribution::Replay(recorded) => {
+            assert!(
+                !recorded.is_empty(),
+                "Replay workload needs recorded requests"
+            );
+            recorded.iter().copied().cycle().take(requests).collect()
+        }
+    }
+}
+
+// Parses a recording with one IP address per line, blank lines are ignored
+pub fn parse_replay(recording: &str) -> Result<Vec<IpAddr>, AddrParseError> {
+    recording
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Submits the requests one chunk at a time, returning how many were permitted
+pub fn submit_chunked(
+    ips: &[IpAddr],
+    chunk_size: usize,
+    mut check: impl FnMut(IpAddr) -> bool,
+) -> usize {
+    let mut permitted = 0;
+    for chunk in ips.chunks(chunk_size) {
+        for &ip in chunk {
+            if check(ip) {
+                permitted += 1;
+            }
+        }
+    }
+    permitted
+}
+
+// Spawns a tokio task per request, awaiting each chunk before submitting the next,
+// returning how many were permitted
+pub async fn submit_chunked_tokio<F>(ips: &[IpAddr], chunk_size: usize, check: Arc<F>) -> usize
+where
+    F: Fn(IpAddr) -> bool + Send + Sync + ?Sized + 'static,
+{
+    let mut permitted = 0;
+    for chunk in ips.chunks(chunk_size) {
+        let tasks: Vec<_> = chunk
+            .iter()
+            .map(|&ip| {
+                let check = Arc::clone(&check);
+                tokio::task::spawn(async move { check(ip) })
+            })
+            .collect();
+
+        permitted += futures::future::try_join_all(tasks)
+            .await
+            .expect("One of the tasks failed.")
+            .into_iter()
+            .filter(|&allowed| allowed)
+            .count();
+    }
+    permitted
+}
+
+// Like submit_chunked_tokio, for checks that have to be awaited
+pub async fn submit_chunked_async<F, Fut>(ips: &[IpAddr], chunk_size: usize, check: Arc<F>) -> usize
+where
+    F: Fn(IpAddr) -> Fut + Send + Sync + ?Sized + 'static,
+    Fut: Future<Output = bool> + Send + 'static,
+{
+    let mut permitted = 0;
+    for chunk in ips.chunks(chunk_size) {
+        let tasks: Vec<_> = chunk
+            .iter()
+            .map(|&ip| tokio::task::spawn(check(ip)))
+            .collect();
+
+        permitted += futures::future::try_join_all(tasks)
+            .await
+            .expect("One of the tasks failed.")
+            .into_iter()
+            .filter(|&allowed| allowed)
+            .count();
+    }
+    permitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_generate_produces_requested_amount() {
+        let distributions = [
+            Distribution::Uniform,
+            Distribution::Zipf {
+                keys: 10,
+                exponent: 1.0,
+            },
+            Distribution::Bursty { burst_len: 7 },
+            Distribution::Replay(vec!["127.0.0.1".parse().unwrap()]),
+        ];
+
+        for distribution in &distributions {
+            assert_eq!(generate(distribution, 1000).len(), 1000);
+        }
+    }
+
+    #[test]
+    fn test_zipf_favours_the_most_popular_key() {
+        let ips = generate(
+            &Distribution::Zipf {
+                keys: 100,
+                exponent: 1.5,
+            },
+            10_000,
+        );
+
+        let mut counts: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in ips {
+            *counts.entry(ip).or_default() += 1;
+        }
+
+        assert!(counts.len() <= 100);
+        let most_popular = counts.values().max().unwrap();
+        assert!(
+            *most_popular > 10_000 / 100 * 10,
+            "Expected a heavily skewed distribution, most popular key only had {} requests",
+            most_popular
+        );
+    }
+
+    #[test]
+    fn test_bursty_repeats_each_address() {
+        let ips = generate(&Distribution::Bursty { burst_len: 5 }, 23);
+
+        assert_eq!(ips.len(), 23);
+        for burst in ips.chunks(5) {
+            assert!(burst.iter().all(|&ip| ip == burst[0]));
+        }
+    }
+
+    #[test]
+    fn test_replay_cycles_through_recording() {
+        let recording = parse_replay("10.0.0.1\n\n10.0.0.2\n").unwrap();
+        let ips = generate(&Distribution::Replay(recording.clone()), 5);
+
+        assert_eq!(
+            ips,
+            vec![
+                recording[0],
+                recording[1],
+                recording[0],
+                recording[1],
+                recording[0]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_replay_rejects_invalid_addresses() {
+        assert!(parse_replay("10.0.0.1\nnot-an-ip").is_err());
+    }
+
+    #[test]
+    fn test_submit_chunked_counts_permitted() {
+        let ips = generate(&Distribution::Uniform, 10);
+        let mut calls = 0;
+
+        let permitted = submit_chunked(&ips, 3, |_| {
+            calls += 1;
+            calls % 2 == 0
+        });
+
+        assert_eq!(calls, 10);
+        assert_eq!(permitted, 5);
+    }
+
+    #[tokio::test]
+    async fn test_submit_chunked_tokio_counts_permitted() {
+        let ips = generate(&Distribution::Uniform, 10);
+
+        let permitted = submit_chunked_tokio(&ips, 3, Arc::new(|_| true)).await;
+
+        assert_eq!(permitted, 10);
+    }
+
+    #[tokio::test]
+    async fn test_submit_chunked_async_counts_permitted() {
+        let ips = generate(&Distribution::Uniform, 10);
+
+        let permitted =
+            submit_chunked_async(&ips, 3, Arc::new(|ip: IpAddr| async move { ip.is_ipv4() })).await;
+
+        assert_eq!(permitted, 10);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+
+const USAGE: &str = "Usage: cargo xtask <bench-baseline|bench-check> [--requests N] [--runs N] [--threshold FRACTION] [--baseline PATH]";
+
+// (implementation, workload, mode)
+type BenchId = (String, String, String);
+
+#[derive(Debug)]
+struct Options {
+    requests: usize,
+    runs: usize,
+    threshold: f64,
+    baseline: PathBuf,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            requests: 100_000,
+            runs: 3,
+            threshold: 0.15,
+            // Throughput depends on the hardware, so baselines are kept out of the
+            // repository
+            baseline: PathBuf::from("target/bench-baseline.csv"),
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(format!("Missing value for {arg}"))?;
+        match arg.as_str() {
+            "--requests" => {
+                options.requests = value.parse().map_err(|e| format!("--requests: {e}"))?
+            }
+            "--runs" => options.runs = value.parse().map_err(|e| format!("--runs: {e}"))?,
+            "--threshold" => {
+                options.threshold = value.parse().map_err(|e| format!("--threshold: {e}"))?
+            }
+            "--baseline" => options.baseline = PathBuf::from(value),
+            other => return Err(format!("Unknown argument: {other}\n{USAGE}")),
+        }
+    }
+
+    if options.runs == 0 {
+        return Err("--runs must be greater than 0".to_string());
+    }
+    if !(0.0..1.0).contains(&options.threshold) {
+        return Err("--threshold must be a fraction between 0 and 1".to_string());
+    }
+
+    Ok(options)
+}
+
+// Runs the reduced suite through bench-runner, keeping the best throughput of every
+// benchmark over all runs to smooth out noise
+fn run_suite(options: &Options) -> Result<BTreeMap<BenchId, f64>, String> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let mut best = BTreeMap::new();
+
+    for run in 1..=options.runs {
+        eprintln!(
+            "Running the reduced benchmark suite ({run}/{})",
+            options.runs
+        );
+        let output = Command::new(&cargo)
+            .args(["run", "--quiet", "--release", "--package", "ratelimit"])
+            .args(["--bin", "bench-runner", "--", "--format", "csv"])
+            .args(["--requests", &options.requests.to_string()])
+            .output()
+            .map_err(|e| format!("Failed to run bench-runner: {e}"))?;
+        if !output.status.success() {
+            return Err(format!(
+                "bench-runner failed:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let csv = String::from_utf8(output.stdout).map_err(|e| e.to_string())?;
+        for (id, throughput) in parse_results(&csv)? {
+            let best = best.entry(id).or_insert(throughput);
+            *best = f64::max(*best, throughput);
+        }
+    }
+    Ok(best)
+}
+
+// Parses bench-runner's CSV output, along with baselines written in the same format
+fn parse_results(csv: &str) -> Result<BTreeMap<BenchId, f64>, String> {
+    csv.lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // The label is the only field that can contain commas, and it comes first
+            let fields: Vec<_> = line.rsplitn(9, ',').collect();
+            let [throughput, _, _, _, _, mode, workload, implementation, _] = fields[..] else {
+                return Err(format!("Malformed result: {line}"));
+            };
+            let throughput = throughput
+                .parse()
+                .map_err(|e| format!("Malformed throughput in {line}: {e}"))?;
+            let id = (
+                implementation.to_string(),
+                workload.to_string(),
+                mode.to_string(),
+            );
+            Ok((id, throughput))
+        })
+        .collect()
+}
+
+fn write_baseline(path: &Path, results: &BTreeMap<BenchId, f64>) -> Result<(), String> {
+    let mut csv = String::from(
+        "label,implementation,workload,mode,requests,allowed,denied,elapsed_ns,throughput_per_sec\n",
+    );
+    for ((implementation, workload, mode), throughput) in results {
+        csv += &format!("baseline,{implementation},{workload},{mode},,,,,{throughput:.2}\n");
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {e}", parent.display()))?;
+    }
+    fs::write(path, csv).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+// Returns a description of every benchmark whose throughput dropped by more than the
+// threshold. Benchmarks missing from either side are reported but never fail.
+fn regressions(
+    baseline: &BTreeMap<BenchId, f64>,
+    current: &BTreeMap<BenchId, f64>,
+    threshold: f64,
+) -> Vec<String> {
+    let mut regressions = Vec::new();
+
+    for (id, &current) in current {
+        let (implementation, workload, mode) = id;
+        let name = format!("{implementation}/{workload}/{mode}");
+        let Some(&baseline) = baseline.get(id) else {
+            eprintln!("{name}: no baseline, skipped");
+            continue;
+        };
+
+        let change = current / baseline - 1.0;
+        eprintln!(
+            "{name}: {current:.0}/s vs {baseline:.0}/s ({:+.1}%)",
+            change * 100.0
+        );
+        if change < -threshold {
+            regressions.push(format!(
+                "{name} regressed by {:.1}%, more than the allowed {:.1}%",
+                -change * 100.0,
+                threshold * 100.0
+            ));
+        }
+    }
+    for id in baseline.keys().filter(|id| !current.contains_key(*id)) {
+        eprintln!("{}/{}/{}: no longer benchmarked", id.0, id.1, id.2);
+    }
+
+    regressions
+}
+
+fn run(task: &str, options: &Options) -> Result<(), String> {
+    match task {
+        "bench-baseline" => {
+            let results = run_suite(options)?;
+            write_baseline(&options.baseline, &results)?;
+            eprintln!("Wrote the baseline to {}", options.baseline.display());
+            Ok(())
+        }
+        "bench-check" => {
+            let baseline = fs::read_to_string(&options.baseline).map_err(|e| {
+                format!(
+                    "{}: {e}\nRecord one first with `cargo xtask bench-baseline`",
+                    options.baseline.display()
+                )
+            })?;
+            let baseline = parse_results(&baseline)?;
+            let results = run_suite(options)?;
+
+            let regressions = regressions(&baseline, &results, options.threshold);
+            if regressions.is_empty() {
+                eprintln!("No regressions");
+                Ok(())
+            } else {
+                Err(regressions.join("\n"))
+            }
+        }
+        other => Err(format!("Unknown task: {other}\n{USAGE}")),
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(task) = args.next() else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&task, &options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}